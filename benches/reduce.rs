@@ -0,0 +1,91 @@
+//! Times reducing generated sources of a growing number of declarations, and
+//! taking them once they are reduced, which happens every time a performance
+//! loops. Run with `cargo bench`.
+
+use std::{collections::HashSet, time::Instant};
+
+/// The crate is a binary, so the modules reducing needs are compiled into the
+/// benchmark. Their tests are left out, and with them what only tests use.
+#[allow(dead_code, unused_imports)]
+#[path = "../src"]
+mod choppo {
+    pub mod parser;
+    pub mod reducer;
+    pub mod rng;
+    pub mod time;
+    pub mod util;
+}
+
+use choppo::{parser, reducer, rng, time, util};
+
+const SIZES: [usize; 3] = [100, 200, 400];
+const RUNS: usize = 5;
+const TAKES: usize = 50;
+
+/// A clip name, as declaration names are letters only.
+fn name(mut index: usize) -> String {
+    let mut name = String::from("c");
+    loop {
+        name.push((b'a' + (index % 26) as u8) as char);
+        index /= 26;
+        if index == 0 {
+            return name;
+        }
+    }
+}
+
+/// `declarations` clips that each loop the one before and the one half as far
+/// up, so every clip is referred to over and over, and a main expression that
+/// chooses between the last two. A quarter of the clips are never played.
+fn source(declarations: usize) -> String {
+    let mut source = String::from("directory = 'videos/';\nextension = '.mov';\n\n");
+    source += "clip ca = 'a' | 'b' : 'c';\n";
+    let played = declarations * 3 / 4;
+    for index in 1..played {
+        source += &format!(
+            "clip {} = (({} * 8)[1:3] | rev {}[1:3]) * 4;\n",
+            name(index),
+            name(index - 1),
+            name(index / 2)
+        );
+    }
+    for index in played..declarations {
+        source += &format!("clip {} = ({} * 8)[1:3] ? 'd';\n", name(index), name(index - 1));
+    }
+    source + &format!("\n{} ? {}\n", name(played - 1), name(played - 2))
+}
+
+fn median(mut times: Vec<f64>) -> f64 {
+    times.sort_by(f64::total_cmp);
+    times[times.len() / 2]
+}
+
+fn main() {
+    for declarations in SIZES {
+        let source = source(declarations);
+        let mut program = None;
+        let reduce_times = (0..RUNS)
+            .map(|_| {
+                let main = parser::parse(&source).unwrap();
+                let start = Instant::now();
+                program = Some(reducer::reduce(main, &HashSet::new()).ok().unwrap());
+                start.elapsed().as_secs_f64()
+            })
+            .collect();
+        let program = program.unwrap();
+        let mut rng = rng::Rng::new(0);
+        let take_times = (0..TAKES)
+            .map(|_| {
+                let start = Instant::now();
+                program.take(&mut rng).unwrap();
+                start.elapsed().as_secs_f64()
+            })
+            .collect();
+        println!(
+            "{:4} declarations: reduce {:8.2} ms, take {:8.3} ms",
+            declarations,
+            median(reduce_times) * 1000.0,
+            median(take_times) * 1000.0
+        );
+    }
+}
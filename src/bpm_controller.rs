@@ -65,6 +65,10 @@ impl BpmController {
         }
     }
 
+    pub fn set_bpm(&mut self, bpm: f64) {
+        self.bpm = bpm;
+    }
+
     pub fn get_bpm(&self) -> f64 {
         self.bpm + self.temp_bpm_offset
     }
@@ -0,0 +1,678 @@
+use std::{fmt, path::Path};
+
+use crate::{
+    importer::{ImportError, Importer},
+    parser::{
+        beats_parser::{
+            BeatChainExpression, BeatExpression, DotBeatExpression, EuclideanBeatExpression,
+            IntersectionBeatExpression, InvertedBeatExpression, NumberBeatExpression,
+            ParenthesesBeatExpression, ReferenceBeatExpression, RepeatedBeatExpression,
+            RotatedBeatExpression, UnionBeatExpression, DEFAULT_RESOLUTION,
+        },
+        error::ParseError,
+        parse,
+        whitespace::whitespace0,
+        ApplyBeatExpression, ArgumentExpression, CallClipExpression, ChoiceClipExpression,
+        ClipChainExpression, ClipExpression, ClipLayerExpression, ClipLoopExpression, Declaration,
+        FitClipExpression, HoldClipExpression, Main, MultiVideoExpression, NumberExpression,
+        ParenthesesClipExpression, PingPongClipExpression, RawVideoExpression,
+        ReferenceClipExpression, RestartExpression, ReverseClipExpression, SourceOffset,
+        SpeedClipExpression, TimeRangeExpression, TruncatedClipExpression,
+    },
+    reducer::{reduce, ReduceError, ReducedClip},
+    rng::Rng,
+    source_watcher::read_file_paths_in_directory,
+};
+
+#[derive(Debug)]
+pub enum FormatError {
+    Import(ImportError),
+    Reduce(Vec<ReduceError>),
+    /// The formatted source does not parse, or formats differently again.
+    Unstable,
+    /// The formatted source reduces to other clips than the source.
+    ChangedMeaning,
+}
+
+impl From<ImportError> for FormatError {
+    fn from(e: ImportError) -> Self {
+        FormatError::Import(e)
+    }
+}
+
+impl From<Vec<ReduceError>> for FormatError {
+    fn from(e: Vec<ReduceError>) -> Self {
+        FormatError::Reduce(e)
+    }
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Import(e) => write!(f, "{}", e),
+            FormatError::Reduce(problems) => {
+                let messages: Vec<String> = problems.iter().map(ToString::to_string).collect();
+                write!(f, "{}", messages.join("\n"))
+            }
+            FormatError::Unstable => write!(f, "the formatted source does not format the same"),
+            FormatError::ChangedMeaning => {
+                write!(f, "the formatted source does not play the same")
+            }
+        }
+    }
+}
+
+/// Formats the main file at `path`, whose source is `input`, and makes sure
+/// that the result plays exactly like it before returning it.
+pub fn format_file(path: &Path, input: &str) -> Result<String, FormatError> {
+    let formatted = format(input).map_err(|e| ImportError::Parse(path.to_path_buf(), e))?;
+    match format(&formatted) {
+        Ok(again) if again == formatted => (),
+        _ => return Err(FormatError::Unstable),
+    }
+    let original = takes(Importer::default().load_source(path, input)?)?;
+    let reformatted = takes(Importer::default().load_source(path, &formatted)?)?;
+    if original != reformatted {
+        return Err(FormatError::ChangedMeaning);
+    }
+    Ok(formatted)
+}
+
+/// A take of the main expression and of every scene, with choices made by the
+/// same seed.
+fn takes(main: Main) -> Result<Vec<ReducedClip>, Vec<ReduceError>> {
+    let available_files = read_file_paths_in_directory(&main.directory_declaration.directory);
+    let program = reduce(main, &available_files)?;
+    let mut takes = vec![program.take(&mut Rng::new(0)).map_err(|e| vec![e])?];
+    for name in program.scene_names() {
+        takes.extend(
+            program
+                .take_scene(name, &mut Rng::new(0))
+                .transpose()
+                .map_err(|e| vec![e])?,
+        );
+    }
+    Ok(takes)
+}
+
+/// Prints a source in its canonical layout: one statement per line, the `=`
+/// of neighbouring declarations aligned, file names in single quotes and only
+/// the parentheses the precedence of the operators needs. Comments stay with
+/// the statement they precede or end the line of.
+pub fn format(input: &str) -> Result<String, ParseError> {
+    let main = parse(input)?;
+    let statements = statements(&main);
+    let layout = scan(input);
+
+    let mut leading = vec![Vec::new(); statements.len()];
+    let mut trailing = vec![Vec::new(); statements.len()];
+    let mut footer = Vec::new();
+    for comment in layout.comments {
+        let statement = layout
+            .semicolons
+            .partition_point(|&end| end < comment.start);
+        if statement > 0 && !input[layout.semicolons[statement - 1]..comment.start].contains('\n') {
+            trailing[statement - 1].push(comment);
+        } else if statement == layout.semicolons.len() && comment.start >= layout.code_end {
+            let main_expression = statement < statements.len();
+            if main_expression && !input[layout.code_end..comment.start].contains('\n') {
+                trailing[statement].push(comment);
+            } else {
+                footer.push(comment);
+            }
+        } else {
+            // comments inside a statement move in front of it
+            leading[statement].push(comment);
+        }
+    }
+
+    // a blank line in front of a statement or its comments ends the run of
+    // declarations whose `=` are aligned
+    let blank: Vec<bool> = (0..statements.len())
+        .map(|statement| {
+            let start = match statement {
+                0 => 0,
+                statement => layout.semicolons[statement - 1] + 1,
+            };
+            let code = whitespace0(&input[start..])
+                .map_or(input.len(), |(rest, _)| input.len() - rest.len());
+            blank_line_before(input, code)
+        })
+        .collect();
+    let mut widths = vec![0; statements.len()];
+    let mut run = 0;
+    for statement in 1..=statements.len() {
+        let breaks = statement == statements.len()
+            || blank[statement]
+            || leading[statement].iter().any(|comment| comment.blank);
+        if breaks {
+            let width = statements[run..statement]
+                .iter()
+                .filter(|statement| statement.value.is_some())
+                .map(|statement| statement.head.chars().count())
+                .max()
+                .unwrap_or(0);
+            widths[run..statement].fill(width);
+            run = statement;
+        }
+    }
+
+    let mut output = String::new();
+    for (index, statement) in statements.iter().enumerate() {
+        for comment in &leading[index] {
+            push_line(&mut output, comment.blank, comment.text);
+        }
+        let mut line = match &statement.value {
+            Some(value) => format!(
+                "{:width$} = {}{}",
+                statement.head,
+                value,
+                statement.end,
+                width = widths[index]
+            ),
+            None => format!("{}{}", statement.head, statement.end),
+        };
+        for comment in &trailing[index] {
+            line.push(' ');
+            line.push_str(comment.text);
+        }
+        push_line(&mut output, blank[index], &line);
+    }
+    for comment in footer {
+        push_line(&mut output, comment.blank, comment.text);
+    }
+    Ok(output)
+}
+
+fn push_line(output: &mut String, blank: bool, line: &str) {
+    if blank && !output.is_empty() {
+        output.push('\n');
+    }
+    output.push_str(line);
+    output.push('\n');
+}
+
+fn blank_line_before(input: &str, offset: usize) -> bool {
+    input[..offset]
+        .chars()
+        .rev()
+        .take_while(|c| c.is_whitespace())
+        .filter(|c| *c == '\n')
+        .count()
+        > 1
+}
+
+/// A statement as `head = value;`, or as `head;` if it has no value.
+struct Statement {
+    head: String,
+    value: Option<String>,
+    end: &'static str,
+}
+
+impl Statement {
+    fn new(head: impl Into<String>, value: Option<String>) -> Self {
+        Self {
+            head: head.into(),
+            value,
+            end: ";",
+        }
+    }
+}
+
+fn statements(main: &Main) -> Vec<Statement> {
+    let mut statements = vec![
+        Statement::new(
+            "directory",
+            Some(quote(&main.directory_declaration.directory)),
+        ),
+        Statement::new(
+            "extension",
+            Some(quote(&main.extension_declaration.extension)),
+        ),
+    ];
+    if let Some(bpm) = &main.bpm_declaration {
+        statements.push(Statement::new("bpm", Some(bpm.bpm.to_string())));
+    }
+    if let Some(meter) = &main.meter_declaration {
+        statements.push(Statement::new(
+            "meter",
+            Some(format!("{}/{}", meter.beats, meter.unit)),
+        ));
+    }
+    for declaration in &main.declarations {
+        statements.push(match declaration {
+            Declaration::ClipDeclaration(declaration) => {
+                let head = if declaration.parameters.is_empty() {
+                    format!("clip {}", declaration.name)
+                } else {
+                    format!(
+                        "clip {}({})",
+                        declaration.name,
+                        declaration.parameters.join(", ")
+                    )
+                };
+                Statement::new(head, Some(clip(&declaration.expression, CHOICE)))
+            }
+            Declaration::BeatDeclaration(declaration) => Statement::new(
+                format!("beat {}", declaration.name),
+                Some(beat(&declaration.expression, BEAT_CHAIN)),
+            ),
+            Declaration::SceneDeclaration(declaration) => Statement::new(
+                format!("scene {}", declaration.name),
+                Some(clip(&declaration.expression, CHOICE)),
+            ),
+            Declaration::ImportDeclaration(declaration) => {
+                let mut head = format!("import {}", quote(&declaration.path));
+                if let Some(namespace) = &declaration.namespace {
+                    head.push_str(&format!(" as {}", namespace));
+                }
+                Statement::new(head, None)
+            }
+        });
+    }
+    if let Some(main_expression) = &main.main_expression {
+        statements.push(Statement {
+            head: clip(main_expression, CHOICE),
+            value: None,
+            end: "",
+        });
+    }
+    statements
+}
+
+#[derive(Clone)]
+struct Comment<'a> {
+    text: &'a str,
+    start: usize,
+    /// Whether a blank line separates it from what comes before.
+    blank: bool,
+}
+
+/// Where the comments, the `;` ending statements and the last code are in a
+/// source.
+struct Layout<'a> {
+    comments: Vec<Comment<'a>>,
+    semicolons: Vec<usize>,
+    code_end: usize,
+}
+
+/// Walks a source the way `take_until_code` does, so that nothing in comments
+/// or quoted file names is taken for code.
+fn scan(input: &str) -> Layout<'_> {
+    let mut layout = Layout {
+        comments: Vec::new(),
+        semicolons: Vec::new(),
+        code_end: 0,
+    };
+    let mut offset = 0;
+    while offset < input.len() {
+        let rest = &input[offset..];
+        let comment = if rest.starts_with("//") || rest.starts_with('#') {
+            Some(rest.find('\n').unwrap_or(rest.len()))
+        } else {
+            rest.strip_prefix("/*")
+                .map(|comment| comment.find("*/").map_or(rest.len(), |end| end + 4))
+        };
+        let length = match comment {
+            Some(length) => {
+                layout.comments.push(Comment {
+                    text: rest[..length].trim_end(),
+                    start: offset,
+                    blank: blank_line_before(input, offset),
+                });
+                length
+            }
+            None if rest.starts_with('\'') || rest.starts_with('"') => {
+                let quote = &rest[..1];
+                let length = rest[1..].find(quote).map_or(rest.len(), |end| end + 2);
+                layout.code_end = offset + length;
+                length
+            }
+            None => {
+                let c = rest.chars().next().unwrap_or_default();
+                if c == ';' {
+                    layout.semicolons.push(offset);
+                }
+                if !c.is_whitespace() {
+                    layout.code_end = offset + c.len_utf8();
+                }
+                c.len_utf8()
+            }
+        };
+        offset += length;
+    }
+    layout
+}
+
+fn quote(text: &str) -> String {
+    if text.contains('\'') {
+        format!("\"{}\"", text)
+    } else {
+        format!("'{}'", text)
+    }
+}
+
+// how tightly clip expressions bind, from loosest to tightest, as described
+// on `parse_clip_expression`
+const CHOICE: usize = 0;
+const PREFIX: usize = 1;
+const CHAIN: usize = 2;
+const LAYER: usize = 3;
+const REVERSE: usize = 4;
+const POSTFIX: usize = 5;
+const PRIMARY: usize = 6;
+
+/// Prints a clip in a place that needs it to bind at least as tightly as
+/// `min_precedence`, in parentheses if it does not.
+fn clip(expression: &ClipExpression, min_precedence: usize) -> String {
+    let (text, precedence) = match expression {
+        ClipExpression::Empty => ("-".to_string(), PRIMARY),
+        ClipExpression::Reference(ReferenceClipExpression { name, .. }) => (name.clone(), PRIMARY),
+        ClipExpression::RawVideo(RawVideoExpression {
+            filename, offset, ..
+        }) => {
+            let text = match offset {
+                None => quote(filename),
+                Some(offset) => format!("{} @ {}", quote(filename), source_offset(offset)),
+            };
+            (text, PRIMARY)
+        }
+        ClipExpression::MultiVideo(MultiVideoExpression {
+            filename, subclips, ..
+        }) => (format!("multi {} {}", subclips, quote(filename)), PRIMARY),
+        ClipExpression::Call(CallClipExpression {
+            name, arguments, ..
+        }) => {
+            let arguments: Vec<String> = arguments.iter().map(argument).collect();
+            (format!("{}({})", name, arguments.join(", ")), PRIMARY)
+        }
+        ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression { clip: inner }) => {
+            return clip(inner, min_precedence)
+        }
+        ClipExpression::Choice(ChoiceClipExpression { alternatives }) => {
+            let alternatives: Vec<String> = alternatives
+                .iter()
+                .map(|alternative| match alternative.weight {
+                    1 => clip(&alternative.clip, CHAIN),
+                    weight => format!("{} {{{}}}", clip(&alternative.clip, CHAIN), weight),
+                })
+                .collect();
+            (alternatives.join(" ? "), CHOICE)
+        }
+        ClipExpression::ApplyBeat(ApplyBeatExpression {
+            beat_expression,
+            clip_expression,
+        }) => (
+            format!(
+                "{} @ {}",
+                beat(beat_expression, BEAT_CHAIN),
+                clip(clip_expression, CHOICE)
+            ),
+            PREFIX,
+        ),
+        ClipExpression::Restart(RestartExpression {
+            beat_expression,
+            clip_expression,
+        }) => (
+            format!(
+                "{} >> {}",
+                beat(beat_expression, BEAT_CHAIN),
+                clip(clip_expression, CHOICE)
+            ),
+            PREFIX,
+        ),
+        ClipExpression::Chain(ClipChainExpression { clip_a, clip_b }) => (
+            format!("{} | {}", clip(clip_a, LAYER), clip(clip_b, CHAIN)),
+            CHAIN,
+        ),
+        ClipExpression::Layer(ClipLayerExpression { clip_a, clip_b }) => (
+            format!("{} : {}", clip(clip_a, REVERSE), clip(clip_b, LAYER)),
+            LAYER,
+        ),
+        ClipExpression::Reverse(ReverseClipExpression { clip: inner }) => {
+            (format!("rev {}", clip(inner, REVERSE)), REVERSE)
+        }
+        ClipExpression::Hold(HoldClipExpression { clip: inner }) => {
+            (format!("hold {}", clip(inner, REVERSE)), REVERSE)
+        }
+        ClipExpression::Loop(ClipLoopExpression {
+            clip: inner,
+            repetitions,
+            ..
+        }) => (
+            format!("{} * {}", clip(inner, POSTFIX), number(repetitions)),
+            POSTFIX,
+        ),
+        ClipExpression::Truncated(TruncatedClipExpression {
+            clip: inner,
+            timerange,
+            ..
+        }) => {
+            let mut unwrapped = inner.as_ref();
+            while let ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression {
+                clip: inner,
+            }) = unwrapped
+            {
+                unwrapped = inner;
+            }
+            let inner = match (unwrapped, timerange) {
+                // `pingpong a[:]` would read the range as the part of the file to play
+                (
+                    ClipExpression::PingPong(_),
+                    TimeRangeExpression {
+                        from: None,
+                        to: None,
+                    },
+                ) => format!("({})", clip(unwrapped, CHOICE)),
+                // so that `a * 2 [1:2]` does not look like it truncates the 2
+                (
+                    ClipExpression::Loop(_)
+                    | ClipExpression::Speed(_)
+                    | ClipExpression::Fit(_)
+                    | ClipExpression::PingPong(_)
+                    | ClipExpression::RawVideo(RawVideoExpression {
+                        offset: Some(_), ..
+                    }),
+                    _,
+                ) => format!("{} ", clip(unwrapped, POSTFIX)),
+                _ => clip(unwrapped, POSTFIX),
+            };
+            (format!("{}{}", inner, timerange), POSTFIX)
+        }
+        ClipExpression::Speed(SpeedClipExpression {
+            clip: inner,
+            numerator,
+            denominator,
+        }) => (
+            format!(
+                "{} ~ {}",
+                clip(inner, POSTFIX),
+                speed(*numerator, *denominator)
+            ),
+            POSTFIX,
+        ),
+        ClipExpression::Fit(FitClipExpression {
+            clip: inner,
+            start,
+            end,
+            beats,
+            ..
+        }) => {
+            let region = match (start, end) {
+                (None, None) => String::new(),
+                (start, end) => format!(" {}", source_region(*start, *end)),
+            };
+            (
+                format!("fit {}{} {}", clip(inner, PRIMARY), region, number(beats)),
+                PRIMARY,
+            )
+        }
+        ClipExpression::PingPong(PingPongClipExpression {
+            clip: inner,
+            start,
+            end,
+        }) => {
+            let region = match (start, end) {
+                (None, None) => String::new(),
+                (start, end) => format!(" {}", source_region(*start, *end)),
+            };
+            (
+                format!("pingpong {}{}", clip(inner, PRIMARY), region),
+                PRIMARY,
+            )
+        }
+    };
+    if precedence < min_precedence {
+        format!("({})", text)
+    } else {
+        text
+    }
+}
+
+fn argument(argument: &ArgumentExpression) -> String {
+    match argument {
+        ArgumentExpression::Number(number) => number.to_string(),
+        ArgumentExpression::Clip(expression) => clip(expression, CHOICE),
+        ArgumentExpression::Beat(expression) => {
+            let text = beat(expression, BEAT_CHAIN);
+            // a lone digit would be read as a number argument
+            if text.chars().all(|c| c.is_ascii_digit()) {
+                format!("({})", text)
+            } else {
+                text
+            }
+        }
+    }
+}
+
+fn number(number: &NumberExpression) -> String {
+    match number {
+        NumberExpression::Literal(literal) => literal.to_string(),
+        NumberExpression::Parameter(name) => name.clone(),
+    }
+}
+
+fn source_region(start: Option<f64>, end: Option<f64>) -> String {
+    let seconds = |seconds: Option<f64>| seconds.map_or(String::new(), |s| format!("{}s", s));
+    format!("[{}:{}]", seconds(start), seconds(end))
+}
+
+fn source_offset(offset: &SourceOffset) -> String {
+    match offset {
+        SourceOffset::Seconds(seconds) => format!("{}s", seconds),
+        SourceOffset::Frame(frame) => format!("f{}", frame),
+        SourceOffset::Cue(name) => format!("cue({})", name),
+    }
+}
+
+/// Prints a speed the way it was written, with as many decimals as the power
+/// of ten it is divided by has zeros.
+fn speed(numerator: isize, denominator: usize) -> String {
+    let sign = if numerator < 0 { "-" } else { "" };
+    let numerator = numerator.unsigned_abs();
+    match denominator.to_string().len() - 1 {
+        0 => format!("{}{}", sign, numerator),
+        decimals => format!(
+            "{}{}.{:0decimals$}",
+            sign,
+            numerator / denominator,
+            numerator % denominator,
+            decimals = decimals
+        ),
+    }
+}
+
+// how tightly beat expressions bind, from loosest to tightest, as described
+// on `parse_beat_expression`
+const BEAT_CHAIN: usize = 0;
+const UNION: usize = 1;
+const INTERSECTION: usize = 2;
+const INVERSION: usize = 3;
+const BEAT_POSTFIX: usize = 4;
+const BEAT_PRIMARY: usize = 5;
+
+fn beat(expression: &BeatExpression, min_precedence: usize) -> String {
+    let (text, precedence) = match expression {
+        BeatExpression::DotBeatExpression(DotBeatExpression { beats, resolution }) => {
+            let pattern: String = beats
+                .iter()
+                .map(|beat| if *beat { '.' } else { '-' })
+                .collect();
+            (group(pattern, *resolution), BEAT_PRIMARY)
+        }
+        BeatExpression::NumberBeatExpression(NumberBeatExpression { beats, resolution }) => {
+            // spaced, so that it is not read as one number
+            let pattern: Vec<String> = beats.iter().map(|beat| beat.to_string()).collect();
+            (group(pattern.join(" "), *resolution), BEAT_PRIMARY)
+        }
+        BeatExpression::ReferenceBeatExpression(ReferenceBeatExpression { name, .. }) => {
+            (name.clone(), BEAT_PRIMARY)
+        }
+        BeatExpression::EuclideanBeatExpression(EuclideanBeatExpression {
+            hits,
+            steps,
+            rotation,
+            ..
+        }) => {
+            let text = match rotation {
+                NumberExpression::Literal(0) => format!("e({}, {})", number(hits), number(steps)),
+                rotation => format!(
+                    "e({}, {}, {})",
+                    number(hits),
+                    number(steps),
+                    number(rotation)
+                ),
+            };
+            (text, BEAT_PRIMARY)
+        }
+        BeatExpression::ParenthesesBeatExpression(ParenthesesBeatExpression { beat: inner }) => {
+            return beat(inner, min_precedence)
+        }
+        BeatExpression::BeatChainExpression(BeatChainExpression { beat_a, beat_b }) => (
+            format!("{} | {}", beat(beat_a, UNION), beat(beat_b, BEAT_CHAIN)),
+            BEAT_CHAIN,
+        ),
+        BeatExpression::UnionBeatExpression(UnionBeatExpression { beat_a, beat_b }) => (
+            format!("{} + {}", beat(beat_a, UNION), beat(beat_b, INTERSECTION)),
+            UNION,
+        ),
+        BeatExpression::IntersectionBeatExpression(IntersectionBeatExpression {
+            beat_a,
+            beat_b,
+        }) => (
+            format!(
+                "{} & {}",
+                beat(beat_a, INTERSECTION),
+                beat(beat_b, INVERSION)
+            ),
+            INTERSECTION,
+        ),
+        BeatExpression::InvertedBeatExpression(InvertedBeatExpression { beat: inner }) => {
+            (format!("!{}", beat(inner, INVERSION)), INVERSION)
+        }
+        BeatExpression::RotatedBeatExpression(RotatedBeatExpression { beat: inner, steps }) => (
+            format!("{} << {}", beat(inner, BEAT_POSTFIX), number(steps)),
+            BEAT_POSTFIX,
+        ),
+        BeatExpression::RepeatedBeatExpression(RepeatedBeatExpression {
+            beat: inner,
+            repetitions,
+        }) => (
+            format!("{} * {}", beat(inner, BEAT_POSTFIX), number(repetitions)),
+            BEAT_POSTFIX,
+        ),
+    };
+    if precedence < min_precedence {
+        format!("({})", text)
+    } else {
+        text
+    }
+}
+
+fn group(pattern: String, resolution: usize) -> String {
+    if resolution == DEFAULT_RESOLUTION {
+        pattern
+    } else {
+        format!("{{{}}}/{}", pattern, resolution)
+    }
+}
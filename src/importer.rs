@@ -0,0 +1,463 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::parser::{
+    beats_parser::{
+        BeatChainExpression, BeatExpression, IntersectionBeatExpression, InvertedBeatExpression,
+        ParenthesesBeatExpression, ReferenceBeatExpression, RepeatedBeatExpression,
+        RotatedBeatExpression, UnionBeatExpression,
+    },
+    error::ParseError,
+    parse, parse_library,
+    span::declaration_spans,
+    ApplyBeatExpression, ArgumentExpression, CallClipExpression, ChoiceClipExpression,
+    ClipChainExpression, ClipExpression, ClipLayerExpression, ClipLoopExpression, Declaration,
+    FitClipExpression, HoldClipExpression, Main, ParenthesesClipExpression, PingPongClipExpression,
+    ReferenceClipExpression, RestartExpression, ReverseClipExpression, SpeedClipExpression,
+    TruncatedClipExpression,
+};
+
+#[derive(Debug)]
+pub enum ImportError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, ParseError),
+    Cycle(Vec<PathBuf>),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImportError::Read(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ImportError::Parse(path, e) => write!(f, "in {}: {}", path.display(), e),
+            ImportError::Cycle(paths) => write!(
+                f,
+                "import cycle: {}",
+                paths
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(" -> ")
+            ),
+        }
+    }
+}
+
+/// Loads a main .chop file and inlines the declarations of everything it
+/// imports. `files` collects every file that was read, so they can be watched,
+/// with the main file first. The spans of a declaration count the file it
+/// comes from among them.
+///
+/// A library imported twice into the same namespace, like one that two
+/// imports of the main file both import, is inlined once.
+#[derive(Default)]
+pub struct Importer {
+    pub files: Vec<PathBuf>,
+    stack: Vec<PathBuf>,
+    /// The namespaces the file being loaded is in, outermost first.
+    namespaces: Vec<String>,
+    /// The names each library declares before its namespace is added, by its
+    /// path and the namespaces it was inlined into.
+    loaded: HashMap<(PathBuf, Vec<String>), Vec<String>>,
+}
+
+/// The declarations an import inlines, and the names declared by the
+/// libraries it imports again, which were inlined by an import before.
+struct Imported {
+    declarations: Vec<Declaration>,
+    repeated: Vec<String>,
+}
+
+impl Importer {
+    pub fn load(&mut self, path: &Path) -> Result<Main, ImportError> {
+        let input = self.read(path)?;
+        self.load_source(path, &input)
+    }
+
+    /// Like `load`, but with the source of the main file given instead of read.
+    pub fn load_source(&mut self, path: &Path, input: &str) -> Result<Main, ImportError> {
+        if !self.files.iter().any(|p| p == path) {
+            self.files.insert(0, path.to_path_buf());
+        }
+        let mut main = parse(input).map_err(|e| ImportError::Parse(path.to_path_buf(), e))?;
+        self.stack.push(canonical(path)?);
+        let declarations = self.resolve(path, main.declarations);
+        self.stack.pop();
+        main.declarations = declarations?.declarations;
+        Ok(main)
+    }
+
+    fn load_library(
+        &mut self,
+        path: &Path,
+        namespace: &Option<String>,
+    ) -> Result<Imported, ImportError> {
+        let canonical_path = canonical(path)?;
+        if let Some(start) = self.stack.iter().position(|p| *p == canonical_path) {
+            let mut cycle = self.stack[start..].to_vec();
+            cycle.push(canonical_path);
+            return Err(ImportError::Cycle(cycle));
+        }
+        let mut namespaces = self.namespaces.clone();
+        namespaces.extend(namespace.clone());
+        let key = (canonical_path, namespaces);
+        if let Some(names) = self.loaded.get(&key) {
+            return Ok(Imported {
+                declarations: Vec::new(),
+                repeated: names.iter().map(|name| qualify(namespace, name)).collect(),
+            });
+        }
+        let input = self.read(path)?;
+        let mut declarations =
+            parse_library(&input).map_err(|e| ImportError::Parse(path.to_path_buf(), e))?;
+        let file = self
+            .files
+            .iter()
+            .position(|p| p == path)
+            .expect("read adds the path");
+        for declaration in &mut declarations {
+            declaration_spans(declaration, &mut |span| span.file = file);
+        }
+        self.stack.push(key.0.clone());
+        let outer = std::mem::replace(&mut self.namespaces, key.1.clone());
+        let imported = self.resolve(path, declarations);
+        self.namespaces = outer;
+        self.stack.pop();
+        let mut imported = imported?;
+        let mut names = declared_names(&imported.declarations);
+        names.extend(imported.repeated.iter().cloned());
+        self.loaded.insert(key, names);
+        if let Some(namespace) = namespace {
+            add_namespace(&mut imported.declarations, &imported.repeated, namespace);
+        }
+        for name in &mut imported.repeated {
+            *name = qualify(namespace, name);
+        }
+        Ok(imported)
+    }
+
+    /// Replaces the imports among `declarations` by the declarations they refer to.
+    fn resolve(
+        &mut self,
+        path: &Path,
+        declarations: Vec<Declaration>,
+    ) -> Result<Imported, ImportError> {
+        let directory = path.parent().unwrap_or(Path::new(""));
+        let mut resolved = Imported {
+            declarations: Vec::new(),
+            repeated: Vec::new(),
+        };
+        for declaration in declarations {
+            match declaration {
+                Declaration::ImportDeclaration(import) => {
+                    let mut imported =
+                        self.load_library(&directory.join(&import.path), &import.namespace)?;
+                    resolved.declarations.append(&mut imported.declarations);
+                    resolved.repeated.append(&mut imported.repeated);
+                }
+                declaration => resolved.declarations.push(declaration),
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn read(&mut self, path: &Path) -> Result<String, ImportError> {
+        if !self.files.iter().any(|p| p == path) {
+            self.files.push(path.to_path_buf());
+        }
+        fs::read_to_string(path).map_err(|e| ImportError::Read(path.to_path_buf(), e))
+    }
+}
+
+fn canonical(path: &Path) -> Result<PathBuf, ImportError> {
+    fs::canonicalize(path).map_err(|e| ImportError::Read(path.to_path_buf(), e))
+}
+
+/// `name` as it is known outside of `namespace`.
+fn qualify(namespace: &Option<String>, name: &str) -> String {
+    match namespace {
+        Some(namespace) => format!("{}.{}", namespace, name),
+        None => name.to_string(),
+    }
+}
+
+/// The names of the clips and beats among `declarations`.
+fn declared_names(declarations: &[Declaration]) -> Vec<String> {
+    declarations
+        .iter()
+        .filter_map(|declaration| match declaration {
+            Declaration::ClipDeclaration(cd) => Some(cd.name.clone()),
+            Declaration::BeatDeclaration(bd) => Some(bd.name.clone()),
+            Declaration::ImportDeclaration(_) | Declaration::SceneDeclaration(_) => None,
+        })
+        .collect()
+}
+
+/// Prefixes every name declared in `declarations` or in `repeated`, and every
+/// reference to one of them, with `namespace.`. Parameters shadow declared
+/// names.
+fn add_namespace(declarations: &mut [Declaration], repeated: &[String], namespace: &str) {
+    let names: HashSet<String> = declared_names(declarations)
+        .into_iter()
+        .chain(repeated.iter().cloned())
+        .collect();
+    let prefix = |name: &mut String| {
+        if names.contains(name) {
+            *name = format!("{}.{}", namespace, name);
+        }
+    };
+    for declaration in declarations {
+        match declaration {
+            Declaration::ClipDeclaration(cd) => {
+                prefix(&mut cd.name);
+                let parameters = &cd.parameters;
+                rename_clip_references(&mut cd.expression, &|name: &mut String| {
+                    if !parameters.contains(name) {
+                        prefix(name)
+                    }
+                });
+            }
+            Declaration::BeatDeclaration(bd) => {
+                prefix(&mut bd.name);
+                rename_beat_references(&mut bd.expression, &prefix);
+            }
+            Declaration::SceneDeclaration(sd) => {
+                // scenes are never referenced, so they have a namespace of their own
+                sd.name = format!("{}.{}", namespace, sd.name);
+                rename_clip_references(&mut sd.expression, &prefix);
+            }
+            Declaration::ImportDeclaration(_) => (),
+        }
+    }
+}
+
+fn rename_clip_references(clip: &mut ClipExpression, rename: &impl Fn(&mut String)) {
+    match clip {
+        ClipExpression::Empty | ClipExpression::RawVideo(_) | ClipExpression::MultiVideo(_) => (),
+        ClipExpression::Reference(ReferenceClipExpression { name, .. }) => rename(name),
+        ClipExpression::Call(CallClipExpression {
+            name, arguments, ..
+        }) => {
+            rename(name);
+            for argument in arguments {
+                match argument {
+                    ArgumentExpression::Number(_) => (),
+                    ArgumentExpression::Clip(clip) => rename_clip_references(clip, rename),
+                    ArgumentExpression::Beat(beat) => rename_beat_references(beat, rename),
+                }
+            }
+        }
+        ClipExpression::Chain(ClipChainExpression { clip_a, clip_b })
+        | ClipExpression::Layer(ClipLayerExpression { clip_a, clip_b }) => {
+            rename_clip_references(clip_a, rename);
+            rename_clip_references(clip_b, rename);
+        }
+        ClipExpression::Loop(ClipLoopExpression { clip, .. })
+        | ClipExpression::Truncated(TruncatedClipExpression { clip, .. })
+        | ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression { clip })
+        | ClipExpression::Reverse(ReverseClipExpression { clip })
+        | ClipExpression::Hold(HoldClipExpression { clip })
+        | ClipExpression::Speed(SpeedClipExpression { clip, .. })
+        | ClipExpression::Fit(FitClipExpression { clip, .. })
+        | ClipExpression::PingPong(PingPongClipExpression { clip, .. }) => {
+            rename_clip_references(clip, rename)
+        }
+        ClipExpression::Choice(ChoiceClipExpression { alternatives }) => {
+            for alternative in alternatives {
+                rename_clip_references(&mut alternative.clip, rename);
+            }
+        }
+        ClipExpression::Restart(RestartExpression {
+            beat_expression,
+            clip_expression,
+        })
+        | ClipExpression::ApplyBeat(ApplyBeatExpression {
+            beat_expression,
+            clip_expression,
+        }) => {
+            rename_beat_references(beat_expression, rename);
+            rename_clip_references(clip_expression, rename);
+        }
+    }
+}
+
+fn rename_beat_references(beat: &mut BeatExpression, rename: &impl Fn(&mut String)) {
+    match beat {
+        BeatExpression::DotBeatExpression(_)
+        | BeatExpression::NumberBeatExpression(_)
+        | BeatExpression::EuclideanBeatExpression(_) => (),
+        BeatExpression::ReferenceBeatExpression(ReferenceBeatExpression { name, .. }) => {
+            rename(name)
+        }
+        BeatExpression::BeatChainExpression(BeatChainExpression { beat_a, beat_b })
+        | BeatExpression::UnionBeatExpression(UnionBeatExpression { beat_a, beat_b })
+        | BeatExpression::IntersectionBeatExpression(IntersectionBeatExpression {
+            beat_a,
+            beat_b,
+        }) => {
+            rename_beat_references(beat_a, rename);
+            rename_beat_references(beat_b, rename);
+        }
+        BeatExpression::InvertedBeatExpression(InvertedBeatExpression { beat })
+        | BeatExpression::RotatedBeatExpression(RotatedBeatExpression { beat, .. })
+        | BeatExpression::RepeatedBeatExpression(RepeatedBeatExpression { beat, .. })
+        | BeatExpression::ParenthesesBeatExpression(ParenthesesBeatExpression { beat }) => {
+            rename_beat_references(beat, rename)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reducer::tests::source, util::TestDirectory};
+    use std::cell::RefCell;
+
+    /// Writes `files` into a directory of their own and returns it, to be
+    /// removed when it is dropped, and the path of the first.
+    fn write(test: &str, files: &[(&str, &str)]) -> (TestDirectory, PathBuf) {
+        let directory = TestDirectory::new(test);
+        for (name, source) in files {
+            fs::write(directory.path().join(name), source).unwrap();
+        }
+        let path = directory.path().join(files[0].0);
+        (directory, path)
+    }
+
+    /// The declared clips, beats and scenes, each with the names it refers to.
+    fn declarations(path: &Path) -> Vec<(String, Vec<String>)> {
+        let main = Importer::default().load(path).unwrap();
+        main.declarations
+            .iter()
+            .map(|declaration| {
+                let references = RefCell::new(Vec::new());
+                let collect = |name: &mut String| references.borrow_mut().push(name.clone());
+                let name = match declaration {
+                    Declaration::ClipDeclaration(cd) => {
+                        rename_clip_references(&mut cd.expression.clone(), &collect);
+                        cd.name.clone()
+                    }
+                    Declaration::BeatDeclaration(bd) => {
+                        rename_beat_references(&mut bd.expression.clone(), &collect);
+                        bd.name.clone()
+                    }
+                    Declaration::SceneDeclaration(sd) => {
+                        rename_clip_references(&mut sd.expression.clone(), &collect);
+                        sd.name.clone()
+                    }
+                    Declaration::ImportDeclaration(_) => unreachable!("imports are inlined"),
+                };
+                (name, references.into_inner())
+            })
+            .collect()
+    }
+
+    fn names(declarations: &[(String, Vec<String>)]) -> Vec<&str> {
+        declarations.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn a_library_imported_twice_is_inlined_once() {
+        let (_directory, path) = write(
+            "diamond",
+            &[
+                (
+                    "main.chop",
+                    &source("import 'a.chop'; import 'b.chop'; a | b"),
+                ),
+                ("a.chop", "import 'lib.chop'; clip a = l;"),
+                ("b.chop", "import 'lib.chop'; clip b = l * 2;"),
+                ("lib.chop", "clip l = 'l';"),
+            ],
+        );
+        assert_eq!(names(&declarations(&path)), ["l", "a", "b"]);
+    }
+
+    #[test]
+    fn a_library_imported_again_is_referred_to_in_its_namespace() {
+        let (_directory, path) = write(
+            "diamond-namespace",
+            &[
+                (
+                    "main.chop",
+                    &source("import 'a.chop' as x; import 'b.chop' as x; x.a | x.b"),
+                ),
+                ("a.chop", "import 'lib.chop'; clip a = l;"),
+                ("b.chop", "import 'lib.chop'; clip b = l * 2;"),
+                ("lib.chop", "clip l = 'l';"),
+            ],
+        );
+        let declarations = declarations(&path);
+        assert_eq!(names(&declarations), ["x.l", "x.a", "x.b"]);
+        assert_eq!(declarations[2].1, ["x.l"]);
+    }
+
+    #[test]
+    fn a_library_imported_into_two_namespaces_is_inlined_twice() {
+        let (_directory, path) = write(
+            "two-namespaces",
+            &[
+                (
+                    "main.chop",
+                    &source("import 'lib.chop' as p; import 'lib.chop' as q; p.l | q.l"),
+                ),
+                ("lib.chop", "clip k = 'k'; clip l = k;"),
+            ],
+        );
+        let declarations = declarations(&path);
+        assert_eq!(names(&declarations), ["p.k", "p.l", "q.k", "q.l"]);
+        assert_eq!(declarations[3].1, ["q.k"]);
+    }
+
+    #[test]
+    fn an_import_cycle_is_an_error() {
+        let (_directory, path) = write(
+            "cycle",
+            &[
+                ("main.chop", &source("import 'a.chop'; a")),
+                ("a.chop", "import 'b.chop'; clip a = b;"),
+                ("b.chop", "import 'a.chop'; clip b = 'b';"),
+            ],
+        );
+        match Importer::default().load(&path) {
+            Err(ImportError::Cycle(cycle)) => {
+                let names: Vec<_> = cycle.iter().map(|p| p.file_name().unwrap()).collect();
+                assert_eq!(names, ["a.chop", "b.chop", "a.chop"]);
+            }
+            other => panic!("expected a cycle, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn a_namespace_prefixes_declarations_but_not_parameters() {
+        let (_directory, path) = write(
+            "namespace",
+            &[
+                ("main.chop", &source("import 'lib.chop' as n; n.f(n.k)")),
+                (
+                    "lib.chop",
+                    "clip k = 'k'; beat b = .-; clip f(k) = b @ k | g; \
+                     clip g = k; scene s = f(k);",
+                ),
+            ],
+        );
+        assert_eq!(
+            declarations(&path),
+            [
+                ("n.k".to_string(), vec![]),
+                ("n.b".to_string(), vec![]),
+                (
+                    "n.f".to_string(),
+                    vec!["n.b".to_string(), "k".to_string(), "n.g".to_string()]
+                ),
+                ("n.g".to_string(), vec!["n.k".to_string()]),
+                (
+                    "n.s".to_string(),
+                    vec!["n.f".to_string(), "n.k".to_string()]
+                ),
+            ]
+        );
+    }
+}
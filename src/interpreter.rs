@@ -1,4 +1,6 @@
-use crate::reducer::{ClipCommand, Time};
+use crate::reducer::{ClipCommand, Program, ReduceError, ReducedClip, Source};
+use crate::rng::Rng;
+use crate::time::Time;
 use std::io::{stdout, Write};
 use std::process::Command;
 
@@ -6,19 +8,52 @@ pub struct Interpreter {
     fps: f64,
     time: f64,
     bpm: f64,
+    beat_unit: f64,
+    bar_length: f64,
     beats: f64,
     display_state: Vec<DisplayState>,
     commands: Vec<(Time, ClipCommand)>,
     commands_idx: usize,
     loop_length: f64,
+    program: Option<Program>,
+    /// The scene playing, or `None` for the main expression.
+    scene: Option<String>,
+    next_scene: Option<(Option<String>, SwitchAt)>,
+    rng: Rng,
+}
+
+/// When a scene that is switched to starts playing.
+#[derive(Debug, Clone, Copy)]
+pub enum SwitchAt {
+    /// At the next bar line, or at the end of the loop if that comes first.
+    Bar,
+    LoopEnd,
 }
 
 #[derive(Debug)]
 enum DisplayState {
     None,
+    /// `frame` is fractional, so clips can play slower or faster than the
+    /// files were recorded.
     Single {
-        file: String,
-        frame: usize,
+        file: Source,
+        frame: f64,
+        speed: f64,
+    },
+    /// `progress` runs from 0 to 1 over the clip, however fast the tempo is.
+    Fitted {
+        file: Source,
+        start: Option<f64>,
+        end: Option<f64>,
+        progress: f64,
+        length: f64,
+    },
+    /// `travel` counts the frames played, forwards and backwards.
+    PingPong {
+        file: Source,
+        start: Option<f64>,
+        end: Option<f64>,
+        travel: f64,
     },
     Multi {
         file: String,
@@ -30,17 +65,112 @@ enum DisplayState {
 }
 
 impl Interpreter {
-    pub fn new(fps: f64, bpm: f64, commands: Vec<(Time, ClipCommand)>, loop_length: f64) -> Self {
+    pub fn new(
+        fps: f64,
+        bpm: f64,
+        commands: Vec<(Time, ClipCommand)>,
+        loop_length: f64,
+        rng: Rng,
+    ) -> Self {
         Self {
             fps,
             bpm,
+            beat_unit: 4.0,
+            bar_length: 1.0,
             time: 0.0,
             beats: 0.0,
             commands_idx: 0,
             display_state: vec![DisplayState::None],
             commands,
             loop_length,
+            program: None,
+            scene: None,
+            next_scene: None,
+            rng,
+        }
+    }
+
+    /// Plays a take of `program`, and a new take every time it loops. The
+    /// scene playing keeps playing, if `program` still declares it. If the
+    /// first take fails, the program before keeps playing.
+    pub fn set_program(&mut self, program: Program) -> Result<(), ReduceError> {
+        let is_first = self.program.is_none();
+        let has_scene = |scene: &Option<String>| match scene {
+            Some(name) => program.scene_names().contains(&name.as_str()),
+            None => program.has_main_expression(),
+        };
+        let scene = if has_scene(&self.scene) {
+            self.scene.clone()
+        } else {
+            program.scene_names().first().map(|name| name.to_string())
+        };
+        let clip = take(&program, &scene, &mut self.rng)?;
+        if scene != self.scene || (is_first && !program.scene_names().is_empty()) {
+            println!("scene {}", scene_label(&scene));
+        }
+        if !matches!(&self.next_scene, Some((next, _)) if has_scene(next)) {
+            self.next_scene = None;
+        }
+        self.scene = scene;
+        self.program = Some(program);
+        self.set_commands(clip.commands, clip.length.into());
+        Ok(())
+    }
+
+    /// Switches to the scene called `name`, or to the main expression for
+    /// `None`, at the next bar or loop end. Returns whether there is such a
+    /// scene.
+    pub fn switch_scene(&mut self, name: Option<&str>, at: SwitchAt) -> bool {
+        let Some(program) = &self.program else {
+            return false;
+        };
+        let exists = match name {
+            Some(name) => program.scene_names().contains(&name),
+            None => program.has_main_expression(),
+        };
+        if exists {
+            let scene = name.map(String::from);
+            match at {
+                SwitchAt::Bar => println!("scene {} at the next bar", scene_label(&scene)),
+                SwitchAt::LoopEnd => {
+                    println!("scene {} at the end of the loop", scene_label(&scene))
+                }
+            }
+            self.next_scene = Some((scene, at));
+        }
+        exists
+    }
+
+    /// Switches to the scene declared `number`th, counting from 1, or to the
+    /// main expression for 0.
+    pub fn switch_scene_number(&mut self, number: usize, at: SwitchAt) -> bool {
+        let name = match (number, &self.program) {
+            (0, _) => None,
+            (number, Some(program)) => match program.scene_names().get(number - 1) {
+                Some(name) => Some(name.to_string()),
+                None => return false,
+            },
+            (_, None) => return false,
+        };
+        self.switch_scene(name.as_deref(), at)
+    }
+
+    /// The scene playing, or `None` for the main expression.
+    pub fn active_scene(&self) -> Option<&str> {
+        self.scene.as_deref()
+    }
+
+    /// Starts the scene playing over, with a new take. If the take fails, the
+    /// one before plays again.
+    fn restart(&mut self) -> Result<(), ReduceError> {
+        self.time = 0.0;
+        self.display_state.clear();
+        self.commands_idx = 0;
+        if let Some(program) = &self.program {
+            let clip = take(program, &self.scene, &mut self.rng)?;
+            self.set_commands(clip.commands, clip.length.into());
         }
+        Ok(())
     }
 
     pub fn set_commands(&mut self, commands: Vec<(Time, ClipCommand)>, loop_length: f64) {
@@ -52,6 +182,21 @@ impl Interpreter {
         self.bpm = bpm;
     }
 
+    /// Sets the note value the bpm counts, `8` for eighth notes.
+    pub fn set_beat_unit(&mut self, beat_unit: f64) {
+        self.beat_unit = beat_unit;
+    }
+
+    /// Sets the length of a bar in whole notes, which scene switches wait for.
+    pub fn set_bar_length(&mut self, bar_length: f64) {
+        self.bar_length = bar_length;
+    }
+
+    /// The number of video frames `time` lasts at the current tempo.
+    fn frames(&self, time: &Time) -> f64 {
+        time.seconds(self.bpm, self.beat_unit) * self.fps
+    }
+
     pub fn reset_beat(&mut self) {
         self.beats = 0.0001;
         self.time = 0.0;
@@ -59,10 +204,12 @@ impl Interpreter {
         self.commands_idx = 0;
     }
 
-    pub fn advance_time(&mut self, seconds: f64) -> Vec<FrameCommand> {
+    /// The frames to show now, and then moves on by `seconds`. Fails where a
+    /// new take has to be made and cannot be.
+    pub fn advance_time(&mut self, seconds: f64) -> Result<Vec<FrameCommand>, ReduceError> {
         'find_command: loop {
             if let Some((time, _)) = &self.commands.get(self.commands_idx) {
-                if (time.num as f64 / time.denom as f64) > self.beats {
+                if f64::from(time) > self.beats {
                     break 'find_command;
                 }
             };
@@ -72,37 +219,65 @@ impl Interpreter {
             if let Some((_, command)) = self.commands.get(self.commands_idx) {
                 let layer = command.layer();
                 if self.display_state.len() <= layer {
-                    self.display_state.push(DisplayState::None);
+                    self.display_state
+                        .resize_with(layer + 1, || DisplayState::None);
                 }
                 match command {
                     ClipCommand::PlayClip(name, layer) => {
                         self.display_state[*layer] = DisplayState::Single {
                             file: name.clone(),
-                            frame: 0,
+                            frame: 0.0,
+                            speed: 1.0,
                         }
                     }
                     ClipCommand::PlayClipFrom(name, layer, time) => {
                         self.display_state[*layer] = DisplayState::Single {
                             file: name.clone(),
-                            frame: ((time.num as f64 / time.denom as f64) * self.bpm) as usize,
+                            frame: self.frames(time),
+                            speed: 1.0,
                         }
                     }
-                    ClipCommand::PlayMulti(name, subs_amt, extension) => {
-                        self.display_state[0] = DisplayState::Multi {
+                    ClipCommand::PlayClipAt(name, layer, time, speed) => {
+                        self.display_state[*layer] = DisplayState::Single {
+                            file: name.clone(),
+                            frame: self.frames(time),
+                            speed: speed.into(),
+                        }
+                    }
+                    ClipCommand::PlayClipFit(name, layer, fit) => {
+                        let length = f64::from(&fit.length);
+                        self.display_state[*layer] = DisplayState::Fitted {
                             file: name.clone(),
+                            start: fit.start,
+                            end: fit.end,
+                            progress: f64::from(&fit.from) / length,
+                            length,
+                        }
+                    }
+                    ClipCommand::PlayClipPingPong(name, layer, pingpong) => {
+                        self.display_state[*layer] = DisplayState::PingPong {
+                            file: name.clone(),
+                            start: pingpong.start,
+                            end: pingpong.end,
+                            travel: self.frames(&pingpong.from),
+                        }
+                    }
+                    ClipCommand::PlayMulti(multi, layer) => {
+                        self.display_state[*layer] = DisplayState::Multi {
+                            file: multi.path.clone(),
                             sub: 0,
-                            subs_amt: *subs_amt,
+                            subs_amt: multi.subclips,
                             frame: 0,
-                            extension: extension.clone(),
+                            extension: multi.extension.clone(),
                         }
                     }
-                    ClipCommand::PlayMultiFrom(name, time, subs_amt, extension) => {
-                        self.display_state[0] = DisplayState::Multi {
-                            file: name.clone(),
+                    ClipCommand::PlayMultiFrom(multi, layer, time) => {
+                        self.display_state[*layer] = DisplayState::Multi {
+                            file: multi.path.clone(),
                             sub: 0,
-                            subs_amt: *subs_amt,
-                            frame: ((time.num as f64 / time.denom as f64) * self.bpm) as usize,
-                            extension: extension.clone(),
+                            subs_amt: multi.subclips,
+                            frame: self.frames(time) as usize,
+                            extension: multi.extension.clone(),
                         }
                     }
                     ClipCommand::MultiNext(layer) => {
@@ -120,7 +295,9 @@ impl Interpreter {
                             }
                         }
                     }
-                    ClipCommand::Stop(layer) => self.display_state[*layer] = DisplayState::None,
+                    ClipCommand::Stop(layer) | ClipCommand::End(layer) => {
+                        self.display_state[*layer] = DisplayState::None
+                    }
                 };
             }
             self.commands_idx += 1;
@@ -130,9 +307,38 @@ impl Interpreter {
         for display_state in &self.display_state {
             let command = match display_state {
                 DisplayState::None => FrameCommand::ShowNone,
-                DisplayState::Single { file, frame } => FrameCommand::ShowSingleFrame {
+                DisplayState::Single { file, frame, speed } if *speed < 0.0 => {
+                    FrameCommand::ShowReversedFrame {
+                        file: file.clone(),
+                        frame: *frame as usize,
+                    }
+                }
+                DisplayState::Single { file, frame, .. } => FrameCommand::ShowSingleFrame {
                     file: file.clone(),
-                    frame: *frame,
+                    frame: *frame as usize,
+                },
+                DisplayState::Fitted {
+                    file,
+                    start,
+                    end,
+                    progress,
+                    length: _,
+                } => FrameCommand::ShowFittedFrame {
+                    file: file.clone(),
+                    start: *start,
+                    end: *end,
+                    progress: *progress,
+                },
+                DisplayState::PingPong {
+                    file,
+                    start,
+                    end,
+                    travel,
+                } => FrameCommand::ShowPingPongFrame {
+                    file: file.clone(),
+                    start: *start,
+                    end: *end,
+                    travel: *travel as usize,
                 },
                 DisplayState::Multi {
                     file,
@@ -141,7 +347,10 @@ impl Interpreter {
                     frame,
                     extension,
                 } => FrameCommand::ShowSingleFrame {
-                    file: format!("{}_{}{}", file.clone(), sub, extension),
+                    file: Source {
+                        path: format!("{}_{}{}", file.clone(), sub, extension),
+                        offset: None,
+                    },
                     frame: *frame,
                 },
             };
@@ -150,18 +359,30 @@ impl Interpreter {
 
         self.time += seconds;
         let old_beats = self.beats;
-        self.beats += self.bpm * seconds / (self.fps * 4.0);
-        if (self.beats * 4.0) as usize != (old_beats * 4.0) as usize || self.beats == 0.0 {
+        let advance = self.bpm * seconds / (self.fps * self.beat_unit);
+        self.beats += advance;
+        if (self.beats * self.beat_unit) as usize != (old_beats * self.beat_unit) as usize
+            || self.beats == 0.0
+        {
             // Play a beep sound
             print!("\x07");
             let _ = stdout().flush();
-            println!("{}", (self.beats * 4.0));
+            println!("{}", (self.beats * self.beat_unit));
         }
 
         for display_state in &mut self.display_state {
             match display_state {
                 DisplayState::None => (),
-                DisplayState::Single { file: _, frame } => *frame += 1,
+                // a clip playing backwards stays on the first frame once it gets there
+                DisplayState::Single {
+                    file: _,
+                    frame,
+                    speed,
+                } => *frame = (*frame + *speed).max(0.0),
+                DisplayState::Fitted {
+                    progress, length, ..
+                } => *progress += advance / *length,
+                DisplayState::PingPong { travel, .. } => *travel += 1.0,
                 DisplayState::Multi {
                     file: _,
                     sub: _,
@@ -171,19 +392,400 @@ impl Interpreter {
                 } => *frame += 1,
             }
         }
+        if let Some((scene, at)) = &self.next_scene {
+            let switch_beats = match at {
+                SwitchAt::Bar => (((old_beats / self.bar_length).floor() + 1.0) * self.bar_length)
+                    .min(self.loop_length),
+                SwitchAt::LoopEnd => self.loop_length,
+            };
+            if self.beats > switch_beats {
+                self.beats -= switch_beats;
+                self.scene = scene.clone();
+                self.next_scene = None;
+                println!("scene {}", scene_label(&self.scene));
+                self.restart()?;
+                return Ok(commands);
+            }
+        }
         if self.beats > self.loop_length {
             self.beats -= self.loop_length;
-            self.time = 0.0;
-            self.display_state.clear();
-            self.commands_idx = 0;
+            self.restart()?;
         }
 
-        commands
+        Ok(commands)
+    }
+}
+
+/// A take of the scene called `scene` of `program`, or of its main
+/// expression for `None`.
+fn take(
+    program: &Program,
+    scene: &Option<String>,
+    rng: &mut Rng,
+) -> Result<ReducedClip, ReduceError> {
+    match scene {
+        Some(name) => program.take_scene(name, rng),
+        None => None,
     }
+    .unwrap_or_else(|| program.take(rng))
+}
+
+fn scene_label(scene: &Option<String>) -> &str {
+    scene.as_deref().unwrap_or("main")
 }
 
 #[derive(Debug, Clone)]
 pub enum FrameCommand {
-    ShowSingleFrame { file: String, frame: usize },
+    ShowSingleFrame {
+        file: Source,
+        frame: usize,
+    },
+    ShowReversedFrame {
+        file: Source,
+        frame: usize,
+    },
+    ShowFittedFrame {
+        file: Source,
+        start: Option<f64>,
+        end: Option<f64>,
+        progress: f64,
+    },
+    ShowPingPongFrame {
+        file: Source,
+        start: Option<f64>,
+        end: Option<f64>,
+        travel: usize,
+    },
     ShowNone,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parser::SourceOffset, reducer::tests::program};
+
+    /// The commands of the take an interpreter seeded with `seed` starts
+    /// `source` with, and of the takes of the next `wraps` times it loops.
+    fn takes(source: &str, seed: u64, wraps: usize) -> Vec<Vec<(Time, ClipCommand)>> {
+        let mut interpreter = Interpreter::new(60.0, 120.0, Vec::new(), 1.0, Rng::new(seed));
+        interpreter.set_program(program(source)).unwrap();
+        let mut takes = vec![interpreter.commands.clone()];
+        while takes.len() <= wraps {
+            interpreter.advance_time(1.0 / 60.0).unwrap();
+            // a loop starts over at 0
+            if interpreter.time == 0.0 {
+                takes.push(interpreter.commands.clone());
+            }
+        }
+        takes
+    }
+
+    #[test]
+    fn a_seed_plays_the_same_takes() {
+        let source = "clip a = 'a' ? 'b';\na | ('c' ? 'd' * 2)";
+        let played = takes(source, 7, 30);
+        assert_eq!(played, takes(source, 7, 30));
+        assert_ne!(played, takes(source, 8, 30));
+    }
+
+    #[test]
+    fn a_fitted_clip_follows_the_tempo() {
+        let mut interpreter = Interpreter::new(60.0, 120.0, Vec::new(), 1.0, Rng::new(0));
+        interpreter.set_program(program("fit 'a' 4")).unwrap();
+        let progress = |seconds: usize, interpreter: &mut Interpreter| {
+            let mut commands = Vec::new();
+            for _ in 0..seconds * 60 {
+                commands = interpreter.advance_time(1.0 / 60.0).unwrap();
+            }
+            match &commands[..] {
+                [FrameCommand::ShowFittedFrame { progress, .. }] => *progress,
+                other => panic!("no fitted frame: {:?}", other),
+            }
+        };
+        // 4 beats take 120 frames at 120 bpm and 240 at 60 bpm, and a frame
+        // shows the progress before it
+        let half = progress(1, &mut interpreter);
+        assert!((half - 59.0 / 120.0).abs() < 1e-9, "{}", half);
+        interpreter.set_bpm(60.0);
+        let three_quarters = progress(1, &mut interpreter);
+        assert!(
+            (three_quarters - 179.0 / 240.0).abs() < 1e-9,
+            "{}",
+            three_quarters
+        );
+    }
+
+    #[test]
+    fn the_tempo_counts_beats_of_the_meter() {
+        let frames_per_loop = |meter: &str, beat_unit: f64| {
+            let mut interpreter = Interpreter::new(60.0, 120.0, Vec::new(), 1.0, Rng::new(0));
+            interpreter.set_beat_unit(beat_unit);
+            interpreter
+                .set_program(program(&format!("{}\n'a'", meter)))
+                .unwrap();
+            let mut frames = 1;
+            interpreter.advance_time(1.0 / 60.0).unwrap();
+            while interpreter.time != 0.0 {
+                interpreter.advance_time(1.0 / 60.0).unwrap();
+                frames += 1;
+            }
+            frames
+        };
+        // a bar of 4 quarter notes lasts 2 seconds at 120 bpm, one of 7
+        // eighth notes 3.5, and of 3 half notes 1.5, give or take the frame
+        // that rounding the steps may add
+        for (meter, beat_unit, frames) in [
+            ("", 4.0, 120),
+            ("meter = 7/8;", 8.0, 210),
+            ("meter = 3/2;", 2.0, 90),
+        ] {
+            let played = frames_per_loop(meter, beat_unit);
+            assert!(
+                (frames..=frames + 1).contains(&played),
+                "{}: {}",
+                meter,
+                played
+            );
+        }
+    }
+
+    #[test]
+    fn a_reversed_clip_counts_its_frames_down() {
+        let mut interpreter = Interpreter::new(60.0, 120.0, Vec::new(), 1.0, Rng::new(0));
+        interpreter.set_program(program("rev 'a'")).unwrap();
+        // the bar lasts 120 frames, which play from the last one back
+        for expected in (1..=120).rev() {
+            match &interpreter.advance_time(1.0 / 60.0).unwrap()[..] {
+                [FrameCommand::ShowReversedFrame { file, frame }] => {
+                    assert_eq!(file.path, "d/a.mov");
+                    assert!(frame.abs_diff(expected) <= 1, "{} {}", frame, expected);
+                }
+                other => panic!("no reversed frame: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn a_sliced_pingpong_travels_on_from_where_it_was_cut() {
+        let mut interpreter = Interpreter::new(60.0, 120.0, Vec::new(), 1.0, Rng::new(0));
+        interpreter
+            .set_program(program("(pingpong 'a' [1s:2s])[2:]"))
+            .unwrap();
+        // a beat lasts 30 frames at 120 bpm
+        for expected in 30..60 {
+            match &interpreter.advance_time(1.0 / 60.0).unwrap()[..] {
+                [FrameCommand::ShowPingPongFrame {
+                    start, end, travel, ..
+                }] => {
+                    assert_eq!((*start, *end), (Some(1.0), Some(2.0)));
+                    assert_eq!(*travel, expected);
+                }
+                other => panic!("no ping-pong frame: {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn a_clip_played_from_a_beat_starts_at_its_frame() {
+        let first_frame = |bpm: f64| {
+            let mut interpreter = Interpreter::new(60.0, bpm, Vec::new(), 1.0, Rng::new(0));
+            interpreter.set_program(program("('a'@f100)[2:]")).unwrap();
+            match &interpreter.advance_time(1.0 / 60.0).unwrap()[..] {
+                [FrameCommand::ShowSingleFrame { file, frame }] => {
+                    assert_eq!(file.offset, Some(SourceOffset::Frame(100)));
+                    *frame
+                }
+                other => panic!("no frame: {:?}", other),
+            }
+        };
+        // a beat in, counted from the offset, which the loader adds
+        assert_eq!(first_frame(120.0), 30);
+        assert_eq!(first_frame(60.0), 60);
+    }
+
+    fn interpreter(source: &str) -> Interpreter {
+        let mut interpreter = Interpreter::new(60.0, 120.0, Vec::new(), 1.0, Rng::new(0));
+        interpreter.set_program(program(source)).unwrap();
+        interpreter
+    }
+
+    /// The files shown over the next `frames` frames, each with the frame
+    /// it is first shown at.
+    fn shown(interpreter: &mut Interpreter, frames: usize) -> Vec<(usize, String)> {
+        let mut shown: Vec<(usize, String)> = Vec::new();
+        for frame in 0..frames {
+            let file = match &interpreter.advance_time(1.0 / 60.0).unwrap()[..] {
+                [FrameCommand::ShowSingleFrame { file, .. }] => file.path.clone(),
+                other => format!("{:?}", other),
+            };
+            if shown.last().is_none_or(|(_, last)| *last != file) {
+                shown.push((frame, file));
+            }
+        }
+        shown
+    }
+
+    /// Checks the files `shown` against `expected`, each first shown within
+    /// a frame of the one expected, as steps of a frame add up inexactly.
+    fn assert_shown(shown: Vec<(usize, String)>, expected: &[(usize, &str)]) {
+        let files: Vec<&str> = shown.iter().map(|(_, file)| file.as_str()).collect();
+        let expected_files: Vec<&str> = expected.iter().map(|(_, file)| *file).collect();
+        assert_eq!(files, expected_files, "{:?}", shown);
+        for ((frame, _), (expected, _)) in shown.iter().zip(expected) {
+            assert!(frame.abs_diff(*expected) <= 1, "{:?}", shown);
+        }
+    }
+
+    const SCENES: &str = "scene intro = 'i' * 4;\nscene drop = 'd' * 2;\n";
+
+    #[test]
+    fn scenes_switch_at_the_next_bar_or_loop_end() {
+        // a bar lasts 120 frames, and the first scene plays first
+        let mut interpreter = interpreter(SCENES);
+        assert_eq!(interpreter.active_scene(), Some("intro"));
+        assert_shown(shown(&mut interpreter, 30), &[(0, "d/i.mov")]);
+        assert!(interpreter.switch_scene(Some("drop"), SwitchAt::Bar));
+        assert_shown(
+            shown(&mut interpreter, 300),
+            &[(0, "d/i.mov"), (90, "d/d.mov")],
+        );
+        assert_eq!(interpreter.active_scene(), Some("drop"));
+        let mut interpreter = self::interpreter(SCENES);
+        assert!(interpreter.switch_scene(Some("drop"), SwitchAt::LoopEnd));
+        assert_shown(
+            shown(&mut interpreter, 600),
+            &[(0, "d/i.mov"), (480, "d/d.mov")],
+        );
+        // a bar after the loop end is not waited for
+        let mut interpreter = self::interpreter("scene a = 'a'[1:3];\nscene b = 'b';\n");
+        interpreter.switch_scene(Some("b"), SwitchAt::Bar);
+        assert_shown(
+            shown(&mut interpreter, 120),
+            &[(0, "d/a.mov"), (60, "d/b.mov")],
+        );
+    }
+
+    #[test]
+    fn scenes_are_switched_to_by_name_or_number() {
+        let mut interpreter = interpreter(SCENES);
+        assert!(!interpreter.switch_scene(Some("outro"), SwitchAt::Bar));
+        // without a main expression there is nothing to go back to
+        assert!(!interpreter.switch_scene(None, SwitchAt::Bar));
+        assert!(!interpreter.switch_scene_number(0, SwitchAt::Bar));
+        assert!(!interpreter.switch_scene_number(3, SwitchAt::Bar));
+        assert!(interpreter.switch_scene_number(2, SwitchAt::Bar));
+        shown(&mut interpreter, 121);
+        assert_eq!(interpreter.active_scene(), Some("drop"));
+        let mut interpreter = self::interpreter(&format!("{}'m'", SCENES));
+        assert_eq!(interpreter.active_scene(), None);
+        assert!(interpreter.switch_scene_number(1, SwitchAt::Bar));
+        shown(&mut interpreter, 121);
+        assert_eq!(interpreter.active_scene(), Some("intro"));
+        assert!(interpreter.switch_scene_number(0, SwitchAt::Bar));
+        assert_shown(
+            shown(&mut interpreter, 200),
+            &[(0, "d/i.mov"), (120, "d/m.mov")],
+        );
+    }
+
+    #[test]
+    fn a_reload_keeps_the_scene_playing() {
+        let mut interpreter = interpreter(SCENES);
+        interpreter.switch_scene(Some("drop"), SwitchAt::Bar);
+        shown(&mut interpreter, 121);
+        interpreter
+            .set_program(program("scene intro = 'i';\nscene drop = 'e';\n"))
+            .unwrap();
+        assert_eq!(interpreter.active_scene(), Some("drop"));
+        // a scene that is gone gives way to the first one
+        interpreter
+            .set_program(program("scene intro = 'i';\n"))
+            .unwrap();
+        assert_eq!(interpreter.active_scene(), Some("intro"));
+        // and a switch to one that is gone is dropped
+        interpreter.switch_scene(Some("intro"), SwitchAt::Bar);
+        interpreter.set_program(program("'m'")).unwrap();
+        assert_eq!(interpreter.active_scene(), None);
+        assert_shown(shown(&mut interpreter, 300), &[(0, "d/m.mov")]);
+    }
+
+    #[test]
+    fn every_loop_is_a_new_take() {
+        let played = takes("'a' ? 'b' ? 'c'", 1, 30);
+        let mut different: Vec<&Vec<(Time, ClipCommand)>> = Vec::new();
+        for take in &played {
+            if !different.contains(&take) {
+                different.push(take);
+            }
+        }
+        assert_eq!(different.len(), 3);
+        let played = takes("('a' * 2)[2:7] | 'b'", 1, 5);
+        assert!(played.iter().all(|take| *take == played[0]));
+    }
+
+    #[test]
+    fn multi_videos_switch_subclips_on_their_own_layer() {
+        let mut interpreter = interpreter("'a' : .---.--- @ multi 2 'x'");
+        let mut shown: Vec<(usize, Vec<String>)> = Vec::new();
+        for frame in 0..150 {
+            let files: Vec<String> = interpreter
+                .advance_time(1.0 / 60.0)
+                .unwrap()
+                .iter()
+                .map(|command| match command {
+                    FrameCommand::ShowSingleFrame { file, .. } => file.path.clone(),
+                    other => format!("{:?}", other),
+                })
+                .collect();
+            if shown.last().is_none_or(|(_, last)| *last != files) {
+                shown.push((frame, files));
+            }
+        }
+        // the beat hits at the start, so the first subclip shown is the second
+        let expected = [
+            (0, ["d/x_1.mov", "d/a.mov"]),
+            (30, ["d/x_0.mov", "d/a.mov"]),
+            (120, ["d/x_1.mov", "d/a.mov"]),
+        ];
+        assert_eq!(shown.len(), expected.len(), "{:?}", shown);
+        for ((frame, files), (expected_frame, expected_files)) in shown.iter().zip(expected) {
+            assert_eq!(files, &expected_files, "{:?}", shown);
+            assert!(frame.abs_diff(expected_frame) <= 1, "{:?}", shown);
+        }
+    }
+
+    #[test]
+    fn a_layer_shows_nothing_once_its_clip_ends() {
+        // the layers shown on every frame, the top one first
+        let layers = |source: &str| {
+            let mut interpreter = interpreter(source);
+            let mut shown: Vec<(usize, Vec<String>)> = Vec::new();
+            for frame in 0..120 {
+                let files: Vec<String> = interpreter
+                    .advance_time(1.0 / 60.0)
+                    .unwrap()
+                    .iter()
+                    .rev()
+                    .map(|command| match command {
+                        FrameCommand::ShowSingleFrame { file, .. } => file.path.clone(),
+                        FrameCommand::ShowNone => "-".into(),
+                        other => format!("{:?}", other),
+                    })
+                    .collect();
+                if shown.last().is_none_or(|(_, last)| *last != files) {
+                    shown.push((frame, files));
+                }
+            }
+            shown
+        };
+        let shown = layers("'a'[1:2] : 'b'");
+        assert_eq!(shown.len(), 2, "{:?}", shown);
+        assert_eq!(shown[0], (0, vec!["d/a.mov".into(), "d/b.mov".into()]));
+        assert_eq!(shown[1].1, ["-", "d/b.mov"]);
+        assert!(shown[1].0.abs_diff(30) <= 1, "{:?}", shown);
+        assert_eq!(
+            layers("hold 'a'[1:2] : 'b'"),
+            [(0, vec!["d/a.mov".into(), "d/b.mov".into()])]
+        );
+    }
+}
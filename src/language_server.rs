@@ -0,0 +1,805 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use lsp_server::{Connection, ErrorCode, Message, Notification, Request, Response};
+use lsp_types::{
+    notification::{
+        DidChangeTextDocument, DidCloseTextDocument, DidOpenTextDocument,
+        Notification as NotificationTrait, PublishDiagnostics,
+    },
+    request::{Completion, GotoDefinition, HoverRequest, Request as RequestTrait},
+    CompletionItem, CompletionItemKind, CompletionOptions, CompletionResponse, Diagnostic,
+    DiagnosticSeverity, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+    DidOpenTextDocumentParams, GotoDefinitionResponse, Hover, HoverContents,
+    HoverProviderCapability, Location, MarkupContent, MarkupKind, OneOf, Position,
+    PublishDiagnosticsParams, Range, ServerCapabilities, TextDocumentSyncCapability,
+    TextDocumentSyncKind, Url,
+};
+
+use crate::{
+    importer::{ImportError, Importer},
+    parser::{
+        error::ParseError, parse, parse_bpm_declaration, parse_declaration,
+        parse_directory_declaration, parse_extension_declaration, parse_meter_declaration,
+        whitespace::whitespace0, Declaration,
+    },
+    reducer::{reduce, Program, ReduceError, ReduceErrorKind},
+    rng::Rng,
+    time::Time,
+};
+
+/// Serves the Language Server Protocol over stdin and stdout until the
+/// editor shuts the server down.
+pub fn run() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let (connection, io_threads) = Connection::stdio();
+    serve(&connection)?;
+    io_threads.join()?;
+    Ok(())
+}
+
+pub fn serve(connection: &Connection) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let capabilities = ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::FULL)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec!["'".into(), "\"".into()]),
+            ..Default::default()
+        }),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        ..Default::default()
+    };
+    connection.initialize(serde_json::to_value(capabilities)?)?;
+
+    // the text of every open document, as the editor has it
+    let mut documents: HashMap<Url, String> = HashMap::new();
+    let mut analyses: HashMap<Url, Analysis> = HashMap::new();
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    return Ok(());
+                }
+                let response = respond(&documents, &mut analyses, request);
+                connection.sender.send(Message::Response(response))?;
+            }
+            Message::Notification(notification) => {
+                let Some(uri) = update(&mut documents, notification)? else {
+                    continue;
+                };
+                // the other documents may import the one that changed, and a
+                // closed one is not asked about
+                analyses.retain(|analyzed, _| *analyzed == uri && documents.contains_key(analyzed));
+                let diagnostics = documents
+                    .get(&uri)
+                    .map(|text| analysis(&mut analyses, &uri, text).diagnostics.clone())
+                    .unwrap_or_default();
+                let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+                connection
+                    .sender
+                    .send(Message::Notification(Notification::new(
+                        PublishDiagnostics::METHOD.into(),
+                        params,
+                    )))?;
+            }
+            Message::Response(_) => (),
+        }
+    }
+    Ok(())
+}
+
+/// Keeps `documents` in sync with the editor, and returns the document that
+/// changed.
+fn update(
+    documents: &mut HashMap<Url, String>,
+    notification: Notification,
+) -> Result<Option<Url>, serde_json::Error> {
+    match notification.method.as_str() {
+        DidOpenTextDocument::METHOD => {
+            let params: DidOpenTextDocumentParams = serde_json::from_value(notification.params)?;
+            let uri = params.text_document.uri;
+            documents.insert(uri.clone(), params.text_document.text);
+            Ok(Some(uri))
+        }
+        DidChangeTextDocument::METHOD => {
+            let params: DidChangeTextDocumentParams = serde_json::from_value(notification.params)?;
+            let uri = params.text_document.uri;
+            // the whole text is sent on every change
+            if let Some(change) = params.content_changes.into_iter().last() {
+                documents.insert(uri.clone(), change.text);
+            }
+            Ok(Some(uri))
+        }
+        DidCloseTextDocument::METHOD => {
+            let params: DidCloseTextDocumentParams = serde_json::from_value(notification.params)?;
+            documents.remove(&params.text_document.uri);
+            Ok(Some(params.text_document.uri))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn respond(
+    documents: &HashMap<Url, String>,
+    analyses: &mut HashMap<Url, Analysis>,
+    request: Request,
+) -> Response {
+    match request.method.as_str() {
+        Completion::METHOD => handle::<Completion>(request, |params| {
+            let position = params.text_document_position;
+            let uri = position.text_document.uri;
+            let text = documents.get(&uri)?;
+            let analysis = analysis(analyses, &uri, text);
+            Some(CompletionResponse::Array(complete(
+                &uri,
+                text,
+                analysis,
+                offset(text, position.position),
+            )))
+        }),
+        GotoDefinition::METHOD => handle::<GotoDefinition>(request, |params| {
+            let position = params.text_document_position_params;
+            let uri = position.text_document.uri;
+            let text = documents.get(&uri)?;
+            let name = word_at(text, offset(text, position.position))?;
+            let definition = analysis(analyses, &uri, text)
+                .definitions
+                .iter()
+                .find(|definition| definition.name == name)?;
+            Some(GotoDefinitionResponse::Scalar(definition.location.clone()))
+        }),
+        HoverRequest::METHOD => handle::<HoverRequest>(request, |params| {
+            let position = params.text_document_position_params;
+            let uri = position.text_document.uri;
+            let text = documents.get(&uri)?;
+            hover(
+                analysis(analyses, &uri, text),
+                text,
+                offset(text, position.position),
+            )
+        }),
+        _ => Response::new_err(
+            request.id,
+            ErrorCode::MethodNotFound as i32,
+            format!("unknown request {}", request.method),
+        ),
+    }
+}
+
+fn handle<R: RequestTrait>(
+    request: Request,
+    respond: impl FnOnce(R::Params) -> R::Result,
+) -> Response {
+    match serde_json::from_value(request.params) {
+        Ok(params) => Response::new_ok(request.id, respond(params)),
+        Err(e) => Response::new_err(request.id, ErrorCode::InvalidParams as i32, e.to_string()),
+    }
+}
+
+/// What the server knows about a document once it has imported and reduced
+/// it. Every request about the document needs it, so it is kept until the
+/// document, or one it may import, changes.
+struct Analysis {
+    /// The text it was made from.
+    text: String,
+    diagnostics: Vec<Diagnostic>,
+    definitions: Vec<Definition>,
+    /// What the document reduces to, with the note value a beat of its meter
+    /// is, if it reduces.
+    program: Option<(Program, usize)>,
+}
+
+/// The analysis of the document at `uri`, made anew if `text` changed since
+/// the last one.
+fn analysis<'a>(analyses: &'a mut HashMap<Url, Analysis>, uri: &Url, text: &str) -> &'a Analysis {
+    if analyses
+        .get(uri)
+        .is_none_or(|analysis| analysis.text != text)
+    {
+        let (diagnostics, program) = reduced(uri, text);
+        let analysis = Analysis {
+            text: text.to_string(),
+            diagnostics,
+            definitions: definitions(uri, text),
+            program,
+        };
+        analyses.insert(uri.clone(), analysis);
+    }
+    &analyses[uri]
+}
+
+/// The errors of a document, from parsing it and what it imports and from
+/// reducing it, and what it reduces to, if it does.
+fn reduced(uri: &Url, text: &str) -> (Vec<Diagnostic>, Option<(Program, usize)>) {
+    // without a file, imports cannot be found, so only the syntax is checked
+    let Some(path) = uri.to_file_path().ok().filter(|path| path.exists()) else {
+        return match parse(text) {
+            Ok(_) => (Vec::new(), None),
+            Err(e) => (vec![parse_diagnostic(text, &e)], None),
+        };
+    };
+    let main = match Importer::default().load_source(&path, text) {
+        Ok(main) => main,
+        Err(ImportError::Parse(file, e)) if file == path => {
+            return (vec![parse_diagnostic(text, &e)], None)
+        }
+        Err(e) => {
+            let range = match &e {
+                ImportError::Read(file, _) | ImportError::Parse(file, _) => {
+                    import_range(text, &path, file)
+                }
+                ImportError::Cycle(_) => None,
+            };
+            let diagnostic = Diagnostic::new_simple(range.unwrap_or_default(), e.to_string());
+            return (vec![diagnostic], None);
+        }
+    };
+    let unit = main.meter_declaration.unwrap_or_default().unit;
+    let directory = &main.directory_declaration.directory;
+    // named the way clips refer to them, with the directory as it is written
+    let available_files = fs::read_dir(media_directory(Some(&path), directory))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            if !entry.file_type().ok()?.is_file() {
+                return None;
+            }
+            Some(format!("{}{}", directory, entry.file_name().to_str()?))
+        })
+        .collect();
+    match reduce(main, &available_files) {
+        Ok(program) => {
+            let warnings = program
+                .warnings()
+                .iter()
+                .map(|warning| reduce_diagnostic(text, warning))
+                .collect();
+            (warnings, Some((program, unit)))
+        }
+        Err(problems) => {
+            let errors = problems
+                .iter()
+                .map(|problem| reduce_diagnostic(text, problem))
+                .collect();
+            (errors, None)
+        }
+    }
+}
+
+/// Marks where `problem` is in the document. A problem in an imported file
+/// is marked where the name it is about is used, if it is.
+fn reduce_diagnostic(text: &str, problem: &ReduceError) -> Diagnostic {
+    let range = match problem.span {
+        Some(span) if span.file == 0 => Some(range(text, span.start, span.end)),
+        _ => {
+            let name = match &problem.kind {
+                ReduceErrorKind::UnknownClip(name)
+                | ReduceErrorKind::UnknownBeat(name)
+                | ReduceErrorKind::UnknownParameter(name)
+                | ReduceErrorKind::RecursiveCall(name)
+                | ReduceErrorKind::NameCollision(name)
+                | ReduceErrorKind::Unused(name)
+                | ReduceErrorKind::ArgumentCount { name, .. } => Some(name),
+                ReduceErrorKind::RecursiveDefinition(names) => names.first(),
+                ReduceErrorKind::ArgumentKind { parameter, .. } => Some(parameter),
+                ReduceErrorKind::MissingFile(_)
+                | ReduceErrorKind::EmptyRange { .. }
+                | ReduceErrorKind::RangeBeforeStart { .. }
+                | ReduceErrorKind::ZeroLengthLoop(_)
+                | ReduceErrorKind::ZeroLengthFit
+                | ReduceErrorKind::Overflow
+                | ReduceErrorKind::LongBeat => None,
+            };
+            name.and_then(|name| Some((find_word(text, name)?, name)))
+                .map(|(start, name)| range(text, start, start + name.len()))
+        }
+    };
+    let severity = if problem.is_warning() {
+        DiagnosticSeverity::WARNING
+    } else {
+        DiagnosticSeverity::ERROR
+    };
+    Diagnostic {
+        severity: Some(severity),
+        ..Diagnostic::new_simple(range.unwrap_or_default(), problem.to_string())
+    }
+}
+
+fn parse_diagnostic(text: &str, e: &ParseError) -> Diagnostic {
+    let line_start: usize = text
+        .split_inclusive('\n')
+        .take(e.line - 1)
+        .map(str::len)
+        .sum();
+    let start = text[line_start..]
+        .char_indices()
+        .nth(e.column - 1)
+        .map_or(text.len(), |(index, _)| line_start + index);
+    // mark what was found instead of what was expected
+    let length = e.snippet.split_whitespace().next().map_or(0, str::len);
+    Diagnostic::new_simple(range(text, start, start + length), e.message())
+}
+
+/// Where the document at `path` imports `file` itself, not through another
+/// import.
+fn import_range(text: &str, path: &Path, file: &Path) -> Option<Range> {
+    let directory = path.parent().unwrap_or(Path::new(""));
+    let tokens = tokens(text);
+    let (start, imported) = tokens.windows(2).find_map(|pair| match pair {
+        [(_, Token::Name("import")), (start, Token::Quoted(imported))]
+            if directory.join(imported) == file =>
+        {
+            Some((*start, imported))
+        }
+        _ => None,
+    })?;
+    Some(range(text, start, start + imported.len()))
+}
+
+fn complete(uri: &Url, text: &str, analysis: &Analysis, offset: usize) -> Vec<CompletionItem> {
+    let line = &text[text[..offset].rfind('\n').map_or(0, |i| i + 1)..offset];
+    // inside quotes only file names can follow
+    if line.matches(['\'', '"']).count() % 2 == 1 {
+        return media_files(uri, text)
+            .into_iter()
+            .map(|name| CompletionItem {
+                label: name,
+                kind: Some(CompletionItemKind::FILE),
+                ..Default::default()
+            })
+            .collect();
+    }
+    analysis
+        .definitions
+        .iter()
+        .filter(|definition| !matches!(definition.kind, DefinitionKind::Scene))
+        .map(|definition| CompletionItem {
+            label: definition.name.clone(),
+            kind: Some(match definition.kind {
+                DefinitionKind::Clip(ref parameters) if !parameters.is_empty() => {
+                    CompletionItemKind::FUNCTION
+                }
+                DefinitionKind::Clip(_) => CompletionItemKind::VARIABLE,
+                DefinitionKind::Beat => CompletionItemKind::CONSTANT,
+                DefinitionKind::Scene => CompletionItemKind::MODULE,
+            }),
+            detail: Some(definition.signature()),
+            ..Default::default()
+        })
+        .collect()
+}
+
+/// The names of the files in the declared directory with the declared
+/// extension, the way a clip refers to them.
+fn media_files(uri: &Url, text: &str) -> Vec<String> {
+    let (Some(directory), Some(extension)) = header(text) else {
+        return Vec::new();
+    };
+    let path = uri.to_file_path().ok();
+    let Ok(entries) = fs::read_dir(media_directory(path.as_deref(), &directory)) else {
+        return Vec::new();
+    };
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            Some(name.strip_suffix(&extension)?.to_string())
+        })
+        .filter(|name| !name.is_empty())
+        .collect();
+    names.sort();
+    names
+}
+
+/// Where the files of the document at `path` are. A relative directory is
+/// found from the document, not from wherever the editor started the server.
+fn media_directory(path: Option<&Path>, directory: &str) -> PathBuf {
+    match path {
+        Some(path) => path.parent().unwrap_or(Path::new("")).join(directory),
+        None => PathBuf::from(directory),
+    }
+}
+
+fn hover(analysis: &Analysis, text: &str, offset: usize) -> Option<Hover> {
+    let name = word_at(text, offset)?;
+    let definition = analysis
+        .definitions
+        .iter()
+        .find(|definition| definition.name == name)?;
+    let mut value = format!("```\n{}\n```", definition.signature());
+    if let Some(beats) = reduced_length(analysis, definition) {
+        value.push_str(&format!("\n\n{} beats long", beats));
+    }
+    Some(Hover {
+        contents: HoverContents::Markup(MarkupContent {
+            kind: MarkupKind::Markdown,
+            value,
+        }),
+        range: None,
+    })
+}
+
+/// How many beats of the meter a take of a clip or scene lasts. Clips with
+/// parameters only have a length where they are called.
+fn reduced_length(analysis: &Analysis, definition: &Definition) -> Option<Time> {
+    let (program, unit) = analysis.program.as_ref()?;
+    let take = match definition.kind {
+        DefinitionKind::Clip(ref parameters) if parameters.is_empty() => {
+            program.take_named_clip(&definition.name, &mut Rng::new(0))
+        }
+        DefinitionKind::Scene => program.take_scene(&definition.name, &mut Rng::new(0)),
+        _ => None,
+    }?
+    .ok()?;
+    take.length.checked_mul(Time::from(*unit as i64))
+}
+
+enum DefinitionKind {
+    Clip(Vec<String>),
+    Beat,
+    Scene,
+}
+
+/// A declared name, with the namespaces of the imports it came through.
+struct Definition {
+    name: String,
+    kind: DefinitionKind,
+    location: Location,
+}
+
+impl Definition {
+    fn signature(&self) -> String {
+        match &self.kind {
+            DefinitionKind::Clip(parameters) if parameters.is_empty() => {
+                format!("clip {}", self.name)
+            }
+            DefinitionKind::Clip(parameters) => {
+                format!("clip {}({})", self.name, parameters.join(", "))
+            }
+            DefinitionKind::Beat => format!("beat {}", self.name),
+            DefinitionKind::Scene => format!("scene {}", self.name),
+        }
+    }
+}
+
+/// The declarations of a document and of everything it imports, as far as
+/// they parse, so that they are there while a declaration is being typed.
+fn definitions(uri: &Url, text: &str) -> Vec<Definition> {
+    let mut definitions = Vec::new();
+    let mut imported = Vec::new();
+    collect_definitions(uri, text, "", &mut imported, &mut definitions);
+    definitions
+}
+
+fn collect_definitions(
+    uri: &Url,
+    text: &str,
+    namespace: &str,
+    imported: &mut Vec<PathBuf>,
+    definitions: &mut Vec<Definition>,
+) {
+    let mut rest = skip_settings(skip_header(text));
+    while let Ok((next, declaration)) = parse_declaration(rest) {
+        let start = whitespace0(rest).map_or(rest, |(code, _)| code);
+        let start = text.len() - start.len();
+        let statement = &text[start..text.len() - next.len()];
+        let (name, kind) = match declaration {
+            Declaration::ClipDeclaration(declaration) => (
+                declaration.name,
+                DefinitionKind::Clip(declaration.parameters),
+            ),
+            Declaration::BeatDeclaration(declaration) => (declaration.name, DefinitionKind::Beat),
+            Declaration::SceneDeclaration(declaration) => (declaration.name, DefinitionKind::Scene),
+            Declaration::ImportDeclaration(import) => {
+                let namespace = match import.namespace {
+                    Some(inner) => format!("{}{}.", namespace, inner),
+                    None => namespace.to_string(),
+                };
+                let library = uri.to_file_path().ok().and_then(|path| {
+                    let path = path.parent()?.join(import.path);
+                    let path = fs::canonicalize(path).ok()?;
+                    let uri = Url::from_file_path(&path).ok()?;
+                    Some((path, uri))
+                });
+                if let Some((path, uri)) = library {
+                    if !imported.contains(&path) {
+                        imported.push(path.clone());
+                        if let Ok(library) = fs::read_to_string(&path) {
+                            collect_definitions(&uri, &library, &namespace, imported, definitions);
+                        }
+                    }
+                }
+                rest = skip_settings(next);
+                continue;
+            }
+        };
+        // the name follows the keyword
+        let keyword = statement.find(char::is_whitespace).unwrap_or(0);
+        let name_start = start + keyword + statement[keyword..].find(&name).unwrap_or(0);
+        definitions.push(Definition {
+            name: format!("{}{}", namespace, name),
+            kind,
+            location: Location::new(
+                uri.clone(),
+                range(text, name_start, name_start + name.len()),
+            ),
+        });
+        rest = skip_settings(next);
+    }
+}
+
+/// The directory and extension a main file declares.
+fn header(text: &str) -> (Option<String>, Option<String>) {
+    let Ok((rest, directory)) = parse_directory_declaration(text) else {
+        return (None, None);
+    };
+    let extension = parse_extension_declaration(rest)
+        .ok()
+        .map(|(_, extension)| extension.extension);
+    (Some(directory.directory), extension)
+}
+
+/// The declarations of a main file start after its directory and extension.
+/// A library has neither.
+fn skip_header(text: &str) -> &str {
+    let mut rest = text;
+    if let Ok((next, _)) = parse_directory_declaration(rest) {
+        rest = next;
+    }
+    if let Ok((next, _)) = parse_extension_declaration(rest) {
+        rest = next;
+    }
+    rest
+}
+
+/// The tempo and meter can be declared between any two declarations.
+fn skip_settings(text: &str) -> &str {
+    let mut rest = text;
+    loop {
+        if let Ok((next, _)) = parse_bpm_declaration(rest) {
+            rest = next;
+        } else if let Ok((next, _)) = parse_meter_declaration(rest) {
+            rest = next;
+        } else {
+            return rest;
+        }
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '.'
+}
+
+/// The possibly namespaced name the cursor is in.
+fn word_at(text: &str, offset: usize) -> Option<String> {
+    let start = text[..offset]
+        .rfind(|c| !is_name_char(c))
+        .map_or(0, |i| i + 1);
+    let end = text[offset..]
+        .find(|c| !is_name_char(c))
+        .map_or(text.len(), |i| offset + i);
+    let word = text[start..end].trim_matches('.');
+    (!word.is_empty()).then(|| word.to_string())
+}
+
+/// Where `name` first appears in `text` as a whole name, outside of comments
+/// and file names.
+fn find_word(text: &str, name: &str) -> Option<usize> {
+    tokens(text)
+        .into_iter()
+        .find_map(|(start, token)| match token {
+            Token::Name(word) if word == name => Some(start),
+            _ => None,
+        })
+}
+
+/// What a document is made of, as far as finding names in it goes.
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    /// A possibly namespaced name, or a number.
+    Name(&'a str),
+    /// What is between quotes, a file name or path.
+    Quoted(&'a str),
+}
+
+/// The tokens of `text` with where they start, leaving out comments the way
+/// `whitespace0` skips them.
+fn tokens(text: &str) -> Vec<(usize, Token<'_>)> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let start = text.len() - rest.len();
+        rest = if rest.starts_with("//") || c == '#' {
+            &rest[rest.find('\n').unwrap_or(rest.len())..]
+        } else if let Some(comment) = rest.strip_prefix("/*") {
+            comment.find("*/").map_or("", |end| &comment[end + 2..])
+        } else if c == '\'' || c == '"' {
+            let quoted = &rest[1..];
+            let end = quoted.find(c).unwrap_or(quoted.len());
+            tokens.push((start + 1, Token::Quoted(&quoted[..end])));
+            quoted.get(end + 1..).unwrap_or("")
+        } else if is_name_char(c) {
+            let end = rest.find(|c| !is_name_char(c)).unwrap_or(rest.len());
+            tokens.push((start, Token::Name(&rest[..end])));
+            &rest[end..]
+        } else {
+            &rest[c.len_utf8()..]
+        };
+    }
+    tokens
+}
+
+fn range(text: &str, start: usize, end: usize) -> Range {
+    Range::new(position(text, start), position(text, end))
+}
+
+/// Positions count lines and UTF-16 code units within them.
+fn position(text: &str, offset: usize) -> Position {
+    let line_start = text[..offset].rfind('\n').map_or(0, |i| i + 1);
+    Position::new(
+        text[..offset].matches('\n').count() as u32,
+        text[line_start..offset].encode_utf16().count() as u32,
+    )
+}
+
+fn offset(text: &str, position: Position) -> usize {
+    let line_start: usize = text
+        .split_inclusive('\n')
+        .take(position.line as usize)
+        .map(str::len)
+        .sum();
+    let line = text[line_start..].split('\n').next().unwrap_or_default();
+    let mut units = 0;
+    for (index, c) in line.char_indices() {
+        if units >= position.character as usize {
+            return line_start + index;
+        }
+        units += c.len_utf16();
+    }
+    line_start + line.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reducer::tests::source, util::TestDirectory};
+
+    /// A document saved as `name` in a directory of its own, so that it is
+    /// imported and reduced like one the editor has open. The directory is
+    /// removed when it is dropped.
+    fn saved(name: &str, text: &str) -> (TestDirectory, Url) {
+        let directory = TestDirectory::new(&format!("server-{}", name));
+        let path = directory.path().join(name);
+        fs::write(&path, text).unwrap();
+        (directory, Url::from_file_path(path).unwrap())
+    }
+
+    fn hovered(analysis: &Analysis, text: &str, name: &str) -> String {
+        let offset = find_word(text, name).unwrap();
+        match hover(analysis, text, offset).unwrap().contents {
+            HoverContents::Markup(markup) => markup.value,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn names_are_found_outside_of_comments_and_file_names() {
+        let text = "// kick\n/* kick */ clip kicks = 'kick' # kick\n;\nkick.a | kick";
+        assert_eq!(find_word(text, "kick"), text.rfind("kick"));
+        assert_eq!(find_word(text, "kicks"), text.find("kicks"));
+        assert_eq!(find_word(text, "snare"), None);
+        assert_eq!(
+            tokens("a /* unclosed"),
+            [(0, Token::Name("a"))],
+            "a comment that is not closed lasts until the end"
+        );
+        assert_eq!(tokens("'open"), [(1, Token::Quoted("open"))]);
+    }
+
+    #[test]
+    fn imports_are_found_where_the_document_makes_them() {
+        let text = "// import 'lib.chop' is below\nimport 'other/lib.chop';\nimport 'lib.chop';";
+        let path = Path::new("/songs/main.chop");
+        let at = |offset| Some(range(text, offset, offset + "lib.chop".len()));
+        assert_eq!(
+            import_range(text, path, Path::new("/songs/lib.chop")),
+            at(text.rfind("lib.chop").unwrap())
+        );
+        assert_eq!(
+            import_range(text, path, Path::new("/songs/other/lib.chop")),
+            Some(range(
+                text,
+                text.find("other").unwrap(),
+                text.find("other").unwrap() + "other/lib.chop".len()
+            ))
+        );
+        // an import of an import is not in the document
+        assert_eq!(
+            import_range(text, path, Path::new("/songs/deep.chop")),
+            None
+        );
+    }
+
+    #[test]
+    fn definitions_are_found_around_the_tempo_and_meter() {
+        let text = &source("meter = 3/4;\nclip a = 'a';\nbpm = 90;\nbeat b = .-;\nb @ a");
+        let uri = Url::parse("file:///songs/main.chop").unwrap();
+        let names: Vec<String> = definitions(&uri, text)
+            .into_iter()
+            .map(|definition| definition.name)
+            .collect();
+        assert_eq!(names, ["a", "b"]);
+    }
+
+    #[test]
+    fn hovering_shows_how_long_a_take_is() {
+        let text = &source("clip a = 'a' * 3;\nclip f(x) = x;\nscene drop = a | f('b');\na");
+        let (_directory, uri) = saved("hover.chop", text);
+        let mut analyses = HashMap::new();
+        let analysis = analysis(&mut analyses, &uri, text);
+        assert_eq!(
+            hovered(analysis, text, "a"),
+            "```\nclip a\n```\n\n12 beats long"
+        );
+        assert_eq!(hovered(analysis, text, "f"), "```\nclip f(x)\n```");
+        assert_eq!(
+            hovered(analysis, text, "drop"),
+            "```\nscene drop\n```\n\n16 beats long"
+        );
+    }
+
+    #[test]
+    fn a_clip_too_long_to_play_is_an_error_not_a_crash() {
+        let text = &source("clip a = ('a' ~ 0.00000000000000001) ~ 0.00000000000000001;\na");
+        let (_directory, uri) = saved("overflow.chop", text);
+        let mut analyses = HashMap::new();
+        let analysis = analysis(&mut analyses, &uri, text);
+        let messages: Vec<&str> = analysis
+            .diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect();
+        assert!(
+            messages.contains(&"the clip is too long to play"),
+            "{:?}",
+            messages
+        );
+        assert_eq!(hovered(analysis, text, "a"), "```\nclip a\n```");
+    }
+
+    #[test]
+    fn a_document_is_analyzed_again_only_once_it_changes() {
+        let text = &source("clip a = 'a';\na");
+        let (_directory, uri) = saved("cache.chop", text);
+        let mut analyses = HashMap::new();
+        let first = analysis(&mut analyses, &uri, text).text.as_ptr();
+        assert_eq!(analysis(&mut analyses, &uri, text).text.as_ptr(), first);
+        let changed = &source("clip a = 'a';\nb");
+        let analysis = analysis(&mut analyses, &uri, changed);
+        assert_eq!(&analysis.text, changed);
+        assert!(analysis
+            .diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.message == "unknown clip b"));
+    }
+
+    #[test]
+    fn files_are_found_from_the_document_wherever_the_server_runs() {
+        let text = "directory = 'media/'; extension = '.mov';\n'a' | 'b'";
+        let (_directory, uri) = saved("media.chop", text);
+        let media = uri.to_file_path().unwrap().with_file_name("media");
+        fs::create_dir_all(&media).unwrap();
+        fs::write(media.join("a.mov"), "").unwrap();
+        let mut analyses = HashMap::new();
+        let messages: Vec<&str> = analysis(&mut analyses, &uri, text)
+            .diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.message.as_str())
+            .collect();
+        assert_eq!(messages, ["file media/b.mov does not exist"]);
+        assert_eq!(media_files(&uri, text), ["a"]);
+    }
+}
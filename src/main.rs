@@ -1,17 +1,28 @@
 extern crate ffmpeg_next as ffmpeg;
 
 use crate::interpreter::Interpreter;
-use crate::source_watcher::SourceWatcher;
+use crate::source_watcher::{SourceWatcher, WarningPolicy};
 
 use bpm_controller::BpmController;
+use formatter::format_file;
+use rng::Rng;
 use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
 use video_player::play_video;
 
 mod bpm_controller;
+mod formatter;
+mod importer;
 mod interpreter;
+mod language_server;
 mod parser;
 mod reducer;
+mod rng;
 mod source_watcher;
+mod time;
 mod time_controller;
 mod util;
 mod video_loader;
@@ -21,15 +32,95 @@ mod video_reader;
 #[tokio::main]
 async fn main() -> Result<(), ffmpeg::Error> {
     let bpm_controller = BpmController::new(120.0);
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
+    let mut path = None;
+    let mut seed = None;
+    let mut policy = WarningPolicy::Reload;
+    let mut args = env::args().skip(1).peekable();
+    match args.peek().map(String::as_str) {
+        Some("fmt") => {
+            args.next();
+            fmt(args);
+            return Ok(());
+        }
+        Some("lsp") => {
+            if let Err(e) = language_server::run() {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+            return Ok(());
+        }
+        _ => (),
+    }
+    while let Some(arg) = args.next() {
+        if arg == "--seed" {
+            match args.next().and_then(|seed| seed.parse::<u64>().ok()) {
+                Some(s) => seed = Some(s),
+                None => {
+                    eprintln!("--seed needs a number");
+                    return Ok(());
+                }
+            }
+        } else if arg == "--strict" {
+            // a source with warnings keeps the one that is playing
+            policy = WarningPolicy::Block;
+        } else {
+            path = Some(arg);
+        }
+    }
+    let Some(path) = path else {
         eprintln!("Please provide a file path as a CLI argument");
         return Ok(());
-    }
-    let source_watcher = SourceWatcher::new(args[1].to_string());
+    };
+    let seed = seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or_default()
+    });
+    // printed so that a performance can be played again with --seed
+    println!("seed {}", seed);
+    let source_watcher = SourceWatcher::new(path, policy);
     let fps = 60.0;
-    let runner = Interpreter::new(fps, 120.0, Vec::new(), 1.0);
+    let runner = Interpreter::new(fps, 120.0, Vec::new(), 1.0, Rng::new(seed));
 
     let _ = play_video(fps, source_watcher, runner, bpm_controller);
     Ok(())
 }
+
+/// `choppo fmt <path>` prints the file in its canonical layout, and
+/// `choppo fmt --check <path>` fails if it is not in it.
+fn fmt(args: impl Iterator<Item = String>) {
+    let mut path = None;
+    let mut check = false;
+    for arg in args {
+        if arg == "--check" {
+            check = true;
+        } else {
+            path = Some(arg);
+        }
+    }
+    let Some(path) = path else {
+        eprintln!("Please provide a file path as a CLI argument");
+        process::exit(1);
+    };
+    let input = match fs::read_to_string(&path) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("could not read {}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    match format_file(Path::new(&path), &input) {
+        Ok(formatted) if check => {
+            if formatted != input {
+                eprintln!("{} is not formatted", path);
+                process::exit(1);
+            }
+        }
+        Ok(formatted) => print!("{}", formatted),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
@@ -1,16 +1,31 @@
+use std::fmt;
+
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until, take_while_m_n},
-    character::complete::{alpha1, alphanumeric1, char, digit1, multispace0},
-    combinator::{fail, opt, recognize},
-    multi::many0,
-    sequence::delimited,
-    IResult,
+    bytes::complete::take_until,
+    character::complete::{alpha1, alphanumeric1, char, digit1, satisfy},
+    combinator::{cut, eof, map, map_res, not, opt, peek, recognize, value, verify},
+    error::context,
+    multi::{many0, separated_list0, separated_list1},
+    sequence::{delimited, pair, preceded, terminated, tuple},
 };
 
-use self::beats_parser::{parse_beat_expression, BeatExpression};
+use self::{
+    beats_parser::{
+        chain_beats, parse_beat_chain_part, parse_beat_expression, parse_rest_of_beat_chain_part,
+        BeatChainExpression, BeatExpression, DotBeatExpression, EuclideanBeatExpression,
+        ParenthesesBeatExpression, ReferenceBeatExpression, RepeatedBeatExpression,
+        DEFAULT_RESOLUTION,
+    },
+    error::{token, IResult, ParseError, SyntaxError},
+    span::{clip_spans, declaration_spans, Span},
+    whitespace::{take_until_code, whitespace0},
+};
 
 pub mod beats_parser;
+pub mod error;
+pub mod span;
+pub mod whitespace;
 
 #[derive(Debug)]
 pub struct DirectoryDeclaration {
@@ -18,18 +33,15 @@ pub struct DirectoryDeclaration {
 }
 
 pub fn parse_directory_declaration(input: &str) -> IResult<&str, DirectoryDeclaration> {
-    let (input, _) = multispace0(input)?;
-    let (input, _) = tag("directory")(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, _) = char('=')(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, directory) = alt((
-        delimited(char('\''), recognize(take_until("'")), char('\'')),
-        delimited(char('"'), recognize(take_until("\"")), char('"')),
-    ))(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, _) = tag(";")(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("directory")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("=")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, directory) = context("quoted directory path", parse_quoted)(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token(";")(input)?;
+    let (input, _) = whitespace0(input)?;
 
     Ok((
         input,
@@ -45,18 +57,15 @@ pub struct ExtensionDeclaration {
 }
 
 pub fn parse_extension_declaration(input: &str) -> IResult<&str, ExtensionDeclaration> {
-    let (input, _) = multispace0(input)?;
-    let (input, _) = tag("extension")(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, _) = char('=')(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, directory) = alt((
-        delimited(char('\''), recognize(take_until("'")), char('\'')),
-        delimited(char('"'), recognize(take_until("\"")), char('"')),
-    ))(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, _) = tag(";")(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("extension")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("=")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, directory) = context("quoted file extension", parse_quoted)(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token(";")(input)?;
+    let (input, _) = whitespace0(input)?;
 
     Ok((
         input,
@@ -66,47 +75,179 @@ pub fn parse_extension_declaration(input: &str) -> IResult<&str, ExtensionDeclar
     ))
 }
 
+#[derive(Debug)]
+pub struct BpmDeclaration {
+    pub bpm: f64,
+}
+
+pub fn parse_bpm_declaration(input: &str) -> IResult<&str, BpmDeclaration> {
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("bpm")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("=")(input)?;
+    cut(parse_bpm)(input)
+}
+
+fn parse_bpm(input: &str) -> IResult<&str, BpmDeclaration> {
+    let (start, _) = whitespace0(input)?;
+    let (input, bpm) = context(
+        "tempo in beats per minute",
+        map_res(
+            recognize(pair(digit1, opt(pair(char('.'), digit1)))),
+            str::parse::<f64>,
+        ),
+    )(start)?;
+    // a tempo of 0 would never get to the next frame
+    if bpm <= 0.0 {
+        return Err(nom::Err::Failure(SyntaxError::expected(
+            start,
+            "tempo above 0",
+        )));
+    }
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token(";")(input)?;
+    let (input, _) = whitespace0(input)?;
+
+    Ok((input, BpmDeclaration { bpm }))
+}
+
+/// `meter = 7/8;` makes a bar seven beats long, each beat an eighth note.
+/// Without a declaration the meter is 4/4.
+#[derive(Debug, Clone, Copy)]
+pub struct MeterDeclaration {
+    pub beats: usize,
+    pub unit: usize,
+}
+
+impl Default for MeterDeclaration {
+    fn default() -> Self {
+        Self { beats: 4, unit: 4 }
+    }
+}
+
+pub fn parse_meter_declaration(input: &str) -> IResult<&str, MeterDeclaration> {
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("meter")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("=")(input)?;
+    cut(parse_meter)(input)
+}
+
+fn parse_meter(input: &str) -> IResult<&str, MeterDeclaration> {
+    let (input, _) = whitespace0(input)?;
+    let (input, beats) = context("beats per bar", verify(parse_number, |beats| *beats > 0))(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("/")(input)?;
+    let (input, _) = whitespace0(input)?;
+    // beats have to fall on the sixteenth grid
+    let (input, unit) = context(
+        "beat unit 1, 2, 4, 8 or 16",
+        verify(parse_number, |unit| [1, 2, 4, 8, 16].contains(unit)),
+    )(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token(";")(input)?;
+    let (input, _) = whitespace0(input)?;
+
+    Ok((input, MeterDeclaration { beats, unit }))
+}
+
+fn parse_quoted(input: &str) -> IResult<&str, &str> {
+    alt((
+        delimited(char('\''), recognize(take_until("'")), char('\'')),
+        delimited(char('"'), recognize(take_until("\"")), char('"')),
+    ))(input)
+}
+
+/// A possibly namespaced name like `kick` or `drums.kick`.
+pub fn parse_qualified_name(input: &str) -> IResult<&str, &str> {
+    recognize(separated_list1(char('.'), alphanumeric1))(input)
+}
+
 #[derive(Debug)]
 pub enum Declaration {
     ClipDeclaration(ClipDeclaration),
     BeatDeclaration(BeatDeclaration),
+    ImportDeclaration(ImportDeclaration),
+    SceneDeclaration(SceneDeclaration),
 }
 
 pub fn parse_declaration(input: &str) -> IResult<&str, Declaration> {
-    let (rest_input, input) = take_until(";")(input)?;
-    let (input, declaration) = alt((parse_clip_declaration, parse_beat_declaration))(input)?;
-    let (input, _) = multispace0(input)?;
-    if !input.is_empty() {
-        return fail(input);
-    }
-    let (input, _) = tag(";")(rest_input)?;
-    let (input, _) = multispace0(input)?;
+    // the main expression never contains a ';', so this has to be a declaration
+    take_until_code(";")(input)?;
+    let (input, declaration) = context(
+        "declaration",
+        cut(alt((
+            parse_clip_declaration,
+            parse_beat_declaration,
+            parse_import_declaration,
+            parse_scene_declaration,
+        ))),
+    )(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = cut(token(";"))(input)?;
+    let (input, _) = whitespace0(input)?;
 
     Ok((input, declaration))
 }
 
+#[derive(Debug)]
+pub struct ImportDeclaration {
+    pub path: String,
+    pub namespace: Option<String>,
+}
+
+pub fn parse_import_declaration(input: &str) -> IResult<&str, Declaration> {
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("import")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, path) = context("quoted file path", parse_quoted)(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, namespace) = opt(parse_import_namespace)(input)?;
+    let (input, _) = whitespace0(input)?;
+
+    Ok((
+        input,
+        Declaration::ImportDeclaration(ImportDeclaration {
+            path: path.into(),
+            namespace: namespace.map(|n| n.into()),
+        }),
+    ))
+}
+
+fn parse_import_namespace(input: &str) -> IResult<&str, &str> {
+    let (input, _) = token("as")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, namespace) = context("namespace", alpha1)(input)?;
+
+    Ok((input, namespace))
+}
+
 #[derive(Debug)]
 pub struct BeatDeclaration {
     pub expression: BeatExpression,
     pub name: String,
+    /// Of the name.
+    pub span: Span,
 }
 
 pub fn parse_beat_declaration(input: &str) -> IResult<&str, Declaration> {
-    let (input, _) = multispace0(input)?;
-    let (input, _) = tag("beat")(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, name) = alpha1(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, _) = char('=')(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("beat")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (rest, name) = context("beat name", alpha1)(input)?;
+    let span = Span::between(input, rest);
+    let (input, _) = whitespace0(rest)?;
+    let (input, _) = token("=")(input)?;
+    let (input, _) = whitespace0(input)?;
     let (input, beat_expression) = parse_beat_expression(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
 
     Ok((
         input,
         Declaration::BeatDeclaration(BeatDeclaration {
             expression: beat_expression,
             name: name.to_string(),
+            span,
         }),
     ))
 }
@@ -115,28 +256,79 @@ pub fn parse_beat_declaration(input: &str) -> IResult<&str, Declaration> {
 pub struct ClipDeclaration {
     pub expression: ClipExpression,
     pub name: String,
+    pub parameters: Vec<String>,
+    /// Of the name.
+    pub span: Span,
 }
 
 pub fn parse_clip_declaration(input: &str) -> IResult<&str, Declaration> {
-    let (input, _) = multispace0(input)?;
-    let (input, _) = alt((tag("clip"), tag("clp")))(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, name) = alpha1(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, _) = char('=')(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = alt((token("clip"), token("clp")))(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (rest, name) = context("clip name", alpha1)(input)?;
+    let span = Span::between(input, rest);
+    let (input, _) = whitespace0(rest)?;
+    let (input, parameters) = opt(parse_clip_parameters)(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("=")(input)?;
+    let (input, _) = whitespace0(input)?;
     let (input, expression) = parse_clip_expression(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
 
     Ok((
         input,
         Declaration::ClipDeclaration(ClipDeclaration {
             expression,
             name: name.into(),
+            parameters: parameters.unwrap_or_default(),
+            span,
+        }),
+    ))
+}
+
+/// `scene drop = ...;` declares a section of a performance that can be
+/// switched to while it plays.
+#[derive(Debug)]
+pub struct SceneDeclaration {
+    pub expression: ClipExpression,
+    pub name: String,
+    /// Of the name.
+    pub span: Span,
+}
+
+pub fn parse_scene_declaration(input: &str) -> IResult<&str, Declaration> {
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("scene")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (rest, name) = context("scene name", alpha1)(input)?;
+    let span = Span::between(input, rest);
+    let (input, _) = whitespace0(rest)?;
+    let (input, _) = token("=")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, expression) = parse_clip_expression(input)?;
+    let (input, _) = whitespace0(input)?;
+
+    Ok((
+        input,
+        Declaration::SceneDeclaration(SceneDeclaration {
+            expression,
+            name: name.into(),
+            span,
         }),
     ))
 }
 
+fn parse_clip_parameters(input: &str) -> IResult<&str, Vec<String>> {
+    let (input, _) = token("(")(input)?;
+    let (input, parameters) = separated_list0(
+        token(","),
+        delimited(whitespace0, context("parameter name", alpha1), whitespace0),
+    )(input)?;
+    let (input, _) = token(")")(input)?;
+
+    Ok((input, parameters.into_iter().map(String::from).collect()))
+}
+
 #[derive(Debug, Clone)]
 pub enum ClipExpression {
     Empty,
@@ -148,144 +340,840 @@ pub enum ClipExpression {
     RawVideo(RawVideoExpression),
     MultiVideo(MultiVideoExpression),
     Reference(ReferenceClipExpression),
+    Call(CallClipExpression),
     ApplyBeat(ApplyBeatExpression),
     ParenthesesClipExpression(ParenthesesClipExpression),
+    Choice(ChoiceClipExpression),
+    Reverse(ReverseClipExpression),
+    Hold(HoldClipExpression),
+    Speed(SpeedClipExpression),
+    Fit(FitClipExpression),
+    PingPong(PingPongClipExpression),
 }
 
+/// Parses a clip expression. From loosest to tightest binding:
+///
+/// - `a ? b` chooses one of its alternatives: `a | b ? c` is `(a | b) ? c`.
+///   A weight like `a {3} ? b` makes an alternative three times as likely.
+/// - `beat @ clip` and `beat >> clip` take everything to their right as the
+///   clip, so `x @ a | b` is `x @ (a | b)`. Their beat is a whole beat chain,
+///   so `x | y @ a` is `(x | y) @ a`, but not a layer: `a : x | y @ b` is
+///   `(a : x) | (y @ b)`.
+/// - `a | b` chains, right associative: `a | b | c` is `a | (b | c)`.
+/// - `a : b` layers, right associative: `a : b | c` is `(a : b) | c`.
+/// - `rev clip` plays backwards, and takes the postfix operators after
+///   it: `rev a[1:2] * 2` is `rev ((a[1:2]) * 2)`. The same goes for
+///   `hold clip`.
+/// - `fit clip n` only takes a name, file or parenthesized clip, and its
+///   postfix operators apply to the stretched clip: `fit a 4 * 2` is
+///   `(fit a 4) * 2`. The same goes for `pingpong clip`.
+/// - `clip * n` loops, `clip[from:to]` truncates and `clip ~ speed` changes
+///   the playback speed, applied left to right: `a : b[1:2] ~ 2` is
+///   `a : ((b[1:2]) ~ 2)`.
 pub fn parse_clip_expression(input: &str) -> IResult<&str, ClipExpression> {
+    let (input, operand) = parse_clip_or_beat(input)?;
+    Ok((input, expect_clip(input, operand)?))
+}
+
+/// What is read where a clip can be the beat of an `@` or `>>`, as nothing
+/// tells them apart before that.
+enum Operand {
+    /// Text that can only be read as a clip.
+    Clip(ClipExpression),
+    /// A name, `-`, `e(hits, steps)`, or loops, parentheses or `|` chains of
+    /// those, which are beats where a beat operator, `@` or `>>` follows.
+    ClipOrBeat(ClipExpression),
+    /// A beat that no `@` or `>>` follows, which is only of use in
+    /// parentheses, as one can still follow them.
+    Beat(BeatExpression),
+}
+
+/// The clip of an operand, or the error of a beat without an `@` or `>>`.
+fn expect_clip(
+    input: &str,
+    operand: Operand,
+) -> Result<ClipExpression, nom::Err<SyntaxError<&str>>> {
+    match operand {
+        Operand::Clip(clip) | Operand::ClipOrBeat(clip) => Ok(clip),
+        Operand::Beat(_) => match alt((token("@"), token(">>")))(input) {
+            Err(e) => Err(e),
+            Ok(_) => unreachable!("a beat is only left over where no `@` or `>>` follows"),
+        },
+    }
+}
+
+/// Parses a clip expression, or a beat, as what is in parentheses can be the
+/// beat of an `@` or `>>` after them.
+fn parse_clip_or_beat(input: &str) -> IResult<&str, Operand> {
+    let (mut input, alternative) = match parse_binary_clip_expression(input, CHAIN_PRECEDENCE)? {
+        (rest, Operand::Beat(beat)) => return Ok((rest, Operand::Beat(beat))),
+        (rest, operand) if token("{")(rest).is_err() && token("?")(rest).is_err() => {
+            return Ok((rest, operand))
+        }
+        (rest, operand) => parse_choice_weight_of(rest, expect_clip(rest, operand)?)?,
+    };
+    let mut alternatives = vec![alternative];
+    while let Ok((rest, _)) = token("?")(input) {
+        let (rest, alternative) = parse_choice_alternative(rest)?;
+        alternatives.push(alternative);
+        input = rest;
+    }
+    if alternatives.len() == 1 {
+        return Err(nom::Err::Error(SyntaxError::expected(input, "'?'")));
+    }
+
+    Ok((
+        input,
+        Operand::Clip(ClipExpression::Choice(ChoiceClipExpression {
+            alternatives: alternatives
+                .into_iter()
+                .map(|(clip, weight)| ChoiceAlternative {
+                    clip,
+                    weight: weight.unwrap_or(1),
+                })
+                .collect(),
+        })),
+    ))
+}
+
+fn parse_choice_alternative(input: &str) -> IResult<&str, (ClipExpression, Option<usize>)> {
+    let (input, operand) = parse_binary_clip_expression(input, CHAIN_PRECEDENCE)?;
+    let clip = expect_clip(input, operand)?;
+    parse_choice_weight_of(input, clip)
+}
+
+fn parse_choice_weight_of(
+    input: &str,
+    clip: ClipExpression,
+) -> IResult<&str, (ClipExpression, Option<usize>)> {
+    if token("{")(input).is_ok() {
+        let (input, weight) = parse_choice_weight(input)?;
+        return Ok((input, (clip, Some(weight))));
+    }
+
+    Ok((input, (clip, None)))
+}
+
+fn parse_choice_weight(input: &str) -> IResult<&str, usize> {
+    let (input, _) = token("{")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, weight) = context("weight", verify(parse_number, |weight| *weight > 0))(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("}")(input)?;
+    let (input, _) = whitespace0(input)?;
+
+    Ok((input, weight))
+}
+
+#[derive(Debug, Clone)]
+pub struct ChoiceClipExpression {
+    pub alternatives: Vec<ChoiceAlternative>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChoiceAlternative {
+    pub clip: ClipExpression,
+    pub weight: usize,
+}
+
+const CHAIN_PRECEDENCE: usize = 1;
+const LAYER_PRECEDENCE: usize = 2;
+
+fn parse_binary_clip_operator(input: &str) -> IResult<&str, usize> {
+    alt((
+        value(CHAIN_PRECEDENCE, token("|")),
+        value(LAYER_PRECEDENCE, token(":")),
+    ))(input)
+}
+
+fn parse_binary_clip_expression(input: &str, min_precedence: usize) -> IResult<&str, Operand> {
+    let (input, operand) = parse_clip_operand(input, min_precedence)?;
+    parse_binary_clip_operators(input, operand, min_precedence)
+}
+
+/// Parses the `|` and `:` after an operand, which make it a clip if there are
+/// any.
+fn parse_binary_clip_operators(
+    input: &str,
+    operand: Operand,
+    min_precedence: usize,
+) -> IResult<&str, Operand> {
+    let (mut clip_a, could_be_beat) = match operand {
+        Operand::Beat(_) => return Ok((input, operand)),
+        Operand::Clip(clip) => (clip, false),
+        Operand::ClipOrBeat(clip) => (clip, true),
+    };
+    let start = input;
+    let mut input = input;
+    while let Ok((rest, precedence)) = parse_binary_clip_operator(input) {
+        if precedence < min_precedence {
+            break;
+        }
+        // both operators are right associative
+        let (rest, clip_b) = parse_binary_clip_expression(rest, precedence)?;
+        let clip_b = Box::new(expect_clip(rest, clip_b)?);
+        clip_a = if precedence == CHAIN_PRECEDENCE {
+            ClipExpression::Chain(ClipChainExpression {
+                clip_a: Box::new(clip_a),
+                clip_b,
+            })
+        } else {
+            ClipExpression::Layer(ClipLayerExpression {
+                clip_a: Box::new(clip_a),
+                clip_b,
+            })
+        };
+        input = rest;
+    }
+    if could_be_beat && input.len() == start.len() {
+        Ok((input, Operand::ClipOrBeat(clip_a)))
+    } else {
+        Ok((input, Operand::Clip(clip_a)))
+    }
+}
+
+/// Parses an operand of `|` or `:`. It is read as a clip until `@` or `>>`
+/// follows, and then taken as their beat, so that nothing is read twice. An
+/// operand of `|` takes in the chain of names after it, which either is the
+/// beat or, for lack of an `@` or `>>`, the operands of `|`.
+fn parse_clip_operand(input: &str, min_precedence: usize) -> IResult<&str, Operand> {
+    let (mut input, mut part) = parse_operand_part(input)?;
+    // the parts before `part`, which can all be beats
+    let mut parts = Vec::new();
+    let last = loop {
+        let clip = match part {
+            Operand::Beat(beat) => {
+                let mut beats: Vec<_> = parts.into_iter().map(clip_as_beat).collect();
+                beats.push(beat);
+                return parse_beat_operand(input, beats);
+            }
+            Operand::Clip(clip) => break Operand::Clip(clip),
+            Operand::ClipOrBeat(clip) => clip,
+        };
+        if alt((token("@"), token(">>")))(input).is_ok() {
+            parts.push(clip);
+            return parse_beat_operand(input, parts.into_iter().map(clip_as_beat).collect());
+        }
+        if min_precedence > CHAIN_PRECEDENCE {
+            break Operand::ClipOrBeat(clip);
+        }
+        match preceded(token("|"), parse_operand_part)(input) {
+            Ok((rest, next)) => {
+                parts.push(clip);
+                part = next;
+                input = rest;
+            }
+            Err(nom::Err::Error(_)) => break Operand::ClipOrBeat(clip),
+            Err(e) => return Err(e),
+        }
+    };
+    if parts.is_empty() {
+        return Ok((input, last));
+    }
+    // the last part binds more tightly than the chain, as in `a | b : c`
+    let (input, last) = parse_binary_clip_operators(input, last, CHAIN_PRECEDENCE)?;
+    let chain = |clip_b| {
+        parts.into_iter().rev().fold(clip_b, |clip_b, clip_a| {
+            ClipExpression::Chain(ClipChainExpression {
+                clip_a: Box::new(clip_a),
+                clip_b: Box::new(clip_b),
+            })
+        })
+    };
+    Ok((
+        input,
+        match last {
+            Operand::ClipOrBeat(last) => Operand::ClipOrBeat(chain(last)),
+            last => Operand::Clip(chain(expect_clip(input, last)?)),
+        },
+    ))
+}
+
+/// Parses a `|` separated part of an operand of `|` or `:`.
+fn parse_operand_part(input: &str) -> IResult<&str, Operand> {
+    let (input, _) = whitespace0(input)?;
+    // patterns, numbers and `!` only start beats
+    let mut chars = input.chars();
+    let starts_beat = match chars.next() {
+        Some('.' | '!' | '{') => true,
+        Some('-') => matches!(chars.next(), Some('.' | '-')),
+        Some(c) => c.is_ascii_digit(),
+        None => false,
+    };
+    if starts_beat {
+        let (input, beat) = parse_beat_chain_part(input)?;
+        return Ok((input, Operand::Beat(beat)));
+    }
+    let (input, clip, parenthesized) = match parse_parentheses(input) {
+        Err(nom::Err::Error(_)) if token("(")(input).is_err() => {
+            let (input, clip) = context("clip expression", parse_postfix_clip_expression)(input)?;
+            (input, clip, false)
+        }
+        Err(e) => return Err(e),
+        Ok((input, Operand::Beat(beat))) => {
+            let (input, beat) = parse_rest_of_beat_chain_part(input, beat)?;
+            return Ok((input, Operand::Beat(beat)));
+        }
+        Ok((input, Operand::Clip(clip))) => {
+            let (input, clip) = parse_clip_postfixes(input, clip)?;
+            (input, clip, false)
+        }
+        Ok((input, Operand::ClipOrBeat(clip))) => {
+            let (input, clip) = parse_clip_postfixes(input, clip)?;
+            (input, clip, true)
+        }
+    };
+    if !can_be_beat(&clip, parenthesized) {
+        return Ok((input, Operand::Clip(clip)));
+    }
+    // so is a clip followed by a beat operator
+    if alt((token("<<"), token("+"), token("&")))(input).is_ok() {
+        let (input, beat) = parse_rest_of_beat_chain_part(input, clip_as_beat(clip))?;
+        return Ok((input, Operand::Beat(beat)));
+    }
+    Ok((input, Operand::ClipOrBeat(clip)))
+}
+
+/// Parses the rest of the beat chain that `beats` start, and the `@` or `>>`
+/// and the clip after it, if they follow.
+fn parse_beat_operand(mut input: &str, mut beats: Vec<BeatExpression>) -> IResult<&str, Operand> {
+    loop {
+        match preceded(token("|"), parse_beat_chain_part)(input) {
+            Ok((rest, beat)) => {
+                beats.push(beat);
+                input = rest;
+            }
+            Err(nom::Err::Error(_)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    let beat_expression = chain_beats(beats);
+    let Ok((input, operator)) = alt((token("@"), token(">>")))(input) else {
+        return Ok((input, Operand::Beat(beat_expression)));
+    };
+    let (input, clip_expression) = parse_clip_expression(input)?;
+    let clip_expression = Box::new(clip_expression);
+    Ok((
+        input,
+        Operand::Clip(if operator == "@" {
+            ClipExpression::ApplyBeat(ApplyBeatExpression {
+                beat_expression,
+                clip_expression,
+            })
+        } else {
+            ClipExpression::Restart(RestartExpression {
+                beat_expression,
+                clip_expression,
+            })
+        }),
+    ))
+}
+
+/// Whether a clip that was just read can be a beat too, given whether the
+/// clip in parentheses it can be made of can be one.
+fn can_be_beat(clip: &ClipExpression, parenthesized: bool) -> bool {
+    match clip {
+        ClipExpression::Reference(_) | ClipExpression::Empty => true,
+        ClipExpression::Loop(ClipLoopExpression { clip, .. }) => can_be_beat(clip, parenthesized),
+        ClipExpression::ParenthesesClipExpression(_) => parenthesized,
+        ClipExpression::Call(call) => euclidean_call(call).is_some(),
+        _ => false,
+    }
+}
+
+/// The beat a clip that can be one stands for.
+fn clip_as_beat(clip: ClipExpression) -> BeatExpression {
+    match clip {
+        ClipExpression::Reference(ReferenceClipExpression { name, span }) => {
+            BeatExpression::ReferenceBeatExpression(ReferenceBeatExpression { name, span })
+        }
+        ClipExpression::Empty => BeatExpression::DotBeatExpression(DotBeatExpression {
+            beats: vec![false],
+            resolution: DEFAULT_RESOLUTION,
+        }),
+        ClipExpression::Loop(ClipLoopExpression {
+            clip, repetitions, ..
+        }) => BeatExpression::RepeatedBeatExpression(RepeatedBeatExpression {
+            beat: Box::new(clip_as_beat(*clip)),
+            repetitions,
+        }),
+        ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression { clip }) => {
+            BeatExpression::ParenthesesBeatExpression(ParenthesesBeatExpression {
+                beat: Box::new(clip_as_beat(*clip)),
+            })
+        }
+        ClipExpression::Chain(ClipChainExpression { clip_a, clip_b }) => {
+            BeatExpression::BeatChainExpression(BeatChainExpression {
+                beat_a: Box::new(clip_as_beat(*clip_a)),
+                beat_b: Box::new(clip_as_beat(*clip_b)),
+            })
+        }
+        ClipExpression::Call(call) => {
+            euclidean_call(&call).expect("only a call that reads as `e(hits, steps)` can be a beat")
+        }
+        _ => unreachable!("only clips that can be beats are taken as beats"),
+    }
+}
+
+/// The Euclidean beat that a call like `e(3, 8)` or `e(n, 8, 2)` reads as.
+fn euclidean_call(call: &CallClipExpression) -> Option<BeatExpression> {
+    if call.name != "e" || !(2..=3).contains(&call.arguments.len()) {
+        return None;
+    }
+    let numbers: Option<Vec<NumberExpression>> = call
+        .arguments
+        .iter()
+        .map(|argument| match argument {
+            ArgumentExpression::Number(number) => Some(NumberExpression::Literal(*number)),
+            ArgumentExpression::Clip(ClipExpression::Reference(ReferenceClipExpression {
+                name,
+                ..
+            })) if name.chars().all(|c| c.is_alphabetic()) => {
+                Some(NumberExpression::Parameter(name.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+    let mut numbers = numbers?.into_iter();
+    Some(BeatExpression::EuclideanBeatExpression(
+        EuclideanBeatExpression {
+            hits: numbers.next()?,
+            steps: numbers.next()?,
+            rotation: numbers.next().unwrap_or(NumberExpression::Literal(0)),
+            span: call.span,
+        },
+    ))
+}
+
+fn parse_postfix_clip_expression(input: &str) -> IResult<&str, ClipExpression> {
+    let (input, clip) = parse_primary_clip_expression(input)?;
+    parse_clip_postfixes(input, clip)
+}
+
+fn parse_clip_postfixes(
+    mut input: &str,
+    mut clip: ClipExpression,
+) -> IResult<&str, ClipExpression> {
+    loop {
+        if token("*")(input).is_ok() {
+            let (rest, repetitions) = parse_clip_loop_repetitions(input)?;
+            let span = Span::between(input, rest);
+            let (rest, _) = whitespace0(rest)?;
+            clip = ClipExpression::Loop(ClipLoopExpression {
+                clip: Box::new(clip),
+                repetitions,
+                span,
+            });
+            input = rest;
+        } else if token("[")(input).is_ok() {
+            let (rest, timerange) = parse_time_range_expression(input)?;
+            let span = Span::between(input, rest);
+            let (rest, _) = whitespace0(rest)?;
+            clip = ClipExpression::Truncated(TruncatedClipExpression {
+                clip: Box::new(clip),
+                timerange,
+                span,
+            });
+            input = rest;
+        } else if token("~")(input).is_ok() {
+            let (rest, (numerator, denominator)) = parse_clip_speed(input)?;
+            clip = ClipExpression::Speed(SpeedClipExpression {
+                clip: Box::new(clip),
+                numerator,
+                denominator,
+            });
+            input = rest;
+        } else {
+            return Ok((input, clip));
+        }
+    }
+}
+
+fn parse_primary_clip_expression(input: &str) -> IResult<&str, ClipExpression> {
     alt((
-        parse_apply_beat_expression,
-        parse_restart_beat_expression,
-        parse_clip_chain_expression,
-        parse_layer_expression,
-        parse_clip_loop_expression,
-        parse_truncated_clip_expression,
         parse_parentheses_clip_expression,
+        parse_reverse_clip_expression,
+        parse_hold_clip_expression,
+        parse_fit_clip_expression,
+        parse_pingpong_clip_expression,
         parse_multi_video_expression,
         parse_raw_video_expression,
+        parse_call_clip_expression,
         parse_reference_clip_expression,
         parse_empty_expression,
     ))(input)
 }
 
-pub fn parse_empty_expression(input: &str) -> IResult<&str, ClipExpression> {
-    let (input, _) = multispace0(input)?;
-    let (input, _) = tag("-")(input)?;
-    let (input, _) = multispace0(input)?;
+#[derive(Debug, Clone)]
+pub struct ReverseClipExpression {
+    pub clip: Box<ClipExpression>,
+}
 
-    Ok((input, ClipExpression::Empty))
+fn parse_reverse_clip_expression(input: &str) -> IResult<&str, ClipExpression> {
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("rev")(input)?;
+    // `rev` starting a longer name is a reference
+    let (input, _) = not(satisfy(|c| c.is_alphanumeric() || c == '.'))(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, clip) = parse_postfix_clip_expression(input)?;
+
+    Ok((
+        input,
+        ClipExpression::Reverse(ReverseClipExpression {
+            clip: Box::new(clip),
+        }),
+    ))
 }
 
+/// Keeps what a clip shows after it ends, until something else plays on its
+/// layers, instead of showing nothing there.
 #[derive(Debug, Clone)]
-pub struct RestartExpression {
-    pub beat_expression: BeatExpression,
-    pub clip_expression: Box<ClipExpression>,
+pub struct HoldClipExpression {
+    pub clip: Box<ClipExpression>,
 }
 
-pub fn parse_restart_beat_expression(input: &str) -> IResult<&str, ClipExpression> {
-    let (input, _) = multispace0(input)?;
-    let (input, beat_expression) = parse_beat_expression(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, _) = tag(">>")(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, clip_expression) = parse_clip_expression(input)?;
-    let (input, _) = multispace0(input)?;
+fn parse_hold_clip_expression(input: &str) -> IResult<&str, ClipExpression> {
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("hold")(input)?;
+    // `hold` starting a longer name is a reference
+    let (input, _) = not(satisfy(|c| c.is_alphanumeric() || c == '.'))(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, clip) = parse_postfix_clip_expression(input)?;
 
     Ok((
         input,
-        ClipExpression::Restart(RestartExpression {
-            beat_expression,
-            clip_expression: Box::new(clip_expression),
+        ClipExpression::Hold(HoldClipExpression {
+            clip: Box::new(clip),
         }),
     ))
 }
+
+/// Stretches the file a clip starts with, from `start` to `end` seconds into
+/// it, or all of it, to last `beats` beats at whatever the tempo is.
+#[derive(Debug, Clone)]
+pub struct FitClipExpression {
+    pub clip: Box<ClipExpression>,
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub beats: NumberExpression,
+    /// Of the beat count.
+    pub span: Span,
+}
+
+/// Parses `fit clip n` or `fit clip [1.5s:3s] n`. Neither the part of the file
+/// nor the beats it is stretched to can be empty.
+fn parse_fit_clip_expression(input: &str) -> IResult<&str, ClipExpression> {
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("fit")(input)?;
+    // `fit` starting a longer name is a reference
+    let (input, _) = not(satisfy(|c| c.is_alphanumeric() || c == '.'))(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, clip) = parse_primary_clip_expression(input)?;
+    // a name cannot follow another one, so this is no longer a reference
+    let (input, (start, end)) = if token("[")(input).is_ok() {
+        cut(context(
+            "source region ending after its start",
+            verify(parse_source_region, |region| match region {
+                (Some(start), Some(end)) => start < end,
+                _ => true,
+            }),
+        ))(input)?
+    } else {
+        (input, (None, None))
+    };
+    let (input, _) = whitespace0(input)?;
+    let (rest, beats) = cut(context(
+        "beat count",
+        verify(parse_number_expression, |beats| {
+            !matches!(beats, NumberExpression::Literal(0))
+        }),
+    ))(input)?;
+    let span = Span::between(input, rest);
+    let (input, _) = whitespace0(rest)?;
+
+    Ok((
+        input,
+        ClipExpression::Fit(FitClipExpression {
+            clip: Box::new(clip),
+            start,
+            end,
+            beats,
+            span,
+        }),
+    ))
+}
+
+/// Plays the file a clip starts with forwards and backwards, turning around at
+/// `start` and `end` seconds into it, or at its start and end.
+#[derive(Debug, Clone)]
+pub struct PingPongClipExpression {
+    pub clip: Box<ClipExpression>,
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+}
+
+/// Parses `pingpong clip` or `pingpong clip [1.5s:3s]`. A range in beats
+/// after the clip truncates the ping-pong clip.
+fn parse_pingpong_clip_expression(input: &str) -> IResult<&str, ClipExpression> {
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("pingpong")(input)?;
+    // `pingpong` starting a longer name is a reference
+    let (input, _) = not(satisfy(|c| c.is_alphanumeric() || c == '.'))(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, clip) = parse_primary_clip_expression(input)?;
+    let (input, region) = opt(parse_source_region)(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (start, end) = region.unwrap_or((None, None));
+
+    Ok((
+        input,
+        ClipExpression::PingPong(PingPongClipExpression {
+            clip: Box::new(clip),
+            start,
+            end,
+        }),
+    ))
+}
+
+/// Parses the part of a file between two times in seconds, like `[1.5s:3s]`.
+fn parse_source_region(input: &str) -> IResult<&str, (Option<f64>, Option<f64>)> {
+    let (input, _) = token("[")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, start) = alt((map(parse_seconds, Some), value(None, peek(token(":")))))(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token(":")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, end) = alt((map(parse_seconds, Some), value(None, peek(token("]")))))(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("]")(input)?;
+
+    Ok((input, (start, end)))
+}
+
+/// Parses a number of seconds like `2s` or `1.5s`.
+fn parse_seconds(input: &str) -> IResult<&str, f64> {
+    context(
+        "seconds",
+        map_res(
+            terminated(
+                recognize(pair(digit1, opt(pair(char('.'), digit1)))),
+                token("s"),
+            ),
+            str::parse,
+        ),
+    )(input)
+}
+
+/// Plays a clip `numerator / denominator` times as fast as the files in it
+/// were recorded, backwards if the speed is negative.
+#[derive(Debug, Clone)]
+pub struct SpeedClipExpression {
+    pub clip: Box<ClipExpression>,
+    pub numerator: isize,
+    pub denominator: usize,
+}
+
+/// Parses `~` and a decimal speed like `2`, `0.5` or `-1`.
+fn parse_clip_speed(input: &str) -> IResult<&str, (isize, usize)> {
+    let (input, _) = token("~")(input)?;
+    let (start, _) = whitespace0(input)?;
+    let (input, speed) = context(
+        "speed",
+        recognize(tuple((
+            opt(char('-')),
+            digit1,
+            opt(pair(char('.'), digit1)),
+        ))),
+    )(start)?;
+    let (input, _) = whitespace0(input)?;
+    // so that both parts of the speed fit into a time
+    if speed.chars().filter(char::is_ascii_digit).count() > MAX_SPEED_DIGITS {
+        return Err(nom::Err::Failure(SyntaxError::expected(
+            start,
+            "speed of at most 18 digits",
+        )));
+    }
+    let decimals = speed.find('.').map_or(0, |dot| speed.len() - dot - 1);
+    let numerator = speed
+        .replace('.', "")
+        .parse::<isize>()
+        .map_err(|_| nom::Err::Error(SyntaxError::new(start, nom::error::ErrorKind::Digit)))?;
+
+    Ok((input, (numerator, 10_usize.pow(decimals as u32))))
+}
+
+const MAX_SPEED_DIGITS: usize = 18;
+
+pub fn parse_empty_expression(input: &str) -> IResult<&str, ClipExpression> {
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("-")(input)?;
+    let (input, _) = whitespace0(input)?;
+
+    Ok((input, ClipExpression::Empty))
+}
+
+#[derive(Debug, Clone)]
+pub struct RestartExpression {
+    pub beat_expression: BeatExpression,
+    pub clip_expression: Box<ClipExpression>,
+}
+
 #[derive(Debug, Clone)]
 pub struct ApplyBeatExpression {
     pub beat_expression: BeatExpression,
     pub clip_expression: Box<ClipExpression>,
 }
 
-pub fn parse_apply_beat_expression(input: &str) -> IResult<&str, ClipExpression> {
-    let (input, _) = multispace0(input)?;
-    let (input, beat_expression) = parse_beat_expression(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, _) = tag("@")(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, clip_expression) = parse_clip_expression(input)?;
-    let (input, _) = multispace0(input)?;
+#[derive(Debug, Clone)]
+pub struct ReferenceClipExpression {
+    pub name: String,
+    pub span: Span,
+}
+
+fn parse_reference_clip_expression(input: &str) -> IResult<&str, ClipExpression> {
+    let (input, _) = whitespace0(input)?;
+    let (rest, name) = context("clip name", parse_qualified_name)(input)?;
+    let span = Span::between(input, rest);
+    let (input, _) = whitespace0(rest)?;
 
     Ok((
         input,
-        ClipExpression::ApplyBeat(ApplyBeatExpression {
-            beat_expression,
-            clip_expression: Box::new(clip_expression),
+        ClipExpression::Reference(ReferenceClipExpression {
+            name: name.into(),
+            span,
         }),
     ))
 }
 
 #[derive(Debug, Clone)]
-pub struct ReferenceClipExpression {
+pub struct CallClipExpression {
     pub name: String,
+    pub arguments: Vec<ArgumentExpression>,
+    /// Of the whole call, arguments included.
+    pub span: Span,
 }
 
-fn parse_reference_clip_expression(input: &str) -> IResult<&str, ClipExpression> {
-    let (input, _) = multispace0(input)?;
-    let (input, name) = alphanumeric1(input)?;
-    let (input, _) = multispace0(input)?;
+fn parse_call_clip_expression(input: &str) -> IResult<&str, ClipExpression> {
+    let (start, _) = whitespace0(input)?;
+    let (input, name) = parse_qualified_name(start)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("(")(input)?;
+    let (input, arguments) = separated_list0(token(","), parse_argument_expression)(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (rest, _) = token(")")(input)?;
+    let (input, _) = whitespace0(rest)?;
 
     Ok((
         input,
-        ClipExpression::Reference(ReferenceClipExpression { name: name.into() }),
+        ClipExpression::Call(CallClipExpression {
+            name: name.into(),
+            arguments,
+            span: Span::between(start, rest),
+        }),
     ))
 }
 
+/// An argument of a parameterized clip. Which kind it is only matters once it
+/// is substituted, so a name or `-` is read as a clip and a lone number as a
+/// number, and the reducer converts them where they are used as beats.
+#[derive(Debug, Clone)]
+pub enum ArgumentExpression {
+    Number(usize),
+    Clip(ClipExpression),
+    Beat(BeatExpression),
+}
+
+fn parse_argument_expression(input: &str) -> IResult<&str, ArgumentExpression> {
+    let end = || peek(preceded(whitespace0, alt((token(","), token(")")))));
+    let (input, _) = whitespace0(input)?;
+    context(
+        "argument",
+        alt((
+            map(terminated(parse_number, end()), ArgumentExpression::Number),
+            map(
+                terminated(parse_clip_expression, end()),
+                ArgumentExpression::Clip,
+            ),
+            map(
+                terminated(parse_beat_expression, end()),
+                ArgumentExpression::Beat,
+            ),
+        )),
+    )(input)
+}
+
+fn parse_number(input: &str) -> IResult<&str, usize> {
+    let (rest, number) = digit1(input)?;
+    let number = number
+        .parse::<usize>()
+        .map_err(|_| nom::Err::Error(SyntaxError::new(input, nom::error::ErrorKind::Digit)))?;
+    Ok((rest, number))
+}
+
 #[derive(Debug, Clone)]
 pub struct ParenthesesClipExpression {
     pub clip: Box<ClipExpression>,
 }
 pub fn parse_parentheses_clip_expression(input: &str) -> IResult<&str, ClipExpression> {
-    let (input, _) = multispace0(input)?;
-    let (input, _) = char('(')(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, clip) = parse_clip_expression(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, _) = char(')')(input)?;
-    let (input, _) = multispace0(input)?;
-    Ok((
-        input,
+    let (input, operand) = parse_parentheses(input)?;
+    Ok((input, expect_clip(input, operand)?))
+}
+
+/// Parses a clip or a beat in parentheses.
+fn parse_parentheses(input: &str) -> IResult<&str, Operand> {
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("(")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, operand) = parse_clip_or_beat(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token(")")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let parenthesized = |clip| {
         ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression {
             clip: Box::new(clip),
-        }),
+        })
+    };
+    Ok((
+        input,
+        match operand {
+            Operand::Clip(clip) => Operand::Clip(parenthesized(clip)),
+            Operand::ClipOrBeat(clip) => Operand::ClipOrBeat(parenthesized(clip)),
+            Operand::Beat(beat) => Operand::Beat(BeatExpression::ParenthesesBeatExpression(
+                ParenthesesBeatExpression {
+                    beat: Box::new(beat),
+                },
+            )),
+        },
     ))
 }
 
 #[derive(Debug, Clone)]
 pub struct ClipLoopExpression {
     pub clip: Box<ClipExpression>,
-    pub repetitions: usize,
-}
-pub fn parse_clip_loop_expression(input: &str) -> IResult<&str, ClipExpression> {
-    let (input, _) = multispace0(input)?;
-    let (input, first_part) = take_until("*")(input)?;
-    let (rest_first, clip) = parse_clip_expression(first_part)?;
-    if !rest_first.is_empty() {
-        return fail(rest_first);
-    }
-    let (input, _) = multispace0(input)?;
-    let (input, _) = char('*')(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, repetitions) = take_while_m_n(1, 1, |c: char| c.is_ascii_digit())(input)?;
-    let repetitions = repetitions
-        .parse::<usize>()
-        .map_err(|_| nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Char)))?;
-    let (input, _) = multispace0(input)?;
-    Ok((
-        input,
-        ClipExpression::Loop(ClipLoopExpression {
-            clip: Box::new(clip),
-            repetitions,
+    pub repetitions: NumberExpression,
+    /// Of the `* n`.
+    pub span: Span,
+}
+fn parse_clip_loop_repetitions(input: &str) -> IResult<&str, NumberExpression> {
+    let (input, _) = token("*")(input)?;
+    let (input, _) = whitespace0(input)?;
+    context("repetition count", parse_number_expression)(input)
+}
+
+fn parse_number_expression(input: &str) -> IResult<&str, NumberExpression> {
+    alt((
+        map(parse_number, NumberExpression::Literal),
+        map(alpha1, |name: &str| {
+            NumberExpression::Parameter(name.into())
         }),
-    ))
+    ))(input)
+}
+
+/// A number, or the name of a parameter that is bound to one.
+#[derive(Debug, Clone)]
+pub enum NumberExpression {
+    Literal(usize),
+    Parameter(String),
 }
 
 #[derive(Debug, Clone)]
@@ -293,75 +1181,19 @@ pub struct ClipChainExpression {
     pub clip_a: Box<ClipExpression>,
     pub clip_b: Box<ClipExpression>,
 }
-pub fn parse_clip_chain_expression(input: &str) -> IResult<&str, ClipExpression> {
-    let (input, _) = multispace0(input)?;
-    let (input, first_part) = take_until("|")(input)?;
-    let (rest_first, clip_a) = parse_clip_expression(first_part)?;
-    if !rest_first.is_empty() {
-        return fail(rest_first);
-    }
-    let (input, _) = multispace0(input)?;
-    let (input, _) = char('|')(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, clip_b) = parse_clip_expression(input)?;
-    let (input, _) = multispace0(input)?;
-    Ok((
-        input,
-        ClipExpression::Chain(ClipChainExpression {
-            clip_a: Box::new(clip_a),
-            clip_b: Box::new(clip_b),
-        }),
-    ))
-}
 
 #[derive(Debug, Clone)]
 pub struct ClipLayerExpression {
     pub clip_a: Box<ClipExpression>,
     pub clip_b: Box<ClipExpression>,
 }
-pub fn parse_layer_expression(input: &str) -> IResult<&str, ClipExpression> {
-    let (input, _) = multispace0(input)?;
-    let (input, first_part) = take_until(":")(input)?;
-    let (rest_first, clip_a) = parse_clip_expression(first_part)?;
-    if !rest_first.is_empty() {
-        return fail(rest_first);
-    }
-    let (input, _) = multispace0(input)?;
-    let (input, _) = char(':')(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, clip_b) = parse_clip_expression(input)?;
-    let (input, _) = multispace0(input)?;
-    Ok((
-        input,
-        ClipExpression::Layer(ClipLayerExpression {
-            clip_a: Box::new(clip_a),
-            clip_b: Box::new(clip_b),
-        }),
-    ))
-}
 
 #[derive(Debug, Clone)]
 pub struct TruncatedClipExpression {
     pub clip: Box<ClipExpression>,
     pub timerange: TimeRangeExpression,
-}
-pub fn parse_truncated_clip_expression(input: &str) -> IResult<&str, ClipExpression> {
-    if !input.contains('[') && !input.contains(']') {
-        return fail(input);
-    }
-    let (input, _) = multispace0(input)?;
-    let (input, first_part) = take_until("[")(input)?;
-    let (_, clip) = parse_clip_expression(first_part)?;
-    let (input, _) = multispace0(input)?;
-    let (input, timerange) = parse_time_range_expression(input)?;
-    let (input, _) = multispace0(input)?;
-    Ok((
-        input,
-        ClipExpression::Truncated(TruncatedClipExpression {
-            clip: Box::new(clip),
-            timerange,
-        }),
-    ))
+    /// Of the range.
+    pub span: Span,
 }
 
 #[derive(Debug, Clone)]
@@ -369,125 +1201,808 @@ pub struct TimeRangeExpression {
     pub from: Option<TimeExpression>,
     pub to: Option<TimeExpression>,
 }
+
+impl fmt::Display for TimeRangeExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let has_bar =
+            |time: &Option<TimeExpression>| time.as_ref().is_some_and(|t| t.bar.is_some());
+        // the colon between two times with bars is set apart from theirs
+        let gap = if has_bar(&self.from) || has_bar(&self.to) {
+            " "
+        } else {
+            ""
+        };
+        write!(f, "[")?;
+        if let Some(from) = &self.from {
+            write!(f, "{}{}", from, gap)?;
+        }
+        write!(f, ":")?;
+        if let Some(to) = &self.to {
+            write!(f, "{}{}", gap, to)?;
+        }
+        write!(f, "]")
+    }
+}
+
+/// `[1:2]` is a range of beats and `[1:2 : 3:1]` one of bars and beats, so a
+/// time is only read with a bar where the rest of the range still follows.
 fn parse_time_range_expression(input: &str) -> IResult<&str, TimeRangeExpression> {
-    let (input, _) = tag("[")(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, from) = opt(parse_time_expression)(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, _) = tag(":")(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, to) = opt(parse_time_expression)(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, _) = tag("]")(input)?;
+    let (input, _) = token("[")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let separator = || pair(token(":"), whitespace0);
+    let (input, from) = alt((
+        terminated(map(parse_time_expression(true), Some), separator()),
+        terminated(opt(parse_time_expression(false)), separator()),
+    ))(input)?;
+    let (input, to) = alt((
+        terminated(map(parse_time_expression(true), Some), token("]")),
+        terminated(opt(parse_time_expression(false)), token("]")),
+    ))(input)?;
     Ok((input, TimeRangeExpression { from, to }))
 }
 
+/// A position in a clip like `2:1.3`, bar 2, beat 1, sixteenth 3, or with a
+/// `-` a length like `-1/2` that is counted back from the end of the clip.
+/// The parts of a position count from 1, those of a length from 0.
 #[derive(Debug, Clone)]
 pub struct TimeExpression {
-    pub beat: usize,
+    pub from_end: bool,
+    pub bar: Option<usize>,
+    /// Numerator and denominator, so that a beat can be fractional.
+    pub beat: (usize, usize),
     pub sixteenth: Option<usize>,
 }
 
-fn parse_time_expression(input: &str) -> IResult<&str, TimeExpression> {
-    let (input, _) = multispace0(input)?;
-    let (input, beat) = digit1(input)?;
-    let beat = beat
-        .parse::<usize>()
-        .map_err(|_| nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Char)))?;
-    let (input, sixteenth) = opt(parse_time_sixteenth_expression)(input)?;
-    let (input, _) = multispace0(input)?;
-
-    Ok((
-        input,
-        TimeExpression {
-            beat: (beat as isize - 1).max(0) as usize,
-            sixteenth: sixteenth.map(|b| (b as isize - 1).max(0) as usize),
-        },
-    ))
+impl fmt::Display for TimeExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.from_end {
+            write!(f, "-")?;
+        }
+        if let Some(bar) = self.bar {
+            write!(f, "{}:", bar)?;
+        }
+        match self.beat {
+            (beat, 1) => write!(f, "{}", beat)?,
+            (numerator, denominator) => write!(f, "{}/{}", numerator, denominator)?,
+        }
+        if let Some(sixteenth) = self.sixteenth {
+            write!(f, ".{}", sixteenth)?;
+        }
+        Ok(())
+    }
 }
 
-fn parse_time_sixteenth_expression(input: &str) -> IResult<&str, usize> {
-    let (input, _) = tag(".")(input)?;
-    let (input, sixteenth) = digit1(input)?;
-
-    let sixteenth = sixteenth
-        .parse::<usize>()
-        .map_err(|_| nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Char)))?;
-    Ok((input, sixteenth))
+fn parse_time_expression<'a>(
+    with_bar: bool,
+) -> impl FnMut(&'a str) -> IResult<&'a str, TimeExpression> {
+    move |input: &'a str| {
+        let (input, _) = whitespace0(input)?;
+        let (input, from_end) = map(opt(token("-")), |minus| minus.is_some())(input)?;
+        let first = usize::from(!from_end);
+        let label = |length: &'static str, position: &'static str| {
+            if from_end {
+                length
+            } else {
+                position
+            }
+        };
+        let (input, bar) = if with_bar {
+            let (input, bar) = terminated(
+                context(
+                    label("bar number", "bar number from 1"),
+                    verify(parse_number, |bar| *bar >= first),
+                ),
+                token(":"),
+            )(input)?;
+            (input, Some(bar))
+        } else {
+            (input, None)
+        };
+        let (input, beat) = context(
+            label("beat number", "beat number from 1"),
+            verify(
+                pair(
+                    parse_number,
+                    map(
+                        opt(preceded(
+                            token("/"),
+                            cut(context(
+                                "denominator above 0",
+                                verify(parse_number, |denominator| *denominator > 0),
+                            )),
+                        )),
+                        |denominator| denominator.unwrap_or(1),
+                    ),
+                ),
+                |(numerator, denominator)| *numerator >= first * denominator,
+            ),
+        )(input)?;
+        let (input, sixteenth) = opt(preceded(
+            token("."),
+            context(
+                label("sixteenth number", "sixteenth number from 1"),
+                verify(parse_number, |sixteenth| *sixteenth >= first),
+            ),
+        ))(input)?;
+        let (input, _) = whitespace0(input)?;
+        Ok((
+            input,
+            TimeExpression {
+                from_end,
+                bar,
+                beat,
+                sixteenth,
+            },
+        ))
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct MultiVideoExpression {
     pub filename: String,
     pub subclips: usize,
+    pub span: Span,
 }
 pub fn parse_multi_video_expression(input: &str) -> IResult<&str, ClipExpression> {
-    let (input, _) = multispace0(input)?;
-    let (input, _) = tag("multi")(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, subclips) = digit1(input)?;
+    let (start, _) = whitespace0(input)?;
+    let (input, _) = token("multi")(start)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, subclips) = context("number of subclips", digit1)(input)?;
 
     let subclips = subclips
         .parse::<usize>()
-        .map_err(|_| nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Char)))?;
-    let (input, _) = multispace0(input)?;
-    let (input, filename) = alt((
-        delimited(char('\''), recognize(take_until("'")), char('\'')),
-        delimited(char('"'), recognize(take_until("\"")), char('"')),
-    ))(input)?;
-    let (input, _) = multispace0(input)?;
+        .map_err(|_| nom::Err::Error(SyntaxError::new(input, nom::error::ErrorKind::Char)))?;
+    let (input, _) = whitespace0(input)?;
+    let (rest, filename) = context("quoted file name", parse_quoted)(input)?;
+    let (input, _) = whitespace0(rest)?;
     Ok((
         input,
         ClipExpression::MultiVideo(MultiVideoExpression {
             filename: filename.into(),
             subclips,
+            span: Span::between(start, rest),
         }),
     ))
 }
 #[derive(Debug, Clone)]
 pub struct RawVideoExpression {
     pub filename: String,
+    pub offset: Option<SourceOffset>,
+    /// Of the file name.
+    pub span: Span,
 }
 pub fn parse_raw_video_expression(input: &str) -> IResult<&str, ClipExpression> {
-    let (input, _) = multispace0(input)?;
-    let (input, filename) = alt((
-        delimited(char('\''), recognize(take_until("'")), char('\'')),
-        delimited(char('"'), recognize(take_until("\"")), char('"')),
-    ))(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (rest, filename) = context("quoted file name", parse_quoted)(input)?;
+    let span = Span::between(input, rest);
+    let (input, _) = whitespace0(rest)?;
+    // a file is no beat, so an `@` after it can only start an offset
+    let (input, offset) = opt(preceded(token("@"), cut(parse_source_offset)))(input)?;
     Ok((
         input,
         ClipExpression::RawVideo(RawVideoExpression {
             filename: filename.into(),
+            offset,
+            span,
         }),
     ))
 }
 
+/// Where in its file a raw video starts, regardless of the tempo.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SourceOffset {
+    Seconds(f64),
+    Frame(usize),
+    /// The start of the chapter with this title.
+    Cue(String),
+}
+
+/// Parses `2.5s`, `f120` or `cue(drop)`.
+fn parse_source_offset(input: &str) -> IResult<&str, SourceOffset> {
+    let (input, _) = whitespace0(input)?;
+    let (input, offset) = context(
+        "source offset",
+        alt((
+            map(parse_seconds, SourceOffset::Seconds),
+            map(preceded(char('f'), parse_number), SourceOffset::Frame),
+            map(
+                delimited(
+                    pair(token("cue"), token("(")),
+                    delimited(
+                        whitespace0,
+                        context("cue name", parse_qualified_name),
+                        whitespace0,
+                    ),
+                    token(")"),
+                ),
+                |name: &str| SourceOffset::Cue(name.into()),
+            ),
+        )),
+    )(input)?;
+    let (input, _) = whitespace0(input)?;
+
+    Ok((input, offset))
+}
+
 #[derive(Debug)]
 pub struct Main {
     pub directory_declaration: DirectoryDeclaration,
     pub extension_declaration: ExtensionDeclaration,
+    pub bpm_declaration: Option<BpmDeclaration>,
+    pub meter_declaration: Option<MeterDeclaration>,
     pub declarations: Vec<Declaration>,
-    pub main_expression: ClipExpression,
+    /// Only a source that declares scenes can do without one.
+    pub main_expression: Option<ClipExpression>,
+    /// Of the main expression.
+    pub main_span: Span,
 }
 
 pub fn parse_main(input: &str) -> IResult<&str, Main> {
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
     let (input, directory_declaration) = parse_directory_declaration(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
     let (input, extension_declaration) = parse_extension_declaration(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, declarations) = many0(parse_declaration)(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, main_expression) = parse_clip_expression(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, (bpm_declaration, meter_declaration, declarations)) =
+        parse_main_declarations(input)?;
+    let (input, _) = whitespace0(input)?;
+    let has_scenes = declarations
+        .iter()
+        .any(|declaration| matches!(declaration, Declaration::SceneDeclaration(_)));
+    let start = input;
+    let (input, main_expression) = if has_scenes && input.is_empty() {
+        (input, None)
+    } else {
+        map(parse_clip_expression, Some)(input)?
+    };
+    let main_span = Span::between(start, input);
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = context("end of input", eof)(input)?;
     Ok((
         input,
         Main {
             directory_declaration,
             extension_declaration,
+            bpm_declaration,
+            meter_declaration,
             declarations,
             main_expression,
+            main_span,
         },
     ))
 }
+
+/// The tempo and meter may be declared among the other declarations, in any
+/// order, but only once each.
+#[allow(clippy::type_complexity)]
+fn parse_main_declarations(
+    mut input: &str,
+) -> IResult<
+    &str,
+    (
+        Option<BpmDeclaration>,
+        Option<MeterDeclaration>,
+        Vec<Declaration>,
+    ),
+> {
+    let mut bpm_declaration = None;
+    let mut meter_declaration = None;
+    let mut declarations = Vec::new();
+    loop {
+        let (start, _) = whitespace0(input)?;
+        match parse_bpm_declaration(input) {
+            Ok((rest, bpm)) => {
+                if bpm_declaration.replace(bpm).is_some() {
+                    return Err(nom::Err::Failure(SyntaxError::expected(
+                        start,
+                        "only one tempo declaration",
+                    )));
+                }
+                input = rest;
+                continue;
+            }
+            Err(nom::Err::Error(_)) => {}
+            Err(e) => return Err(e),
+        }
+        match parse_meter_declaration(input) {
+            Ok((rest, meter)) => {
+                if meter_declaration.replace(meter).is_some() {
+                    return Err(nom::Err::Failure(SyntaxError::expected(
+                        start,
+                        "only one meter declaration",
+                    )));
+                }
+                input = rest;
+                continue;
+            }
+            Err(nom::Err::Error(_)) => {}
+            Err(e) => return Err(e),
+        }
+        match parse_declaration(input) {
+            Ok((rest, declaration)) => {
+                declarations.push(declaration);
+                input = rest;
+            }
+            Err(nom::Err::Error(_)) => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((input, (bpm_declaration, meter_declaration, declarations)))
+}
+
+/// Parses a whole .chop source, locating any syntax error in it.
+pub fn parse(input: &str) -> Result<Main, ParseError> {
+    let (_, mut main) = parse_main(input).map_err(|e| ParseError::new(input, e))?;
+    for declaration in &mut main.declarations {
+        declaration_spans(declaration, &mut |span| span.locate(input));
+    }
+    if let Some(main_expression) = &mut main.main_expression {
+        clip_spans(main_expression, &mut |span| span.locate(input));
+    }
+    main.main_span.locate(input);
+    // the main expression is followed by nothing but whitespace
+    main.main_span.end = main.main_span.start + input[main.main_span.start..].trim_end().len();
+    Ok(main)
+}
+
+pub fn parse_library_declarations(input: &str) -> IResult<&str, Vec<Declaration>> {
+    let (input, _) = whitespace0(input)?;
+    let (input, declarations) = many0(parse_declaration)(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = context("declaration", eof)(input)?;
+    Ok((input, declarations))
+}
+
+/// Parses an imported .chop file, which only holds declarations.
+pub fn parse_library(input: &str) -> Result<Vec<Declaration>, ParseError> {
+    let (_, mut declarations) =
+        parse_library_declarations(input).map_err(|e| ParseError::new(input, e))?;
+    for declaration in &mut declarations {
+        declaration_spans(declaration, &mut |span| span.locate(input));
+    }
+    Ok(declarations)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    use super::*;
+
+    /// A clip with all its operators in parentheses.
+    fn clip(expression: &ClipExpression) -> String {
+        match expression {
+            ClipExpression::Empty => "-".into(),
+            ClipExpression::Reference(reference) => reference.name.clone(),
+            ClipExpression::RawVideo(video) => format!("'{}'", video.filename),
+            ClipExpression::Chain(chain) => {
+                format!("({} | {})", clip(&chain.clip_a), clip(&chain.clip_b))
+            }
+            ClipExpression::Layer(layer) => {
+                format!("({} : {})", clip(&layer.clip_a), clip(&layer.clip_b))
+            }
+            ClipExpression::Loop(repeated) => {
+                format!(
+                    "({} * {})",
+                    clip(&repeated.clip),
+                    number(&repeated.repetitions)
+                )
+            }
+            ClipExpression::ApplyBeat(apply) => format!(
+                "({} @ {})",
+                beat(&apply.beat_expression),
+                clip(&apply.clip_expression)
+            ),
+            ClipExpression::Restart(restart) => format!(
+                "({} >> {})",
+                beat(&restart.beat_expression),
+                clip(&restart.clip_expression)
+            ),
+            ClipExpression::Hold(hold) => format!("(hold {})", clip(&hold.clip)),
+            ClipExpression::ParenthesesClipExpression(parentheses) => clip(&parentheses.clip),
+            other => format!("{:?}", other),
+        }
+    }
+
+    fn beat(expression: &BeatExpression) -> String {
+        match expression {
+            BeatExpression::DotBeatExpression(dots) => dots
+                .beats
+                .iter()
+                .map(|&hit| if hit { '.' } else { '-' })
+                .collect(),
+            BeatExpression::ReferenceBeatExpression(reference) => reference.name.clone(),
+            BeatExpression::BeatChainExpression(chain) => {
+                format!("({} | {})", beat(&chain.beat_a), beat(&chain.beat_b))
+            }
+            BeatExpression::RepeatedBeatExpression(repeated) => {
+                format!(
+                    "({} * {})",
+                    beat(&repeated.beat),
+                    number(&repeated.repetitions)
+                )
+            }
+            BeatExpression::RotatedBeatExpression(rotated) => {
+                format!("({} << {})", beat(&rotated.beat), number(&rotated.steps))
+            }
+            BeatExpression::UnionBeatExpression(union) => {
+                format!("({} + {})", beat(&union.beat_a), beat(&union.beat_b))
+            }
+            BeatExpression::IntersectionBeatExpression(intersection) => format!(
+                "({} & {})",
+                beat(&intersection.beat_a),
+                beat(&intersection.beat_b)
+            ),
+            BeatExpression::InvertedBeatExpression(inverted) => {
+                format!("!{}", beat(&inverted.beat))
+            }
+            BeatExpression::EuclideanBeatExpression(euclidean) => format!(
+                "e({}, {}, {})",
+                number(&euclidean.hits),
+                number(&euclidean.steps),
+                number(&euclidean.rotation)
+            ),
+            BeatExpression::ParenthesesBeatExpression(parentheses) => beat(&parentheses.beat),
+            other => format!("{:?}", other),
+        }
+    }
+
+    fn number(expression: &NumberExpression) -> String {
+        match expression {
+            NumberExpression::Literal(number) => number.to_string(),
+            NumberExpression::Parameter(name) => name.clone(),
+        }
+    }
+
+    /// Parses all of `source` as a clip expression.
+    fn parsed(source: &str) -> String {
+        let (rest, expression) = parse_clip_expression(source).unwrap();
+        assert_eq!(rest, "", "{}", source);
+        clip(&expression)
+    }
+
+    #[test]
+    fn beats_read_as_clips() {
+        assert_eq!(parsed("x | y @ a | b"), "((x | y) @ (a | b))");
+        assert_eq!(parsed("a : x | y @ b"), "((a : x) | (y @ b))");
+        assert_eq!(parsed("a * 2 | b"), "((a * 2) | b)");
+        assert_eq!(parsed("(x | y) * 2 << 1 @ a"), "((((x | y) * 2) << 1) @ a)");
+        assert_eq!(parsed("x + - | ..-. >> a"), "(((x + -) | ..-.) >> a)");
+        assert_eq!(
+            parsed("e(3, 8) | e(n, 8, 2) @ a"),
+            "((e(3, 8, 0) | e(n, 8, 2)) @ a)"
+        );
+        assert_eq!(parsed("((..-.)) @ a"), "(..-. @ a)");
+        assert!(parse_clip_expression("..-.").is_err());
+        assert!(parse_clip_expression("a & b").is_err());
+    }
+
+    #[test]
+    fn beat_operators_bind_in_order() {
+        assert_eq!(parsed("!x + y & z @ a"), "((!x + (y & z)) @ a)");
+        assert_eq!(parsed("x & !!y | z @ a"), "(((x & !!y) | z) @ a)");
+        assert_eq!(parsed("!x * 2 << 1 >> a"), "(!((x * 2) << 1) >> a)");
+        assert_eq!(parsed("x + y + z @ a"), "(((x + y) + z) @ a)");
+        assert_eq!(parsed("!(x | y) & .- @ a"), "((!(x | y) & .-) @ a)");
+        assert_eq!(parsed("x << n + y @ a"), "(((x << n) + y) @ a)");
+        for source in ["x + @ a", "x & @ a", "! @ a", "x << @ a", "x * @ a"] {
+            assert!(parse_clip_expression(source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn groups_are_dot_or_number_beats_with_their_steps() {
+        assert_eq!(parsed("{. - .}/12 @ a"), "(.-. @ a)");
+        assert_eq!(parsed("{ ... } / 12 | .. @ a"), "((... | ..) @ a)");
+        for source in [
+            "{...}/0 @ a",
+            "{...} @ a",
+            "{...}/ @ a",
+            "{}/12 @ a",
+            "{.3}/12 @ a",
+            "{..x}/12 @ a",
+        ] {
+            assert!(
+                matches!(parse_clip_expression(source), Err(nom::Err::Failure(_))),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn comments_go_wherever_whitespace_does() {
+        assert_eq!(
+            parsed("a /* x */ | // y\n b # z\n * 2 : -"),
+            "(a | ((b * 2) : -))"
+        );
+        assert_eq!(
+            parsed(".--. /* rest */ | # -.\n -. @ - // nothing\n"),
+            "((.--. | -.) @ -)"
+        );
+        assert_eq!(parsed("x | -- // x\n>> a"), parsed("x | -- >> a"));
+        let source = "// a set\ndirectory = 'd/'; # where\nextension = '.mov';\n\
+                      /* clip a = 'a'; */ clip /* name */ a = 'b' /* ; */;\n\
+                      a // the end";
+        let main = parse(source).unwrap();
+        assert_eq!(main.declarations.len(), 1);
+        assert_eq!(clip(main.main_expression.as_ref().unwrap()), "a");
+    }
+
+    #[test]
+    fn tempo_and_meter_are_declared() {
+        let main = |declarations: &str| {
+            parse(&format!(
+                "directory = 'd/'; extension = '.mov';\n{}\n'a'",
+                declarations
+            ))
+        };
+        let declared = main("bpm = 128.5; meter = 7/8;").unwrap();
+        assert_eq!(declared.bpm_declaration.unwrap().bpm, 128.5);
+        let meter = declared.meter_declaration.unwrap();
+        assert_eq!((meter.beats, meter.unit), (7, 8));
+        let undeclared = main("").unwrap();
+        assert!(undeclared.bpm_declaration.is_none());
+        assert!(undeclared.meter_declaration.is_none());
+        let meter = MeterDeclaration::default();
+        assert_eq!((meter.beats, meter.unit), (4, 4));
+        assert!(main("meter = 3/4;").unwrap().bpm_declaration.is_none());
+        let reordered = main("meter = 7/8; clip b = 'b'; bpm = 128;").unwrap();
+        assert_eq!(reordered.bpm_declaration.unwrap().bpm, 128.0);
+        assert_eq!(reordered.meter_declaration.unwrap().beats, 7);
+        assert_eq!(reordered.declarations.len(), 1);
+        let error = main("clip b = 'b';\nbpm = 128;\nbpm = 130;").unwrap_err();
+        assert_eq!((error.line, error.column), (4, 1));
+        for (declarations, expected) in [
+            ("bpm = fast;", "tempo in beats per minute"),
+            ("bpm = 128", "';'"),
+            ("bpm = 0;", "tempo above 0"),
+            ("bpm = 0.0;", "tempo above 0"),
+            ("bpm = 128; bpm = 130;", "only one tempo declaration"),
+            (
+                "meter = 7/8; clip b = 'b'; meter = 3/4;",
+                "only one meter declaration",
+            ),
+            ("meter = 0/4;", "beats per bar"),
+            ("meter = 7/3;", "beat unit 1, 2, 4, 8 or 16"),
+            ("meter = 7;", "'/'"),
+        ] {
+            let error = main(declarations).unwrap_err();
+            assert_eq!(error.expected, [expected], "{}", declarations);
+        }
+    }
+
+    #[test]
+    fn scenes_make_the_main_expression_optional() {
+        let main = |source: &str| {
+            parse(&format!(
+                "directory = 'd/'; extension = '.mov';\n{}",
+                source
+            ))
+        };
+        let scenes = main("scene intro = 'i';\nscene drop = 'a' | 'b';\n").unwrap();
+        assert!(scenes.main_expression.is_none());
+        let names: Vec<&str> = scenes
+            .declarations
+            .iter()
+            .filter_map(|declaration| match declaration {
+                Declaration::SceneDeclaration(scene) => Some(scene.name.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names, ["intro", "drop"]);
+        let both = main("scene intro = 'i';\n'm'").unwrap();
+        assert_eq!(clip(both.main_expression.as_ref().unwrap()), "'m'");
+        assert_eq!(
+            main("clip a = 'a';\n").unwrap_err().expected,
+            ["clip expression"]
+        );
+        assert!(main("scene intro = ;\n").is_err());
+        assert!(main("scene 2 = 'a';\n").is_err());
+    }
+
+    fn speed(source: &str) -> (isize, usize) {
+        match parse_clip_expression(source) {
+            Ok(("", ClipExpression::Speed(speed))) => (speed.numerator, speed.denominator),
+            other => panic!("{} is no speed change: {:?}", source, other),
+        }
+    }
+
+    #[test]
+    fn speeds() {
+        assert_eq!(speed("a ~ 2"), (2, 1));
+        assert_eq!(speed("a ~ 0.25"), (25, 100));
+        assert_eq!(speed("a ~ -1.5"), (-15, 10));
+        assert_eq!(
+            speed("a ~ -0.00000000000000001"),
+            (-1, 100_000_000_000_000_000)
+        );
+        assert_eq!(
+            speed("a ~ 999999999999999999"),
+            (999_999_999_999_999_999, 1)
+        );
+        for source in [
+            "a ~ 0.00000000000000000001",
+            "a ~ 9999999999999999999",
+            "a ~ -1.000000000000000000",
+        ] {
+            assert!(
+                matches!(parse_clip_expression(source), Err(nom::Err::Failure(_))),
+                "{}",
+                source
+            );
+        }
+        assert!(parse_clip_expression("a ~ .5").is_err());
+    }
+
+    fn fit(source: &str) -> (Option<f64>, Option<f64>, String) {
+        match parse_clip_expression(source) {
+            Ok(("", ClipExpression::Fit(fit))) => (fit.start, fit.end, number(&fit.beats)),
+            other => panic!("{} is no fit: {:?}", source, other),
+        }
+    }
+
+    #[test]
+    fn fits() {
+        assert_eq!(fit("fit a 4"), (None, None, "4".into()));
+        assert_eq!(fit("fit a [1.5s:2s] n"), (Some(1.5), Some(2.0), "n".into()));
+        assert_eq!(fit("fit a [:2s] 1"), (None, Some(2.0), "1".into()));
+        for source in [
+            "fit a 0",
+            "fit a [2s:2s] 4",
+            "fit a [3s:1s] 4",
+            "fit a [1s:2s]",
+        ] {
+            assert!(
+                matches!(parse_clip_expression(source), Err(nom::Err::Failure(_))),
+                "{}",
+                source
+            );
+        }
+    }
+
+    fn offset(source: &str) -> Option<SourceOffset> {
+        match parse_clip_expression(source) {
+            Ok(("", ClipExpression::RawVideo(video))) => video.offset,
+            other => panic!("{} is no raw video: {:?}", source, other),
+        }
+    }
+
+    #[test]
+    fn files_are_played_from_an_offset() {
+        assert_eq!(offset("'a'"), None);
+        assert_eq!(offset("'a'@2.5s"), Some(SourceOffset::Seconds(2.5)));
+        assert_eq!(offset("'a' @ 3s"), Some(SourceOffset::Seconds(3.0)));
+        assert_eq!(offset("'a'@f120"), Some(SourceOffset::Frame(120)));
+        assert_eq!(
+            offset("'a'@cue( drop )"),
+            Some(SourceOffset::Cue("drop".into()))
+        );
+        assert_eq!(
+            offset("'a'@cue(part.two)"),
+            Some(SourceOffset::Cue("part.two".into()))
+        );
+        // the offset belongs to the file, operators to the clip
+        assert_eq!(parsed("'a'@f1 * 2 | 'b'@cue(x)"), "(('a' * 2) | 'b')");
+        assert_eq!(parsed("x @ 'a'@2s"), "(x @ 'a')");
+        for source in [
+            "'a'@",
+            "'a'@2",
+            "'a'@f",
+            "'a'@cue()",
+            "'a'@cue(x",
+            "'a'@-1s",
+        ] {
+            assert!(
+                matches!(parse_clip_expression(source), Err(nom::Err::Failure(_))),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn hold_is_a_prefix_of_one_clip() {
+        assert_eq!(parsed("hold a : b"), "((hold a) : b)");
+        assert_eq!(parsed("hold a * 2 | b"), "((hold (a * 2)) | b)");
+        assert_eq!(parsed("hold (a | b)"), "(hold (a | b))");
+        assert_eq!(parsed("x @ hold hold a"), "(x @ (hold (hold a)))");
+        // a longer name starting with it is a reference, as is it on its own
+        assert_eq!(parsed("holder | hold.a"), "(holder | hold.a)");
+        assert_eq!(parsed("hold"), "hold");
+    }
+
+    /// Parses all of `source` as a time range and writes it out again.
+    fn range(source: &str) -> String {
+        let (rest, range) = parse_time_range_expression(source).unwrap();
+        assert_eq!(rest, "", "{}", source);
+        range.to_string()
+    }
+
+    #[test]
+    fn time_ranges_are_positions_or_lengths_from_the_end() {
+        assert_eq!(range("[1:2]"), "[1:2]");
+        assert_eq!(range("[ 2 : ]"), "[2:]");
+        assert_eq!(range("[:-1]"), "[:-1]");
+        assert_eq!(range("[-2:-1/2]"), "[-2:-1/2]");
+        assert_eq!(range("[3/2:2.3]"), "[3/2:2.3]");
+        // a colon with a bar on either side of it is one between two times
+        assert_eq!(range("[2:1.3 : 4:1]"), "[2:1.3 : 4:1]");
+        assert_eq!(range("[2:1:4:1]"), "[2:1 : 4:1]");
+        assert_eq!(range("[2:1 :]"), "[2:1 :]");
+        assert_eq!(range("[:-1:0]"), "[: -1:0]");
+        // positions count from 1, lengths from 0
+        assert_eq!(range("[-0:]"), "[-0:]");
+        for source in [
+            "[0:]", "[1/2:]", "[0:1 :]", "[1.0:]", "[1/0:]", "[1]", "[1:2",
+        ] {
+            assert!(parse_time_range_expression(source).is_err(), "{}", source);
+        }
+    }
+
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<Option<usize>> = const { std::cell::Cell::new(None) };
+    }
+
+    /// Hands allocations on to the system allocator, counting them on the
+    /// threads that ask to.
+    struct CountingAllocator;
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            // the count is gone while the thread is being torn down
+            let _ = ALLOCATIONS.try_with(|count| count.set(count.get().map(|count| count + 1)));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// The allocations parsing `source` as a clip makes. Every clip or beat
+    /// read allocates, so they grow with how often the text is read.
+    fn allocations(source: &str) -> usize {
+        ALLOCATIONS.with(|count| count.set(Some(0)));
+        let (rest, _) = parse_clip_expression(source).unwrap();
+        assert_eq!(rest, "");
+        ALLOCATIONS.with(|count| count.take()).unwrap()
+    }
+
+    #[test]
+    fn operands_are_read_once() {
+        // read once, twice the operands take at most twice the allocations,
+        // while read again per operand they would take four times as many
+        let grows_linearly = |source: &dyn Fn(usize) -> String, n: usize| {
+            let (once, twice) = (allocations(&source(n)), allocations(&source(2 * n)));
+            assert!(
+                twice * 2 <= once * 5,
+                "{} and {} allocations for {}...",
+                once,
+                twice,
+                &source(n)[..20]
+            );
+        };
+        let chain = |operand: &'static str, after: &'static str| {
+            move |n: usize| vec![operand; n].join(" | ") + after
+        };
+        for (operand, after) in [
+            ("a * 2", ""),
+            ("(a | b) * 2", ""),
+            ("a << 1", " @ x"),
+            ("rev a[1:2]", " : -"),
+        ] {
+            grows_linearly(&chain(operand, after), 100);
+        }
+        // parentheses only as deep as the stack of a test thread allows
+        let nested = |inner: &'static str, after: &'static str| {
+            move |depth: usize| "(".repeat(depth) + inner + &")".repeat(depth) + after
+        };
+        for (inner, after) in [("a", ""), ("a | b", " * 2 @ x"), ("..-.", " @ x")] {
+            grows_linearly(&nested(inner, after), 20);
+        }
+    }
+}
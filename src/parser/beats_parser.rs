@@ -1,10 +1,18 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until, take_while_m_n},
-    character::complete::{alphanumeric1, char, multispace0},
-    combinator::fail,
-    multi::many1,
-    IResult,
+    bytes::complete::take_while_m_n,
+    combinator::{cut, map, opt, verify},
+    error::context,
+    multi::{many1, separated_list1},
+    sequence::{preceded, terminated},
+};
+
+use super::{
+    error::{token, IResult, SyntaxError},
+    parse_number, parse_number_expression, parse_qualified_name,
+    span::Span,
+    whitespace::whitespace0,
+    NumberExpression,
 };
 
 #[derive(Debug, Clone)]
@@ -13,81 +21,309 @@ pub enum BeatExpression {
     NumberBeatExpression(NumberBeatExpression),
     BeatChainExpression(BeatChainExpression),
     ReferenceBeatExpression(ReferenceBeatExpression),
+    EuclideanBeatExpression(EuclideanBeatExpression),
+    UnionBeatExpression(UnionBeatExpression),
+    IntersectionBeatExpression(IntersectionBeatExpression),
+    InvertedBeatExpression(InvertedBeatExpression),
+    RotatedBeatExpression(RotatedBeatExpression),
+    RepeatedBeatExpression(RepeatedBeatExpression),
+    ParenthesesBeatExpression(ParenthesesBeatExpression),
 }
 
+/// Parses beat patterns, references and their `|` chains, which associate to
+/// the right: `a | b | c` is `a | (b | c)`. From loosest to tightest, the
+/// parts of a chain combine with `+`, `&`, prefix `!` and postfix `*` and `<<`.
 pub fn parse_beat_expression(input: &str) -> IResult<&str, BeatExpression> {
-    let (input, beat_expression) = alt((
-        parse_beat_chain_expression,
-        parse_dot_beat_expression,
-        parse_number_beat_expression,
-        parse_reference_beat_expression,
-    ))(input)?;
+    let (input, parts) = parse_beat_chain(input)?;
+    // a dangling `|` is an error here, rather than the end of the chain
+    if let Ok((rest, _)) = token("|")(input) {
+        parse_beat_chain_part(rest)?;
+    }
+
+    Ok((input, chain_beats(parts)))
+}
+
+/// Parses the `|` separated parts of a beat chain. The chain ends before a
+/// `|` that is not followed by a beat.
+pub fn parse_beat_chain(input: &str) -> IResult<&str, Vec<BeatExpression>> {
+    separated_list1(token("|"), parse_beat_chain_part)(input)
+}
+
+pub fn parse_beat_chain_part(input: &str) -> IResult<&str, BeatExpression> {
+    let (input, _) = whitespace0(input)?;
+    parse_beat_union(input)
+}
+
+/// Parses the rest of a beat chain part that starts with `beat`, for one
+/// that was read as a clip until a beat operator followed it.
+pub fn parse_rest_of_beat_chain_part(
+    input: &str,
+    beat: BeatExpression,
+) -> IResult<&str, BeatExpression> {
+    let (input, beat) = parse_beat_postfixes(input, beat)?;
+    let (input, beat) = parse_beat_intersections(input, beat)?;
+    parse_beat_unions(input, beat)
+}
+
+fn parse_beat_union(input: &str) -> IResult<&str, BeatExpression> {
+    let (input, beat) = parse_beat_intersection(input)?;
+    parse_beat_unions(input, beat)
+}
+
+fn parse_beat_unions(mut input: &str, mut beat: BeatExpression) -> IResult<&str, BeatExpression> {
+    while let Ok((rest, _)) = token("+")(input) {
+        let (rest, _) = whitespace0(rest)?;
+        let (rest, beat_b) = cut(parse_beat_intersection)(rest)?;
+        beat = BeatExpression::UnionBeatExpression(UnionBeatExpression {
+            beat_a: Box::new(beat),
+            beat_b: Box::new(beat_b),
+        });
+        input = rest;
+    }
+    Ok((input, beat))
+}
+
+fn parse_beat_intersection(input: &str) -> IResult<&str, BeatExpression> {
+    let (input, beat) = parse_inverted_beat_expression(input)?;
+    parse_beat_intersections(input, beat)
+}
+
+fn parse_beat_intersections(
+    mut input: &str,
+    mut beat: BeatExpression,
+) -> IResult<&str, BeatExpression> {
+    while let Ok((rest, _)) = token("&")(input) {
+        let (rest, _) = whitespace0(rest)?;
+        let (rest, beat_b) = cut(parse_inverted_beat_expression)(rest)?;
+        beat = BeatExpression::IntersectionBeatExpression(IntersectionBeatExpression {
+            beat_a: Box::new(beat),
+            beat_b: Box::new(beat_b),
+        });
+        input = rest;
+    }
+    Ok((input, beat))
+}
+
+pub fn parse_inverted_beat_expression(input: &str) -> IResult<&str, BeatExpression> {
+    let (input, _) = whitespace0(input)?;
+    match token("!")(input) {
+        Ok((rest, _)) => {
+            let (rest, _) = whitespace0(rest)?;
+            let (rest, beat) = cut(parse_inverted_beat_expression)(rest)?;
+            Ok((
+                rest,
+                BeatExpression::InvertedBeatExpression(InvertedBeatExpression {
+                    beat: Box::new(beat),
+                }),
+            ))
+        }
+        Err(_) => parse_postfix_beat_expression(input),
+    }
+}
+
+fn parse_postfix_beat_expression(input: &str) -> IResult<&str, BeatExpression> {
+    let (input, beat) = parse_primary_beat_expression(input)?;
+    parse_beat_postfixes(input, beat)
+}
+
+fn parse_beat_postfixes(
+    mut input: &str,
+    mut beat: BeatExpression,
+) -> IResult<&str, BeatExpression> {
+    loop {
+        if let Ok((rest, _)) = token("*")(input) {
+            let (rest, _) = whitespace0(rest)?;
+            let (rest, repetitions) =
+                cut(context("repetition count", parse_number_expression))(rest)?;
+            beat = BeatExpression::RepeatedBeatExpression(RepeatedBeatExpression {
+                beat: Box::new(beat),
+                repetitions,
+            });
+            input = rest;
+        } else if let Ok((rest, _)) = token("<<")(input) {
+            let (rest, _) = whitespace0(rest)?;
+            let (rest, steps) = cut(context("rotation", parse_number_expression))(rest)?;
+            beat = BeatExpression::RotatedBeatExpression(RotatedBeatExpression {
+                beat: Box::new(beat),
+                steps,
+            });
+            input = rest;
+        } else {
+            return Ok((input, beat));
+        }
+        let (rest, _) = whitespace0(input)?;
+        input = rest;
+    }
+}
+
+fn parse_primary_beat_expression(input: &str) -> IResult<&str, BeatExpression> {
+    context(
+        "beat expression",
+        alt((
+            parse_parentheses_beat_expression,
+            parse_grouped_beat_expression,
+            parse_dot_beat_expression,
+            parse_number_beat_expression,
+            parse_euclidean_beat_expression,
+            parse_reference_beat_expression,
+        )),
+    )(input)
+}
+
+pub fn parse_parentheses_beat_expression(input: &str) -> IResult<&str, BeatExpression> {
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("(")(input)?;
+    let (input, beat) = parse_beat_expression(input)?;
+    let (input, _) = token(")")(input)?;
+    let (input, _) = whitespace0(input)?;
+
+    Ok((
+        input,
+        BeatExpression::ParenthesesBeatExpression(ParenthesesBeatExpression {
+            beat: Box::new(beat),
+        }),
+    ))
+}
 
-    Ok((input, beat_expression))
+pub fn chain_beats(parts: Vec<BeatExpression>) -> BeatExpression {
+    parts
+        .into_iter()
+        .rev()
+        .reduce(|beat_b, beat_a| {
+            BeatExpression::BeatChainExpression(BeatChainExpression {
+                beat_a: Box::new(beat_a),
+                beat_b: Box::new(beat_b),
+            })
+        })
+        .expect("a beat chain has at least one part")
 }
 
 pub fn parse_dot_beat_expression(input: &str) -> IResult<&str, BeatExpression> {
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
     let (input, beats) = parse_dot_beats(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
 
     Ok((
         input,
-        BeatExpression::DotBeatExpression(DotBeatExpression { beats }),
+        BeatExpression::DotBeatExpression(DotBeatExpression {
+            beats,
+            resolution: DEFAULT_RESOLUTION,
+        }),
     ))
 }
 
 pub fn parse_dot_beats(input: &str) -> IResult<&str, Vec<bool>> {
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
     let (input, beats) = many1(parse_dot_beat)(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
 
     Ok((input, beats))
 }
 
 pub fn parse_dot_beat(input: &str) -> IResult<&str, bool> {
-    let (input, beat) = alt((tag("."), tag("-")))(input)?;
+    let (input, beat) = alt((token("."), token("-")))(input)?;
 
     Ok((input, beat == "."))
 }
 
 pub fn parse_number_beat_expression(input: &str) -> IResult<&str, BeatExpression> {
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
     let (input, beats) = parse_number_beats(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
 
     Ok((
         input,
-        BeatExpression::NumberBeatExpression(NumberBeatExpression { beats }),
+        BeatExpression::NumberBeatExpression(NumberBeatExpression {
+            beats,
+            resolution: DEFAULT_RESOLUTION,
+        }),
     ))
 }
 
+/// Parses a dot or number pattern with the number of its steps that make a
+/// whole note, like `{...}/12` for eighth note triplets or `{. . . . .}/20`
+/// for quintuplet sixteenths.
+pub fn parse_grouped_beat_expression(input: &str) -> IResult<&str, BeatExpression> {
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token("{")(input)?;
+    let (input, _) = whitespace0(input)?;
+    // the steps of a group may be spaced out, like `{. - .}/12`
+    let spaced_dots = map(many1(terminated(parse_dot_beat, whitespace0)), |beats| {
+        BeatExpression::DotBeatExpression(DotBeatExpression {
+            beats,
+            resolution: DEFAULT_RESOLUTION,
+        })
+    });
+    let (input, pattern) = cut(context(
+        "dot or number beats",
+        alt((spaced_dots, parse_number_beat_expression)),
+    ))(input)?;
+    let (input, _) = cut(token("}"))(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = cut(token("/"))(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, resolution) = cut(context(
+        "steps per whole note",
+        verify(parse_number, |resolution| *resolution > 0),
+    ))(input)?;
+    let (input, _) = whitespace0(input)?;
+
+    let beat = match pattern {
+        BeatExpression::DotBeatExpression(dots) => {
+            BeatExpression::DotBeatExpression(DotBeatExpression { resolution, ..dots })
+        }
+        BeatExpression::NumberBeatExpression(numbers) => {
+            BeatExpression::NumberBeatExpression(NumberBeatExpression {
+                resolution,
+                ..numbers
+            })
+        }
+        _ => unreachable!("a group holds dot or number beats"),
+    };
+    Ok((input, beat))
+}
+
 pub fn parse_number_beats(input: &str) -> IResult<&str, Vec<usize>> {
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
     let (input, beats) = many1(parse_number_beat)(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
 
     Ok((input, beats))
 }
 
 pub fn parse_number_beat(input: &str) -> IResult<&str, usize> {
-    let (input, _) = multispace0(input)?;
-    let (input, beat) = take_while_m_n(1, 1, |c: char| c.is_ascii_digit())(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, beat) = context(
+        "beat gap digit",
+        take_while_m_n(1, 1, |c: char| c.is_ascii_digit()),
+    )(input)?;
     let beat = beat
         .parse::<usize>()
-        .map_err(|_| nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Char)))?;
+        .map_err(|_| nom::Err::Error(SyntaxError::new(input, nom::error::ErrorKind::Char)))?;
 
     Ok((input, beat))
 }
 
+/// How many steps of a dot or number pattern make a whole note, unless it
+/// is grouped with another number: sixteenths.
+pub const DEFAULT_RESOLUTION: usize = 16;
+
+/// The most steps a beat can take, so that a count mistyped with too many
+/// digits is reported rather than filling up memory.
+pub const MAX_STEPS: usize = 1 << 16;
+
 #[derive(Debug, Clone)]
 pub struct DotBeatExpression {
     pub beats: Vec<bool>,
+    /// The steps per whole note.
+    pub resolution: usize,
 }
 
 #[derive(Debug, Clone)]
 pub struct NumberBeatExpression {
     pub beats: Vec<usize>,
+    /// The steps per whole note.
+    pub resolution: usize,
 }
 
 #[derive(Debug, Clone)]
@@ -99,37 +335,99 @@ pub struct BeatChainExpression {
 #[derive(Debug, Clone)]
 pub struct ReferenceBeatExpression {
     pub name: String,
+    pub span: Span,
 }
 
 pub fn parse_reference_beat_expression(input: &str) -> IResult<&str, BeatExpression> {
-    let (input, _) = multispace0(input)?;
-    let (input, name) = alphanumeric1(input)?;
-    let (input, _) = multispace0(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (rest, name) = context("beat name", parse_qualified_name)(input)?;
+    let span = Span::between(input, rest);
+    let (input, _) = whitespace0(rest)?;
 
     Ok((
         input,
-        BeatExpression::ReferenceBeatExpression(ReferenceBeatExpression { name: name.into() }),
+        BeatExpression::ReferenceBeatExpression(ReferenceBeatExpression {
+            name: name.into(),
+            span,
+        }),
     ))
 }
 
-pub fn parse_beat_chain_expression(input: &str) -> IResult<&str, BeatExpression> {
-    let (input, _) = multispace0(input)?;
-    let (input, first_part) = take_until("|")(input)?;
-    let (rest_first, beat_a) = parse_beat_expression(first_part)?;
-    if !rest_first.is_empty() {
-        return fail(rest_first);
-    }
-    let (input, _) = multispace0(input)?;
-    let (input, _) = char('|')(input)?;
-    let (input, _) = multispace0(input)?;
-    let (input, beat_b) = parse_beat_expression(input)?;
-    let (input, _) = multispace0(input)?;
+/// `hits` sixteenths spread as evenly as possible over `steps` sixteenths,
+/// rotated `rotation` steps to the left.
+#[derive(Debug, Clone)]
+pub struct EuclideanBeatExpression {
+    pub hits: NumberExpression,
+    pub steps: NumberExpression,
+    pub rotation: NumberExpression,
+    pub span: Span,
+}
+
+/// Parses `e(hits, steps)` or `e(hits, steps, rotation)`.
+pub fn parse_euclidean_beat_expression(input: &str) -> IResult<&str, BeatExpression> {
+    let (start, _) = whitespace0(input)?;
+    let (input, _) = token("e")(start)?;
+    let (input, _) = token("(")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, hits) = context("number of hits", parse_number_expression)(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, _) = token(",")(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, steps) = context("number of steps", parse_number_expression)(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (input, rotation) = opt(preceded(
+        token(","),
+        preceded(whitespace0, context("rotation", parse_number_expression)),
+    ))(input)?;
+    let (input, _) = whitespace0(input)?;
+    let (rest, _) = token(")")(input)?;
+    let (input, _) = whitespace0(rest)?;
 
     Ok((
         input,
-        BeatExpression::BeatChainExpression(BeatChainExpression {
-            beat_a: Box::new(beat_a),
-            beat_b: Box::new(beat_b),
+        BeatExpression::EuclideanBeatExpression(EuclideanBeatExpression {
+            hits,
+            steps,
+            rotation: rotation.unwrap_or(NumberExpression::Literal(0)),
+            span: Span::between(start, rest),
         }),
     ))
 }
+
+/// The hits of both beats, as long as the longer one.
+#[derive(Debug, Clone)]
+pub struct UnionBeatExpression {
+    pub beat_a: Box<BeatExpression>,
+    pub beat_b: Box<BeatExpression>,
+}
+
+/// The hits the beats share, as long as the longer one.
+#[derive(Debug, Clone)]
+pub struct IntersectionBeatExpression {
+    pub beat_a: Box<BeatExpression>,
+    pub beat_b: Box<BeatExpression>,
+}
+
+/// A hit on every step of the beat that has none.
+#[derive(Debug, Clone)]
+pub struct InvertedBeatExpression {
+    pub beat: Box<BeatExpression>,
+}
+
+/// The beat rotated `steps` of its steps to the left.
+#[derive(Debug, Clone)]
+pub struct RotatedBeatExpression {
+    pub beat: Box<BeatExpression>,
+    pub steps: NumberExpression,
+}
+
+#[derive(Debug, Clone)]
+pub struct RepeatedBeatExpression {
+    pub beat: Box<BeatExpression>,
+    pub repetitions: NumberExpression,
+}
+
+#[derive(Debug, Clone)]
+pub struct ParenthesesBeatExpression {
+    pub beat: Box<BeatExpression>,
+}
@@ -0,0 +1,226 @@
+use std::fmt;
+
+use nom::{
+    bytes::complete::tag,
+    error::{ContextError, ErrorKind, FromExternalError},
+};
+
+pub type IResult<I, O> = nom::IResult<I, O, SyntaxError<I>>;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expected {
+    Token(&'static str),
+    Label(&'static str),
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expected::Token(token) => write!(f, "'{}'", token),
+            Expected::Label(label) => write!(f, "{}", label),
+        }
+    }
+}
+
+/// Error type threaded through all nom parsers. When alternatives fail, the
+/// one that got furthest into the input wins, and alternatives failing at the
+/// same position merge what they expected.
+#[derive(Debug, Clone)]
+pub struct SyntaxError<I> {
+    pub input: I,
+    pub kind: ErrorKind,
+    expected: Vec<Expected>,
+}
+
+impl<I> SyntaxError<I> {
+    pub fn new(input: I, kind: ErrorKind) -> Self {
+        Self {
+            input,
+            kind,
+            expected: Vec::new(),
+        }
+    }
+
+    pub fn expected(input: I, label: &'static str) -> Self {
+        Self {
+            input,
+            kind: ErrorKind::Fail,
+            expected: vec![Expected::Label(label)],
+        }
+    }
+}
+
+impl<'a> nom::error::ParseError<&'a str> for SyntaxError<&'a str> {
+    fn from_error_kind(input: &'a str, kind: ErrorKind) -> Self {
+        Self::new(input, kind)
+    }
+
+    fn append(_input: &'a str, _kind: ErrorKind, other: Self) -> Self {
+        other
+    }
+
+    fn or(mut self, other: Self) -> Self {
+        match self.input.as_ptr().cmp(&other.input.as_ptr()) {
+            std::cmp::Ordering::Greater => self,
+            std::cmp::Ordering::Less => other,
+            std::cmp::Ordering::Equal => {
+                for expected in other.expected {
+                    if !self.expected.contains(&expected) {
+                        self.expected.push(expected);
+                    }
+                }
+                self
+            }
+        }
+    }
+}
+
+impl<'a> ContextError<&'a str> for SyntaxError<&'a str> {
+    fn add_context(input: &'a str, ctx: &'static str, mut other: Self) -> Self {
+        if input.as_ptr() == other.input.as_ptr() {
+            other.expected = vec![Expected::Label(ctx)];
+        }
+        other
+    }
+}
+
+impl<'a, E> FromExternalError<&'a str, E> for SyntaxError<&'a str> {
+    fn from_external_error(input: &'a str, kind: ErrorKind, _e: E) -> Self {
+        Self::new(input, kind)
+    }
+}
+
+/// Like `tag`, but reports the token as expected when it is missing.
+pub fn token<'a>(token: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    move |input: &'a str| {
+        tag(token)(input).map_err(|e| {
+            e.map(|mut e: SyntaxError<&str>| {
+                e.expected = vec![Expected::Token(token)];
+                e
+            })
+        })
+    }
+}
+
+/// A parse failure located in the source text, ready to be shown to the user.
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub snippet: String,
+    pub expected: Vec<String>,
+    source_line: String,
+}
+
+impl ParseError {
+    pub fn new(source: &str, error: nom::Err<SyntaxError<&str>>) -> Self {
+        let (offset, expected) = match error {
+            nom::Err::Error(e) | nom::Err::Failure(e) => (
+                (e.input.as_ptr() as usize)
+                    .saturating_sub(source.as_ptr() as usize)
+                    .min(source.len()),
+                e.expected.iter().map(|e| e.to_string()).collect(),
+            ),
+            nom::Err::Incomplete(_) => (source.len(), Vec::new()),
+        };
+        let line_start = source[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = source[offset..]
+            .find('\n')
+            .map(|i| offset + i)
+            .unwrap_or(source.len());
+        Self {
+            line: source[..offset].matches('\n').count() + 1,
+            column: source[line_start..offset].chars().count() + 1,
+            snippet: source[offset..line_end].chars().take(24).collect(),
+            expected,
+            source_line: source[line_start..line_end].to_string(),
+        }
+    }
+
+    /// What was expected and what was found instead, without where.
+    pub fn message(&self) -> String {
+        let found = if self.snippet.trim().is_empty() {
+            "found end of line".to_string()
+        } else {
+            format!("found \"{}\"", self.snippet.trim_end())
+        };
+        match self.expected.as_slice() {
+            [] => found,
+            [expected] => format!("expected {}, {}", expected, found),
+            expected => format!("expected one of {}, {}", expected.join(", "), found),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "parse error at line {}, column {}",
+            self.line, self.column
+        )?;
+        if self.expected.is_empty() {
+            writeln!(f, ", {}", self.message())?;
+        } else {
+            writeln!(f, ": {}", self.message())?;
+        }
+        writeln!(f, "  {}", self.source_line)?;
+        write!(f, "  {}^", " ".repeat(self.column - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn error(source: &str) -> ParseError {
+        let source = format!("directory = 'd/';\nextension = '.mov';\n{}", source);
+        parse(&source).expect_err("the source has a mistake")
+    }
+
+    #[test]
+    fn a_mistake_is_located_where_it_is_made() {
+        let e = error("clip a = 'a' | ;\na");
+        assert_eq!((e.line, e.column), (3, 16));
+        assert_eq!(e.message(), "expected clip expression, found \";\"");
+        assert_eq!(
+            e.to_string(),
+            "parse error at line 3, column 16: expected clip expression, found \";\"\n  \
+             clip a = 'a' | ;\n                 ^"
+        );
+        let e = error("beat b = .-x-;\nb @ 'a'");
+        assert_eq!((e.line, e.column), (3, 12));
+        assert_eq!(e.message(), "expected ';', found \"x-;\"");
+    }
+
+    #[test]
+    fn columns_count_characters() {
+        let e = error("'ä' | ümlaut(");
+        assert_eq!((e.line, e.column), (3, 7));
+        assert_eq!(e.snippet, "ümlaut(");
+    }
+
+    #[test]
+    fn the_end_of_a_line_is_found_too() {
+        let e = error("'a' * ");
+        assert_eq!(e.message(), "expected repetition count, found end of line");
+        let e = error("/* open\n'a'");
+        assert_eq!((e.line, e.column), (3, 3));
+        assert_eq!(e.expected, ["end of block comment '*/'"]);
+    }
+
+    #[test]
+    fn alternatives_failing_at_one_place_are_all_expected() {
+        let input = "x";
+        let a = SyntaxError::expected(input, "clip");
+        let b = SyntaxError::expected(input, "beat");
+        let merged = nom::error::ParseError::or(a, b);
+        let e = ParseError::new(input, nom::Err::Error(merged));
+        assert_eq!(e.message(), "expected one of clip, beat, found \"x\"");
+        // the alternative that got further wins
+        let further = SyntaxError::expected(&input[1..], "name");
+        let merged = nom::error::ParseError::or(SyntaxError::expected(input, "clip"), further);
+        assert_eq!(ParseError::new(input, nom::Err::Error(merged)).column, 2);
+    }
+}
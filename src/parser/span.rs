@@ -0,0 +1,147 @@
+use super::{
+    beats_parser::{
+        BeatChainExpression, BeatExpression, EuclideanBeatExpression, IntersectionBeatExpression,
+        InvertedBeatExpression, ParenthesesBeatExpression, ReferenceBeatExpression,
+        RepeatedBeatExpression, RotatedBeatExpression, UnionBeatExpression,
+    },
+    ApplyBeatExpression, ArgumentExpression, CallClipExpression, ChoiceClipExpression,
+    ClipChainExpression, ClipExpression, ClipLayerExpression, ClipLoopExpression, Declaration,
+    FitClipExpression, HoldClipExpression, MultiVideoExpression, ParenthesesClipExpression,
+    PingPongClipExpression, RawVideoExpression, ReferenceClipExpression, RestartExpression,
+    ReverseClipExpression, SpeedClipExpression, TruncatedClipExpression,
+};
+
+/// Where something was written: a byte range of the file with index `file`
+/// among the files the importer read, the main file being 0.
+///
+/// A parser only sees the input that is left, so while parsing `start` and
+/// `end` hold how much of it was left at either end. `parse` and
+/// `parse_library` turn them into offsets once the whole source is known.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub file: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// The span of what was parsed from `input` when `rest` was left.
+    pub fn between(input: &str, rest: &str) -> Self {
+        Span {
+            file: 0,
+            start: input.len(),
+            end: rest.len(),
+        }
+    }
+
+    /// Turns what was left of `source` at either end into offsets into it.
+    pub fn locate(&mut self, source: &str) {
+        self.start = source.len() - self.start;
+        self.end = source.len() - self.end;
+    }
+
+    /// The line and column `start` is at, both counted from 1, with columns
+    /// counted in characters.
+    pub fn line_column(&self, source: &str) -> (usize, usize) {
+        let start = self.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        (
+            source[..start].matches('\n').count() + 1,
+            source[line_start..start].chars().count() + 1,
+        )
+    }
+}
+
+pub fn declaration_spans(declaration: &mut Declaration, f: &mut impl FnMut(&mut Span)) {
+    match declaration {
+        Declaration::ClipDeclaration(cd) => {
+            f(&mut cd.span);
+            clip_spans(&mut cd.expression, f);
+        }
+        Declaration::BeatDeclaration(bd) => {
+            f(&mut bd.span);
+            beat_spans(&mut bd.expression, f);
+        }
+        Declaration::SceneDeclaration(sd) => {
+            f(&mut sd.span);
+            clip_spans(&mut sd.expression, f);
+        }
+        Declaration::ImportDeclaration(_) => (),
+    }
+}
+
+pub fn clip_spans(clip: &mut ClipExpression, f: &mut impl FnMut(&mut Span)) {
+    match clip {
+        ClipExpression::Empty => (),
+        ClipExpression::RawVideo(RawVideoExpression { span, .. })
+        | ClipExpression::MultiVideo(MultiVideoExpression { span, .. })
+        | ClipExpression::Reference(ReferenceClipExpression { span, .. }) => f(span),
+        ClipExpression::Call(CallClipExpression {
+            arguments, span, ..
+        }) => {
+            f(span);
+            for argument in arguments {
+                match argument {
+                    ArgumentExpression::Number(_) => (),
+                    ArgumentExpression::Clip(clip) => clip_spans(clip, f),
+                    ArgumentExpression::Beat(beat) => beat_spans(beat, f),
+                }
+            }
+        }
+        ClipExpression::Chain(ClipChainExpression { clip_a, clip_b })
+        | ClipExpression::Layer(ClipLayerExpression { clip_a, clip_b }) => {
+            clip_spans(clip_a, f);
+            clip_spans(clip_b, f);
+        }
+        ClipExpression::Loop(ClipLoopExpression { clip, span, .. })
+        | ClipExpression::Truncated(TruncatedClipExpression { clip, span, .. })
+        | ClipExpression::Fit(FitClipExpression { clip, span, .. }) => {
+            f(span);
+            clip_spans(clip, f);
+        }
+        ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression { clip })
+        | ClipExpression::Reverse(ReverseClipExpression { clip })
+        | ClipExpression::Hold(HoldClipExpression { clip })
+        | ClipExpression::Speed(SpeedClipExpression { clip, .. })
+        | ClipExpression::PingPong(PingPongClipExpression { clip, .. }) => clip_spans(clip, f),
+        ClipExpression::Choice(ChoiceClipExpression { alternatives }) => {
+            for alternative in alternatives {
+                clip_spans(&mut alternative.clip, f);
+            }
+        }
+        ClipExpression::Restart(RestartExpression {
+            beat_expression,
+            clip_expression,
+        })
+        | ClipExpression::ApplyBeat(ApplyBeatExpression {
+            beat_expression,
+            clip_expression,
+        }) => {
+            beat_spans(beat_expression, f);
+            clip_spans(clip_expression, f);
+        }
+    }
+}
+
+pub fn beat_spans(beat: &mut BeatExpression, f: &mut impl FnMut(&mut Span)) {
+    match beat {
+        BeatExpression::DotBeatExpression(_) | BeatExpression::NumberBeatExpression(_) => (),
+        BeatExpression::ReferenceBeatExpression(ReferenceBeatExpression { span, .. })
+        | BeatExpression::EuclideanBeatExpression(EuclideanBeatExpression { span, .. }) => f(span),
+        BeatExpression::BeatChainExpression(BeatChainExpression { beat_a, beat_b })
+        | BeatExpression::UnionBeatExpression(UnionBeatExpression { beat_a, beat_b })
+        | BeatExpression::IntersectionBeatExpression(IntersectionBeatExpression {
+            beat_a,
+            beat_b,
+        }) => {
+            beat_spans(beat_a, f);
+            beat_spans(beat_b, f);
+        }
+        BeatExpression::InvertedBeatExpression(InvertedBeatExpression { beat })
+        | BeatExpression::RotatedBeatExpression(RotatedBeatExpression { beat, .. })
+        | BeatExpression::RepeatedBeatExpression(RepeatedBeatExpression { beat, .. })
+        | BeatExpression::ParenthesesBeatExpression(ParenthesesBeatExpression { beat }) => {
+            beat_spans(beat, f)
+        }
+    }
+}
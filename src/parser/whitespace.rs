@@ -0,0 +1,81 @@
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take_until},
+    character::complete::{multispace1, not_line_ending},
+    combinator::{cut, recognize},
+    error::{context, ErrorKind},
+    multi::many0_count,
+    sequence::{pair, tuple},
+};
+
+use super::error::{token, IResult, SyntaxError};
+
+/// Skips whitespace, `//` and `#` line comments and `/* */` block comments.
+pub fn whitespace0(input: &str) -> IResult<&str, &str> {
+    recognize(many0_count(alt((
+        multispace1,
+        recognize(pair(alt((tag("//"), tag("#"))), not_line_ending)),
+        recognize(tuple((
+            tag("/*"),
+            cut(context("end of block comment '*/'", take_until("*/"))),
+            token("*/"),
+        ))),
+    ))))(input)
+}
+
+/// Like `take_until`, but does not look for `pattern` inside comments or
+/// quoted file names.
+pub fn take_until_code<'a>(
+    pattern: &'static str,
+) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    move |input: &'a str| {
+        let mut rest = input;
+        while !rest.is_empty() {
+            if rest.starts_with(pattern) {
+                let offset = input.len() - rest.len();
+                return Ok((rest, &input[..offset]));
+            }
+            rest = if rest.starts_with("//") || rest.starts_with('#') {
+                &rest[rest.find('\n').unwrap_or(rest.len())..]
+            } else if let Some(comment) = rest.strip_prefix("/*") {
+                comment.find("*/").map_or("", |end| &comment[end + 2..])
+            } else if rest.starts_with('\'') || rest.starts_with('"') {
+                let quote = &rest[..1];
+                rest[1..].find(quote).map_or("", |end| &rest[end + 2..])
+            } else {
+                let c = rest.chars().next().unwrap_or_default();
+                &rest[c.len_utf8()..]
+            };
+        }
+        Err(nom::Err::Error(SyntaxError::new(
+            input,
+            ErrorKind::TakeUntil,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_are_whitespace() {
+        assert_eq!(
+            whitespace0("  // a\n# b\n/* c\n d */x").unwrap(),
+            ("x", "  // a\n# b\n/* c\n d */")
+        );
+        assert_eq!(whitespace0("-- x").unwrap(), ("-- x", ""));
+        assert_eq!(whitespace0("/ x").unwrap(), ("/ x", ""));
+        assert!(matches!(whitespace0("/* open"), Err(nom::Err::Failure(_))));
+    }
+
+    #[test]
+    fn code_is_found_outside_comments_and_file_names() {
+        let input = "a /* ; */ 'x;y' \"z;\" // ;\n# ;\n; b";
+        let (rest, code) = take_until_code(";")(input).unwrap();
+        assert_eq!(rest, "; b");
+        assert_eq!(code.len(), input.len() - 3);
+        assert!(take_until_code(";")("a // ;").is_err());
+        assert!(take_until_code(";")("'a;").is_err());
+    }
+}
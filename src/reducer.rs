@@ -1,22 +1,210 @@
 use std::{
     collections::{HashMap, HashSet},
-    ops::{Add, Sub},
+    fmt,
+    sync::Arc,
 };
 
 use crate::{
     parser::{
         beats_parser::{
-            BeatChainExpression, BeatExpression, DotBeatExpression, NumberBeatExpression,
-            ReferenceBeatExpression,
+            BeatChainExpression, BeatExpression, DotBeatExpression, EuclideanBeatExpression,
+            IntersectionBeatExpression, InvertedBeatExpression, NumberBeatExpression,
+            ParenthesesBeatExpression, ReferenceBeatExpression, RepeatedBeatExpression,
+            RotatedBeatExpression, UnionBeatExpression, DEFAULT_RESOLUTION, MAX_STEPS,
         },
-        ApplyBeatExpression, ClipChainExpression, ClipExpression, ClipLayerExpression,
-        ClipLoopExpression, Main, MultiVideoExpression, ParenthesesClipExpression,
-        RawVideoExpression, ReferenceClipExpression, RestartExpression, TruncatedClipExpression,
+        span::Span,
+        ApplyBeatExpression, ChoiceAlternative, ChoiceClipExpression, ClipChainExpression,
+        ClipDeclaration, ClipExpression, ClipLayerExpression, ClipLoopExpression,
+        FitClipExpression, HoldClipExpression, Main, MeterDeclaration, MultiVideoExpression,
+        NumberExpression, ParenthesesClipExpression, PingPongClipExpression, RawVideoExpression,
+        ReferenceClipExpression, RestartExpression, ReverseClipExpression, SourceOffset,
+        SpeedClipExpression, TimeRangeExpression, TruncatedClipExpression,
     },
-    util::{frac_to_time, time_expression_to_time, time_to_frac},
+    rng::Rng,
+    time::Time,
+    util::{bar_length, time_expression_to_time},
 };
 
-pub fn reduce(input: Main, available_files: &HashSet<String>) -> ReducedClip {
+use self::{
+    expander::{expand_beat_expression, expand_clip_expression},
+    validator::{check_collisions, check_lengths, check_references, unused_declarations},
+};
+
+mod expander;
+mod validator;
+
+#[derive(Debug)]
+pub enum ReduceErrorKind {
+    /// A file a clip plays that is not in the directory.
+    MissingFile(String),
+    /// A clip or beat that neither the main expression nor any scene uses.
+    Unused(String),
+    UnknownClip(String),
+    UnknownBeat(String),
+    UnknownParameter(String),
+    ArgumentCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    ArgumentKind {
+        parameter: String,
+        expected: &'static str,
+    },
+    RecursiveCall(String),
+    /// The names along the cycle, starting and ending with the same one.
+    RecursiveDefinition(Vec<String>),
+    NameCollision(String),
+    /// A range, as written, that selects nothing of a clip `beats` long.
+    EmptyRange {
+        range: String,
+        beats: Time,
+    },
+    RangeBeforeStart {
+        range: String,
+        beats: Time,
+    },
+    /// The main expression or a scene, which is played over and over, takes
+    /// no time.
+    ZeroLengthLoop(String),
+    /// A clip fitted to a parameter that is 0 beats.
+    ZeroLengthFit,
+    /// A length, or a time in a clip, that does not fit into a time.
+    Overflow,
+    /// A beat of more than `MAX_STEPS` steps.
+    LongBeat,
+}
+
+impl ReduceErrorKind {
+    pub fn at(self, span: Span) -> ReduceError {
+        ReduceError {
+            kind: self,
+            span: Some(span),
+        }
+    }
+}
+
+impl fmt::Display for ReduceErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReduceErrorKind::MissingFile(file) => write!(f, "file {} does not exist", file),
+            ReduceErrorKind::Unused(name) => write!(f, "{} is never used", name),
+            ReduceErrorKind::UnknownClip(name) => write!(f, "unknown clip {}", name),
+            ReduceErrorKind::UnknownBeat(name) => write!(f, "unknown beat {}", name),
+            ReduceErrorKind::UnknownParameter(name) => write!(f, "unknown parameter {}", name),
+            ReduceErrorKind::ArgumentCount {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "clip {} takes {} arguments, but {} were given",
+                name, expected, found
+            ),
+            ReduceErrorKind::ArgumentKind {
+                parameter,
+                expected,
+            } => write!(
+                f,
+                "the argument for parameter {} cannot be used as a {}",
+                parameter, expected
+            ),
+            ReduceErrorKind::RecursiveCall(name) => write!(f, "clip {} calls itself", name),
+            ReduceErrorKind::RecursiveDefinition(names) => {
+                write!(f, "recursive definition {}", names.join(" -> "))
+            }
+            ReduceErrorKind::NameCollision(name) => {
+                write!(f, "{} is declared more than once", name)
+            }
+            ReduceErrorKind::EmptyRange { range, beats } => {
+                write!(f, "range {} of a clip {} beats long is empty", range, beats)
+            }
+            ReduceErrorKind::RangeBeforeStart { range, beats } => write!(
+                f,
+                "range {} starts before the clip {} beats long does",
+                range, beats
+            ),
+            ReduceErrorKind::ZeroLengthLoop(what) => {
+                write!(f, "{} is played in a loop but is empty", what)
+            }
+            ReduceErrorKind::ZeroLengthFit => write!(f, "a clip cannot be fitted to 0 beats"),
+            ReduceErrorKind::Overflow => write!(f, "the clip is too long to play"),
+            ReduceErrorKind::LongBeat => {
+                write!(f, "the beat is more than {} steps long", MAX_STEPS)
+            }
+        }
+    }
+}
+
+/// A problem with a source, located at the expression that causes it where
+/// there is one.
+#[derive(Debug)]
+pub struct ReduceError {
+    pub kind: ReduceErrorKind,
+    pub span: Option<Span>,
+}
+
+impl ReduceError {
+    /// A warning leaves a source playable, a missing file plays as nothing.
+    pub fn is_warning(&self) -> bool {
+        matches!(
+            self.kind,
+            ReduceErrorKind::MissingFile(_) | ReduceErrorKind::Unused(_)
+        )
+    }
+}
+
+impl From<ReduceErrorKind> for ReduceError {
+    fn from(kind: ReduceErrorKind) -> Self {
+        ReduceError { kind, span: None }
+    }
+}
+
+impl fmt::Display for ReduceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+/// The declarations of the main file that every clip is reduced with.
+struct Settings {
+    path: String,
+    extension: String,
+    meter: MeterDeclaration,
+}
+
+/// A source with its parameterized clips expanded. Its choices are made anew
+/// for every take, so a take is one pass through the main expression or a
+/// scene.
+pub struct Program {
+    settings: Settings,
+    beats: HashMap<String, BeatExpression>,
+    clips: HashMap<String, ClipExpression>,
+    /// The clips without parameters, in the order they are declared in.
+    clip_names: Vec<String>,
+    /// The clips without a choice in them, which play the same in every take,
+    /// reduced once for all of them.
+    fixed: HashMap<String, Arc<ReducedClip>>,
+    main_expression: Option<ClipExpression>,
+    /// In the order they are declared in.
+    scenes: Vec<(String, ClipExpression)>,
+    warnings: Vec<ReduceError>,
+}
+
+/// Reduces `input` once to find every problem with it. Warnings are kept
+/// with the program, errors are returned along with them.
+pub fn reduce(input: Main, available_files: &HashSet<String>) -> Result<Program, Vec<ReduceError>> {
+    let mut problems = check_collisions(&input.declarations);
+    problems.extend(unused_declarations(
+        &input.declarations,
+        input.main_expression.as_ref(),
+    ));
+    let main_span = input.main_span;
+    let settings = Settings {
+        path: input.directory_declaration.directory.clone(),
+        extension: input.extension_declaration.extension.clone(),
+        meter: input.meter_declaration.unwrap_or_default(),
+    };
     let beats: HashMap<String, BeatExpression> = input
         .declarations
         .iter()
@@ -24,583 +212,1377 @@ pub fn reduce(input: Main, available_files: &HashSet<String>) -> ReducedClip {
             crate::parser::Declaration::BeatDeclaration(bd) => Some(bd),
             _ => None,
         })
-        .map(|declaration| (declaration.name.clone(), declaration.expression.clone()))
+        .filter_map(|declaration| {
+            match expand_beat_expression(&declaration.expression, &HashMap::new()) {
+                Ok(beat) => Some((declaration.name.clone(), beat)),
+                Err(e) => {
+                    problems.push(e);
+                    None
+                }
+            }
+        })
         .collect();
 
-    let clips: HashMap<String, ClipExpression> = input
+    let clip_declarations: HashMap<String, &ClipDeclaration> = input
         .declarations
         .iter()
         .filter_map(|declaration| match declaration {
             crate::parser::Declaration::ClipDeclaration(cd) => Some(cd),
             _ => None,
         })
-        .map(|declaration| (declaration.name.clone(), declaration.expression.clone()))
+        .map(|declaration| (declaration.name.clone(), declaration))
+        .collect();
+    let expand = |clip: &ClipExpression, problems: &mut Vec<ReduceError>| {
+        expand_clip_expression(clip, &clip_declarations, &HashMap::new(), &mut Vec::new())
+            .map_err(|e| problems.push(e))
+            .ok()
+    };
+    // clips with parameters only exist where they are called
+    let clip_names: Vec<String> = input
+        .declarations
+        .iter()
+        .filter_map(|declaration| match declaration {
+            crate::parser::Declaration::ClipDeclaration(cd) if cd.parameters.is_empty() => {
+                Some(cd.name.clone())
+            }
+            _ => None,
+        })
         .collect();
-    let reduced_beats = beats
+    let clips: HashMap<String, ClipExpression> = clip_names
         .iter()
-        .fold(HashMap::new(), |reduced_beats, (name, beat)| {
-            let (result, mut reduced_beats) = reduce_beat_expression(beat, &beats, &reduced_beats);
-            reduced_beats.insert(name.clone(), result);
-            reduced_beats
-        });
-    let reduced_clips = clips
+        .filter_map(|name| {
+            let clip = expand(&clip_declarations[name].expression, &mut problems)?;
+            Some((name.clone(), clip))
+        })
+        .collect();
+    let main_expression = input
+        .main_expression
+        .as_ref()
+        .and_then(|main_expression| expand(main_expression, &mut problems));
+    let scenes: Vec<(String, ClipExpression, Span)> = input
+        .declarations
         .iter()
-        .fold(HashMap::new(), |reduced_clips, (name, clip)| {
-            let (result, mut reduced_clips) = reduce_clip_expression(
-                &input.directory_declaration.directory,
-                &input.extension_declaration.extension,
-                clip,
-                &clips,
-                &reduced_clips,
-                &reduced_beats,
-            );
-            reduced_clips.insert(name.clone(), result);
-            reduced_clips
-        });
-    let (result, _) = reduce_clip_expression(
-        &input.directory_declaration.directory,
-        &input.extension_declaration.extension,
-        &input.main_expression,
-        &clips,
-        &reduced_clips,
-        &reduced_beats,
-    );
-    for (_, cmd) in &result.commands {
-        let files = match cmd {
-            ClipCommand::PlayClip(file, _) => vec![file.clone()],
-            ClipCommand::PlayClipFrom(file, _, _) => vec![file.clone()],
-            ClipCommand::PlayMulti(file, num, extension) => (0..*num)
-                .into_iter()
-                .map(|n| format!("{}{}{}", file, n, extension))
-                .collect(),
-            ClipCommand::PlayMultiFrom(file, _, num, extension) => (0..*num)
-                .into_iter()
-                .map(|n| format!("{}{}{}", file, n, extension))
-                .collect(),
-            ClipCommand::Stop(_) => Vec::new(),
-            ClipCommand::MultiNext(_) => Vec::new(),
+        .filter_map(|declaration| match declaration {
+            crate::parser::Declaration::SceneDeclaration(sd) => Some(sd),
+            _ => None,
+        })
+        .filter_map(|declaration| {
+            let scene = expand(&declaration.expression, &mut problems)?;
+            Some((declaration.name.clone(), scene, declaration.span))
+        })
+        .collect();
+    if has_errors(&problems) {
+        return Err(problems);
+    }
+    let mut program = Program {
+        settings,
+        beats,
+        clips,
+        clip_names,
+        fixed: HashMap::new(),
+        main_expression,
+        scenes: scenes
+            .iter()
+            .map(|(name, scene, _)| (name.clone(), scene.clone()))
+            .collect(),
+        warnings: Vec::new(),
+    };
+    problems.extend(check_references(&program));
+    if has_errors(&problems) {
+        return Err(problems);
+    }
+    let mut files = Vec::new();
+    for clip in program.clips.values() {
+        collect_files(&program.settings, clip, &mut files);
+    }
+    for clip in program.main_expression.iter() {
+        collect_files(&program.settings, clip, &mut files);
+    }
+    for (_, scene) in &program.scenes {
+        collect_files(&program.settings, scene, &mut files);
+    }
+    for (file, span) in files {
+        if !available_files.contains(&file) {
+            problems.push(ReduceErrorKind::MissingFile(file).at(span));
+        }
+    }
+    match reduce_fixed_clips(&program) {
+        Ok(fixed) => program.fixed = fixed,
+        Err(errors) => problems.extend(errors),
+    }
+    if has_errors(&problems) {
+        return Err(problems);
+    }
+    let clip_spans: HashMap<&str, Span> = clip_declarations
+        .iter()
+        .map(|(name, declaration)| (name.as_str(), declaration.span))
+        .collect();
+    let loops: Vec<(String, &ClipExpression, Span)> = program
+        .main_expression
+        .iter()
+        .map(|main_expression| ("the main expression".into(), main_expression, main_span))
+        .chain(
+            scenes
+                .iter()
+                .map(|(name, scene, span)| (format!("scene {}", name), scene, *span)),
+        )
+        .collect();
+    problems.extend(check_lengths(&program, &clip_spans, &loops));
+    if has_errors(&problems) {
+        return Err(problems);
+    }
+    if let Err(e) = hoist_fixed_parts(&mut program) {
+        problems.push(e);
+        return Err(problems);
+    }
+    program.warnings = problems;
+    Ok(program)
+}
+
+fn has_errors(problems: &[ReduceError]) -> bool {
+    problems.iter().any(|problem| !problem.is_warning())
+}
+
+impl Program {
+    /// Takes the main expression, or the first scene of a source without one.
+    pub fn take(&self, rng: &mut Rng) -> Result<ReducedClip, ReduceError> {
+        let clip = match (&self.main_expression, self.scenes.first()) {
+            (Some(main_expression), _) => main_expression,
+            (None, Some((_, scene))) => scene,
+            (None, None) => unreachable!("a source without scenes has a main expression"),
         };
-        for file in files {
-            if !available_files.contains(&file) {
-                println!("file {} does not exist", file);
+        self.take_clip(clip, rng)
+    }
+
+    pub fn take_scene(
+        &self,
+        name: &str,
+        rng: &mut Rng,
+    ) -> Option<Result<ReducedClip, ReduceError>> {
+        let (_, scene) = self.scenes.iter().find(|(scene, _)| scene == name)?;
+        Some(self.take_clip(scene, rng))
+    }
+
+    /// Takes a clip without parameters by the name it is declared with.
+    pub fn take_named_clip(
+        &self,
+        name: &str,
+        rng: &mut Rng,
+    ) -> Option<Result<ReducedClip, ReduceError>> {
+        let clip = self.clips.get(name)?;
+        Some(self.take_clip(clip, rng))
+    }
+
+    /// The problems that did not keep the source from being reduced.
+    pub fn warnings(&self) -> &[ReduceError] {
+        &self.warnings
+    }
+
+    pub fn has_main_expression(&self) -> bool {
+        self.main_expression.is_some()
+    }
+
+    pub fn scene_names(&self) -> Vec<&str> {
+        self.scenes.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// Reduces what `clip` refers to as it gets to it, so a take only reduces
+    /// the clips and beats it plays, and the choices are rolled in the order
+    /// they are played in.
+    fn take_clip(&self, clip: &ClipExpression, rng: &mut Rng) -> Result<ReducedClip, ReduceError> {
+        let mut memo = Memo::new(&self.fixed, &self.beats);
+        let result = reduce_clip_expression(&self.settings, clip, &self.clips, &mut memo, rng)?;
+        Ok(Arc::unwrap_or_clone(result))
+    }
+}
+
+/// The clips and beats of a take that are reduced so far, by name. Every
+/// reduction adds to the one table, and a reduced clip is shared by whatever
+/// refers to it instead of being copied until something changes it.
+struct Memo<'a> {
+    /// Reduced before the take, and the same in all of them.
+    fixed: &'a HashMap<String, Arc<ReducedClip>>,
+    beat_declarations: &'a HashMap<String, BeatExpression>,
+    clips: HashMap<String, Arc<ReducedClip>>,
+    beats: HashMap<String, ReducedBeat>,
+}
+
+impl<'a> Memo<'a> {
+    fn new(
+        fixed: &'a HashMap<String, Arc<ReducedClip>>,
+        beat_declarations: &'a HashMap<String, BeatExpression>,
+    ) -> Self {
+        Memo {
+            fixed,
+            beat_declarations,
+            clips: HashMap::new(),
+            beats: HashMap::new(),
+        }
+    }
+}
+
+/// Reduces the clips of `program` that make no choice, directly or through
+/// the clips they refer to. Each error is reported once, however many of
+/// them refer to the clip it is in.
+fn reduce_fixed_clips(
+    program: &Program,
+) -> Result<HashMap<String, Arc<ReducedClip>>, Vec<ReduceError>> {
+    let mut known = HashMap::new();
+    let none = HashMap::new();
+    let mut memo = Memo::new(&none, &program.beats);
+    let mut errors: Vec<ReduceError> = Vec::new();
+    for name in &program.clip_names {
+        if has_choice(&program.clips[name], &program.clips, &mut known) {
+            continue;
+        }
+        // no choice is rolled
+        let mut rng = Rng::new(0);
+        if let Err(e) = reduce_reference_expression(
+            &program.settings,
+            &mut memo,
+            name,
+            &program.clips,
+            &mut rng,
+        ) {
+            if errors.iter().all(|reported| reported.span != e.span) {
+                errors.push(e);
+            }
+        }
+    }
+    if errors.is_empty() {
+        Ok(memo.clips)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Reduces the parts of the clips with choices, and of the main expression and
+/// scenes, that make none, and refers to them instead. A take then only
+/// reduces the choices it rolls and what they are combined with.
+fn hoist_fixed_parts(program: &mut Program) -> Result<(), ReduceError> {
+    let mut known = HashMap::new();
+    let mut hoisted = HashMap::new();
+    let mut clips = Vec::new();
+    for name in &program.clip_names {
+        if !program.fixed.contains_key(name) {
+            let mut clip = program.clips[name].clone();
+            hoist(program, &mut clip, &mut known, &mut hoisted)?;
+            clips.push((name.clone(), clip));
+        }
+    }
+    let mut main_expression = program.main_expression.clone();
+    for clip in main_expression.iter_mut() {
+        hoist(program, clip, &mut known, &mut hoisted)?;
+    }
+    let mut scenes = program.scenes.clone();
+    for (_, scene) in &mut scenes {
+        hoist(program, scene, &mut known, &mut hoisted)?;
+    }
+    program.clips.extend(clips);
+    program.main_expression = main_expression;
+    program.scenes = scenes;
+    program.fixed.extend(hoisted);
+    Ok(())
+}
+
+/// Replaces the largest parts of `clip` without a choice by references to
+/// them reduced, which are added to `hoisted` under names no declaration can
+/// have.
+fn hoist<'a>(
+    program: &'a Program,
+    clip: &mut ClipExpression,
+    known: &mut HashMap<&'a str, bool>,
+    hoisted: &mut HashMap<String, Arc<ReducedClip>>,
+) -> Result<(), ReduceError> {
+    match clip {
+        // as cheap to take as a reference
+        ClipExpression::Empty | ClipExpression::Reference(_) => return Ok(()),
+        _ if !has_choice(clip, &program.clips, known) => {
+            let mut memo = Memo::new(&program.fixed, &program.beats);
+            let reduced = reduce_clip_expression(
+                &program.settings,
+                clip,
+                &program.clips,
+                &mut memo,
+                &mut Rng::new(0),
+            )?;
+            let name = format!("#{}", hoisted.len());
+            hoisted.insert(name.clone(), reduced);
+            *clip = ClipExpression::Reference(ReferenceClipExpression {
+                name,
+                span: Span::default(),
+            });
+            return Ok(());
+        }
+        _ => (),
+    }
+    match clip {
+        ClipExpression::Chain(ClipChainExpression { clip_a, clip_b })
+        | ClipExpression::Layer(ClipLayerExpression { clip_a, clip_b }) => {
+            hoist(program, clip_a, known, hoisted)?;
+            hoist(program, clip_b, known, hoisted)
+        }
+        ClipExpression::Loop(ClipLoopExpression { clip, .. })
+        | ClipExpression::Truncated(TruncatedClipExpression { clip, .. })
+        | ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression { clip })
+        | ClipExpression::Reverse(ReverseClipExpression { clip })
+        | ClipExpression::Hold(HoldClipExpression { clip })
+        | ClipExpression::Speed(SpeedClipExpression { clip, .. })
+        | ClipExpression::Fit(FitClipExpression { clip, .. })
+        | ClipExpression::PingPong(PingPongClipExpression { clip, .. })
+        | ClipExpression::Restart(RestartExpression {
+            clip_expression: clip,
+            ..
+        })
+        | ClipExpression::ApplyBeat(ApplyBeatExpression {
+            clip_expression: clip,
+            ..
+        }) => hoist(program, clip, known, hoisted),
+        ClipExpression::Choice(ChoiceClipExpression { alternatives }) => {
+            for alternative in alternatives {
+                hoist(program, &mut alternative.clip, known, hoisted)?;
+            }
+            Ok(())
+        }
+        // a reference, or a part that makes no choice
+        _ => Ok(()),
+    }
+}
+
+/// Whether `clip` or a clip it refers to makes a choice, so that it can play
+/// differently from take to take. `known` holds the clips found out about.
+fn has_choice<'a>(
+    clip: &ClipExpression,
+    clips: &'a HashMap<String, ClipExpression>,
+    known: &mut HashMap<&'a str, bool>,
+) -> bool {
+    match clip {
+        ClipExpression::Empty
+        | ClipExpression::RawVideo(_)
+        | ClipExpression::MultiVideo(_)
+        | ClipExpression::Call(_) => false,
+        ClipExpression::Choice(_) => true,
+        ClipExpression::Reference(ReferenceClipExpression { name, .. }) => {
+            if let Some(choice) = known.get(name.as_str()) {
+                return *choice;
+            }
+            let (name, clip) = clips.get_key_value(name).expect("references are checked");
+            let choice = has_choice(clip, clips, known);
+            known.insert(name, choice);
+            choice
+        }
+        ClipExpression::Chain(ClipChainExpression { clip_a, clip_b })
+        | ClipExpression::Layer(ClipLayerExpression { clip_a, clip_b }) => {
+            has_choice(clip_a, clips, known) || has_choice(clip_b, clips, known)
+        }
+        ClipExpression::Loop(ClipLoopExpression { clip, .. })
+        | ClipExpression::Truncated(TruncatedClipExpression { clip, .. })
+        | ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression { clip })
+        | ClipExpression::Reverse(ReverseClipExpression { clip })
+        | ClipExpression::Hold(HoldClipExpression { clip })
+        | ClipExpression::Speed(SpeedClipExpression { clip, .. })
+        | ClipExpression::Fit(FitClipExpression { clip, .. })
+        | ClipExpression::PingPong(PingPongClipExpression { clip, .. })
+        | ClipExpression::Restart(RestartExpression {
+            clip_expression: clip,
+            ..
+        })
+        | ClipExpression::ApplyBeat(ApplyBeatExpression {
+            clip_expression: clip,
+            ..
+        }) => has_choice(clip, clips, known),
+    }
+}
+
+/// Collects the video files `clip` plays, with every alternative of a choice,
+/// and where they are named.
+fn collect_files(settings: &Settings, clip: &ClipExpression, files: &mut Vec<(String, Span)>) {
+    match clip {
+        ClipExpression::Empty | ClipExpression::Reference(_) | ClipExpression::Call(_) => (),
+        ClipExpression::RawVideo(RawVideoExpression { filename, span, .. }) => files.push((
+            format!("{}{}{}", settings.path, filename, settings.extension),
+            *span,
+        )),
+        ClipExpression::MultiVideo(MultiVideoExpression {
+            filename,
+            subclips,
+            span,
+        }) => files.extend((0..*subclips).map(|n| {
+            (
+                format!("{}{}{}{}", settings.path, filename, n, settings.extension),
+                *span,
+            )
+        })),
+        ClipExpression::Chain(ClipChainExpression { clip_a, clip_b })
+        | ClipExpression::Layer(ClipLayerExpression { clip_a, clip_b }) => {
+            collect_files(settings, clip_a, files);
+            collect_files(settings, clip_b, files);
+        }
+        ClipExpression::Loop(ClipLoopExpression { clip, .. })
+        | ClipExpression::Truncated(TruncatedClipExpression { clip, .. })
+        | ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression { clip })
+        | ClipExpression::Reverse(ReverseClipExpression { clip })
+        | ClipExpression::Hold(HoldClipExpression { clip })
+        | ClipExpression::Speed(SpeedClipExpression { clip, .. })
+        | ClipExpression::Fit(FitClipExpression { clip, .. })
+        | ClipExpression::PingPong(PingPongClipExpression { clip, .. })
+        | ClipExpression::Restart(RestartExpression {
+            clip_expression: clip,
+            ..
+        })
+        | ClipExpression::ApplyBeat(ApplyBeatExpression {
+            clip_expression: clip,
+            ..
+        }) => collect_files(settings, clip, files),
+        ClipExpression::Choice(ChoiceClipExpression { alternatives }) => {
+            for alternative in alternatives {
+                collect_files(settings, &alternative.clip, files);
             }
         }
     }
-    result
 }
 
 fn reduce_clip_expression(
-    path: &str,
-    extension: &str,
+    settings: &Settings,
     clip: &ClipExpression,
     all_clip_expressions: &HashMap<String, ClipExpression>,
-    reduced_clips: &HashMap<String, ReducedClip>,
-    reduced_beats: &HashMap<String, ReducedBeat>,
-) -> (ReducedClip, HashMap<String, ReducedClip>) {
+    memo: &mut Memo,
+    rng: &mut Rng,
+) -> Result<Arc<ReducedClip>, ReduceError> {
     match clip {
-        ClipExpression::Empty => (reduce_empty_expression(), reduced_clips.clone()),
+        ClipExpression::Empty => Ok(Arc::new(reduce_empty_expression(&settings.meter))),
         ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression { clip }) => {
-            reduce_clip_expression(
-                path,
-                extension,
-                clip,
-                all_clip_expressions,
-                reduced_clips,
-                reduced_beats,
-            )
+            reduce_clip_expression(settings, clip, all_clip_expressions, memo, rng)
         }
         ClipExpression::Restart(RestartExpression {
             clip_expression,
             beat_expression,
         }) => reduce_restart_expression(
-            path,
-            extension,
+            settings,
             clip_expression,
             all_clip_expressions,
-            reduced_clips,
-            reduced_beats,
+            memo,
             beat_expression,
+            rng,
         ),
-        ClipExpression::Layer(ClipLayerExpression { clip_a, clip_b }) => reduce_layer_expression(
-            path,
-            extension,
-            clip_a,
-            all_clip_expressions,
-            reduced_clips,
-            reduced_beats,
-            clip_b,
-        ),
-        ClipExpression::Chain(ClipChainExpression { clip_a, clip_b }) => reduce_chain_expression(
-            path,
-            extension,
-            clip_a,
-            all_clip_expressions,
-            reduced_clips,
-            reduced_beats,
-            clip_b,
-        ),
-        ClipExpression::Truncated(TruncatedClipExpression { clip, timerange }) => {
-            reduce_truncate_expression(
-                path,
-                extension,
-                clip,
-                all_clip_expressions,
-                reduced_clips,
-                reduced_beats,
-                timerange,
-            )
+        ClipExpression::Layer(ClipLayerExpression { clip_a, clip_b }) => {
+            reduce_layer_expression(settings, clip_a, all_clip_expressions, memo, clip_b, rng)
         }
-        ClipExpression::RawVideo(RawVideoExpression { filename }) => {
-            reduce_raw_video_expression(path, extension, filename, reduced_clips)
+        ClipExpression::Chain(ClipChainExpression { clip_a, clip_b }) => {
+            reduce_chain_expression(settings, clip_a, all_clip_expressions, memo, clip_b, rng)
         }
-        ClipExpression::MultiVideo(MultiVideoExpression { filename, subclips }) => {
-            reduce_multi_video_expression(path, extension, filename, subclips, reduced_clips)
+        ClipExpression::Truncated(TruncatedClipExpression {
+            clip,
+            timerange,
+            span,
+        }) => {
+            reduce_truncate_expression(settings, clip, all_clip_expressions, memo, timerange, rng)
+                // an error in the clip being sliced keeps its own location
+                .map_err(|e| ReduceError {
+                    span: e.span.or(Some(*span)),
+                    ..e
+                })
+        }
+        ClipExpression::RawVideo(RawVideoExpression {
+            filename, offset, ..
+        }) => Ok(Arc::new(reduce_raw_video_expression(
+            settings, filename, offset,
+        ))),
+        ClipExpression::MultiVideo(MultiVideoExpression {
+            filename, subclips, ..
+        }) => Ok(Arc::new(reduce_multi_video_expression(
+            settings, filename, subclips,
+        ))),
+        ClipExpression::Reference(ReferenceClipExpression { name, .. }) => {
+            reduce_reference_expression(settings, memo, name, all_clip_expressions, rng)
         }
-        ClipExpression::Reference(ReferenceClipExpression { name }) => reduce_reference_expression(
-            path,
-            extension,
-            reduced_clips,
-            name,
-            all_clip_expressions,
-            reduced_beats,
-        ),
         ClipExpression::ApplyBeat(ApplyBeatExpression {
             beat_expression,
             clip_expression,
         }) => reduce_apply_beat_expression(
-            path,
-            extension,
+            settings,
             clip_expression,
             all_clip_expressions,
-            reduced_clips,
-            reduced_beats,
+            memo,
             beat_expression,
+            rng,
         ),
-        ClipExpression::Loop(ClipLoopExpression { clip, repetitions }) => {
-            reduce_clip_loop_expression(
-                path,
-                extension,
-                *repetitions,
-                clip,
-                all_clip_expressions,
-                reduced_clips,
-                reduced_beats,
-            )
+        ClipExpression::Loop(ClipLoopExpression {
+            clip,
+            repetitions: NumberExpression::Literal(repetitions),
+            ..
+        }) => reduce_clip_loop_expression(
+            settings,
+            *repetitions,
+            clip,
+            all_clip_expressions,
+            memo,
+            rng,
+        ),
+        ClipExpression::Reverse(ReverseClipExpression { clip }) => {
+            let clip = reduce_clip_expression(settings, clip, all_clip_expressions, memo, rng)?;
+            Ok(Arc::new(reverse(&clip)?))
+        }
+        ClipExpression::Hold(HoldClipExpression { clip }) => {
+            let clip = reduce_clip_expression(settings, clip, all_clip_expressions, memo, rng)?;
+            Ok(Arc::new(hold(&clip)))
+        }
+        ClipExpression::Speed(SpeedClipExpression {
+            clip,
+            numerator,
+            denominator,
+        }) => {
+            let clip = reduce_clip_expression(settings, clip, all_clip_expressions, memo, rng)?;
+            let speed = Time::new(*numerator as i64, *denominator as i64);
+            Ok(Arc::new(change_speed(&clip, &speed)?))
+        }
+        ClipExpression::Fit(FitClipExpression {
+            clip,
+            start,
+            end,
+            beats: NumberExpression::Literal(beats),
+            ..
+        }) => {
+            let clip = reduce_clip_expression(settings, clip, all_clip_expressions, memo, rng)?;
+            let length = whole(*beats)?
+                .checked_div(Time::from(settings.meter.unit as i64))
+                .ok_or(ReduceErrorKind::Overflow)?;
+            Ok(Arc::new(fit(&clip, *start, *end, length)))
+        }
+        ClipExpression::PingPong(PingPongClipExpression { clip, start, end }) => {
+            let clip = reduce_clip_expression(settings, clip, all_clip_expressions, memo, rng)?;
+            Ok(Arc::new(pingpong(&clip, *start, *end)))
+        }
+        ClipExpression::Choice(ChoiceClipExpression { alternatives }) => {
+            reduce_choice_expression(settings, alternatives, all_clip_expressions, memo, rng)
+        }
+        ClipExpression::Loop(_) | ClipExpression::Fit(_) | ClipExpression::Call(_) => {
+            unreachable!("calls and parameters are expanded before reduction")
         }
     }
 }
 
-fn reduce_empty_expression() -> ReducedClip {
+fn reduce_choice_expression(
+    settings: &Settings,
+    alternatives: &[ChoiceAlternative],
+    all_clip_expressions: &HashMap<String, ClipExpression>,
+    memo: &mut Memo,
+    rng: &mut Rng,
+) -> Result<Arc<ReducedClip>, ReduceError> {
+    let weights: Vec<usize> = alternatives
+        .iter()
+        .map(|alternative| alternative.weight)
+        .collect();
+    let chosen = rng.choose_weighted(&weights);
+    reduce_clip_expression(
+        settings,
+        &alternatives[chosen].clip,
+        all_clip_expressions,
+        memo,
+        rng,
+    )
+}
+
+fn reduce_empty_expression(meter: &MeterDeclaration) -> ReducedClip {
     ReducedClip {
         commands: vec![(Time::zero(), ClipCommand::Stop(0))],
-        length: Time { num: 1, denom: 1 },
+        length: bar_length(meter),
     }
 }
 
 fn reduce_raw_video_expression(
-    path: &str,
-    extension: &str,
+    settings: &Settings,
     filename: &str,
-    reduced_clips: &HashMap<String, ReducedClip>,
-) -> (ReducedClip, HashMap<String, ReducedClip>) {
-    (
-        ReducedClip {
-            commands: vec![(
-                Time { num: 0, denom: 1 },
-                ClipCommand::PlayClip(format!("{}{}{}", path, filename, extension), 0),
-            )],
-            length: Time { num: 1, denom: 1 },
-        },
-        reduced_clips.clone(),
+    offset: &Option<SourceOffset>,
+) -> ReducedClip {
+    single(
+        ClipCommand::PlayClip(
+            Source {
+                path: format!("{}{}{}", settings.path, filename, settings.extension),
+                offset: offset.clone(),
+            },
+            0,
+        ),
+        bar_length(&settings.meter),
     )
 }
 
 fn reduce_multi_video_expression(
-    path: &str,
-    extension: &str,
+    settings: &Settings,
     filename: &str,
     subclips: &usize,
-    reduced_clips: &HashMap<String, ReducedClip>,
-) -> (ReducedClip, HashMap<String, ReducedClip>) {
-    (
-        ReducedClip {
-            commands: vec![(
-                Time { num: 0, denom: 1 },
-                ClipCommand::PlayMulti(
-                    format!("{}{}", path, filename),
-                    *subclips,
-                    extension.to_string(),
-                ),
-            )],
-            length: Time { num: 1, denom: 1 },
-        },
-        reduced_clips.clone(),
+) -> ReducedClip {
+    single(
+        ClipCommand::PlayMulti(
+            Multi {
+                path: format!("{}{}", settings.path, filename),
+                subclips: *subclips,
+                extension: settings.extension.to_string(),
+            },
+            0,
+        ),
+        bar_length(&settings.meter),
     )
 }
 
+/// A count of beats or repetitions as a time, if it fits into one.
+fn whole(count: usize) -> Result<Time, ReduceError> {
+    i64::try_from(count)
+        .map(Time::from)
+        .map_err(|_| ReduceErrorKind::Overflow.into())
+}
+
+/// A clip that plays `command` on layer 0 for `length`, and then ends.
+fn single(command: ClipCommand, length: Time) -> ReducedClip {
+    ReducedClip {
+        commands: vec![(Time::zero(), command), (length, ClipCommand::End(0))],
+        length,
+    }
+}
+
 fn reduce_reference_expression(
-    path: &str,
-    extension: &str,
-    reduced_clips: &HashMap<String, ReducedClip>,
+    settings: &Settings,
+    memo: &mut Memo,
     name: &String,
     all_clip_expressions: &HashMap<String, ClipExpression>,
-    reduced_beats: &HashMap<String, ReducedBeat>,
-) -> (ReducedClip, HashMap<String, ReducedClip>) {
-    if let Some(clip) = reduced_clips.get(name) {
-        (clip.clone(), reduced_clips.clone())
+    rng: &mut Rng,
+) -> Result<Arc<ReducedClip>, ReduceError> {
+    if let Some(clip) = memo.clips.get(name).or_else(|| memo.fixed.get(name)) {
+        Ok(Arc::clone(clip))
     } else {
-        let (clip, mut reduced_clips) = reduce_clip_expression(
-            path,
-            extension,
+        let clip = reduce_clip_expression(
+            settings,
             &all_clip_expressions[name],
             all_clip_expressions,
-            reduced_clips,
-            reduced_beats,
-        );
-        reduced_clips.insert(name.clone(), clip.clone());
-        (clip, reduced_clips)
+            memo,
+            rng,
+        )?;
+        memo.clips.insert(name.clone(), Arc::clone(&clip));
+        Ok(clip)
     }
 }
 
 fn reduce_restart_expression(
-    path: &str,
-    extension: &str,
+    settings: &Settings,
     clip_expression: &Box<ClipExpression>,
     all_clip_expressions: &HashMap<String, ClipExpression>,
-    reduced_clips: &HashMap<String, ReducedClip>,
-    reduced_beats: &HashMap<String, ReducedBeat>,
+    memo: &mut Memo,
     beat_expression: &BeatExpression,
-) -> (ReducedClip, HashMap<String, ReducedClip>) {
-    let (mut clip, reduced_clips) = reduce_clip_expression(
-        path,
-        extension,
-        clip_expression,
-        all_clip_expressions,
-        reduced_clips,
-        reduced_beats,
-    );
-    let (mut beat, _) = reduce_beat_expression(beat_expression, &HashMap::new(), reduced_beats);
+    rng: &mut Rng,
+) -> Result<Arc<ReducedClip>, ReduceError> {
+    let clip = reduce_clip_expression(settings, clip_expression, all_clip_expressions, memo, rng)?;
+    let mut beat =
+        reduce_beat_expression(beat_expression, memo.beat_declarations, &mut memo.beats)?;
     beat.beats.push(beat.length);
     let left_shift = beat.beats[..beat.beats.len() - 1].iter();
     let right_shift = beat.beats[1..].iter();
     let lengths: Vec<Time> = left_shift
         .zip(right_shift)
-        .map(|(b1, b2)| b2 - b1)
-        .collect();
-    let beat_commands = lengths
-        .into_iter()
-        .map(|beat_time| {
-            let mut clip = clip.clone();
-            slice_clip(&mut clip, &None, &Some(beat_time));
-            clip
-        })
-        .reduce(chain);
-    clip.commands.sort_by_key(|b| time_to_frac(&b.0));
-    (beat_commands.unwrap_or(clip), reduced_clips)
+        .map(|(b1, b2)| b2.checked_sub(*b1).ok_or(ReduceErrorKind::Overflow))
+        .collect::<Result<_, _>>()?;
+    let mut beat_commands: Option<ReducedClip> = None;
+    for beat_time in lengths {
+        let mut piece = ReducedClip::clone(&clip);
+        slice_clip(&mut piece, &Time::zero(), &beat_time)?;
+        beat_commands = Some(match beat_commands {
+            Some(before) => chain(&before, &piece)?,
+            None => piece,
+        });
+    }
+    Ok(match beat_commands {
+        Some(clip) => Arc::new(clip),
+        None => {
+            let mut clip = Arc::unwrap_or_clone(clip);
+            clip.commands.sort_by_key(|b| b.0);
+            Arc::new(clip)
+        }
+    })
 }
 
 fn reduce_apply_beat_expression(
-    path: &str,
-    extension: &str,
+    settings: &Settings,
     clip_expression: &Box<ClipExpression>,
     all_clip_expressions: &HashMap<String, ClipExpression>,
-    reduced_clips: &HashMap<String, ReducedClip>,
-    reduced_beats: &HashMap<String, ReducedBeat>,
+    memo: &mut Memo,
     beat_expression: &BeatExpression,
-) -> (ReducedClip, HashMap<String, ReducedClip>) {
-    let (mut clip, reduced_clips) = reduce_clip_expression(
-        path,
-        extension,
-        clip_expression,
-        all_clip_expressions,
-        reduced_clips,
-        reduced_beats,
-    );
-    let (beat, _) = reduce_beat_expression(beat_expression, &HashMap::new(), reduced_beats);
+    rng: &mut Rng,
+) -> Result<Arc<ReducedClip>, ReduceError> {
+    let clip = reduce_clip_expression(settings, clip_expression, all_clip_expressions, memo, rng)?;
+    let mut clip = Arc::unwrap_or_clone(clip);
+    let beat = reduce_beat_expression(beat_expression, memo.beat_declarations, &mut memo.beats)?;
+    // every multi video in the clip switches on the beat, on whatever layer
+    let mut layers: Vec<usize> = clip
+        .commands
+        .iter()
+        .filter(|(_, command)| {
+            matches!(
+                command,
+                ClipCommand::PlayMulti(..) | ClipCommand::PlayMultiFrom(..)
+            )
+        })
+        .map(|(_, command)| command.layer())
+        .collect();
+    layers.sort_unstable();
+    layers.dedup();
     let mut beat_commands = beat
         .beats
         .into_iter()
-        .map(|b| (b, ClipCommand::MultiNext(0)))
+        .flat_map(|b| {
+            layers
+                .iter()
+                .map(move |layer| (b, ClipCommand::MultiNext(*layer)))
+        })
         .collect();
     clip.commands.append(&mut beat_commands);
-    clip.commands.sort_by_key(|b| time_to_frac(&b.0));
-    (clip, reduced_clips)
+    clip.commands.sort_by_key(|b| b.0);
+    Ok(Arc::new(clip))
 }
 
 fn reduce_truncate_expression(
-    path: &str,
-    extension: &str,
+    settings: &Settings,
     clip: &Box<ClipExpression>,
     all_clip_expressions: &HashMap<String, ClipExpression>,
-    reduced_clips: &HashMap<String, ReducedClip>,
-    reduced_beats: &HashMap<String, ReducedBeat>,
-    timerange: &crate::parser::TimeRangeExpression,
-) -> (ReducedClip, HashMap<String, ReducedClip>) {
-    let (mut clip, reduced_clips) = reduce_clip_expression(
-        path,
-        extension,
-        clip,
-        all_clip_expressions,
-        reduced_clips,
-        reduced_beats,
+    memo: &mut Memo,
+    timerange: &TimeRangeExpression,
+    rng: &mut Rng,
+) -> Result<Arc<ReducedClip>, ReduceError> {
+    let clip = reduce_clip_expression(settings, clip, all_clip_expressions, memo, rng)?;
+    let position = |time| {
+        time_expression_to_time(time, &settings.meter, &clip.length)
+            .ok_or(ReduceErrorKind::Overflow)
+    };
+    let start = timerange.from.as_ref().map_or(Ok(Time::zero()), position)?;
+    let end = timerange.to.as_ref().map_or(Ok(clip.length), position)?;
+    let beats = || {
+        clip.length
+            .checked_mul(Time::from(settings.meter.unit as i64))
+            .ok_or(ReduceErrorKind::Overflow)
+    };
+    if start < Time::zero() {
+        return Err(ReduceErrorKind::RangeBeforeStart {
+            range: timerange.to_string(),
+            beats: beats()?,
+        }
+        .into());
+    }
+    if end <= start {
+        return Err(ReduceErrorKind::EmptyRange {
+            range: timerange.to_string(),
+            beats: beats()?,
+        }
+        .into());
+    }
+    let mut clip = Arc::unwrap_or_clone(clip);
+    slice_clip(&mut clip, &start, &end)?;
+    Ok(Arc::new(clip))
+}
+
+/// Cuts `clip` down to what plays from `from` to `to`. What started before
+/// `from` starts at the beginning, as far into its file as it had got.
+fn slice_clip(clip: &mut ReducedClip, from: &Time, to: &Time) -> Result<(), ReduceError> {
+    let ending = ending_layers(clip, to);
+    // of what started on a layer until `from`, only the last is still playing
+    let mut playing: HashMap<usize, Time> = HashMap::new();
+    for (time, command) in &clip.commands {
+        if !matches!(command, ClipCommand::MultiNext(_)) && time <= from {
+            let last = playing.entry(command.layer()).or_insert(*time);
+            *last = (*last).max(*time);
+        }
+    }
+    clip.commands
+        .retain(|(time, command)| match playing.get(&command.layer()) {
+            Some(last) if !matches!(command, ClipCommand::MultiNext(_)) => time >= last,
+            _ => true,
+        });
+    let later =
+        |time: &Time, elapsed: Time| time.checked_add(elapsed).ok_or(ReduceErrorKind::Overflow);
+    for command in &mut clip.commands {
+        command.0 = command
+            .0
+            .checked_sub(*from)
+            .ok_or(ReduceErrorKind::Overflow)?;
+        if command.0 < Time::zero() {
+            let elapsed = -command.0;
+            match &command.1 {
+                ClipCommand::PlayClip(path, layer) => {
+                    command.1 = ClipCommand::PlayClipFrom(path.clone(), *layer, elapsed)
+                }
+                ClipCommand::PlayClipFrom(path, layer, time) => {
+                    command.1 =
+                        ClipCommand::PlayClipFrom(path.clone(), *layer, later(time, elapsed)?)
+                }
+                ClipCommand::PlayClipAt(path, layer, time, speed) => {
+                    command.1 = ClipCommand::PlayClipAt(
+                        path.clone(),
+                        *layer,
+                        later(
+                            time,
+                            speed
+                                .checked_mul(elapsed)
+                                .ok_or(ReduceErrorKind::Overflow)?,
+                        )?,
+                        *speed,
+                    )
+                }
+                ClipCommand::PlayClipFit(path, layer, fit) => {
+                    command.1 = ClipCommand::PlayClipFit(
+                        path.clone(),
+                        *layer,
+                        Fit {
+                            from: later(&fit.from, elapsed)?,
+                            ..fit.clone()
+                        },
+                    )
+                }
+                ClipCommand::PlayClipPingPong(path, layer, pingpong) => {
+                    command.1 = ClipCommand::PlayClipPingPong(
+                        path.clone(),
+                        *layer,
+                        PingPong {
+                            from: later(&pingpong.from, elapsed)?,
+                            ..pingpong.clone()
+                        },
+                    )
+                }
+                ClipCommand::PlayMulti(multi, layer) => {
+                    command.1 = ClipCommand::PlayMultiFrom(multi.clone(), *layer, elapsed)
+                }
+                ClipCommand::PlayMultiFrom(multi, layer, time) => {
+                    command.1 =
+                        ClipCommand::PlayMultiFrom(multi.clone(), *layer, later(time, elapsed)?)
+                }
+                ClipCommand::MultiNext(_) => (),
+                ClipCommand::Stop(_) | ClipCommand::End(_) => (),
+            };
+            command.0 = Time::zero();
+        }
+    }
+    let length = to.checked_sub(*from).ok_or(ReduceErrorKind::Overflow)?;
+    clip.commands.retain(|c| c.0 < length);
+    clip.length = length;
+    end_layers(clip, &ending);
+    Ok(())
+}
+
+/// The layers `clip` ends at or after `from`. Those are the ones still
+/// playing then, unless `clip` is held.
+fn ending_layers(clip: &ReducedClip, from: &Time) -> Vec<usize> {
+    let mut layers: Vec<usize> = clip
+        .commands
+        .iter()
+        .filter(|(time, command)| time >= from && matches!(command, ClipCommand::End(_)))
+        .map(|(_, command)| command.layer())
+        .collect();
+    layers.sort_unstable();
+    layers.dedup();
+    layers
+}
+
+fn end_layers(clip: &mut ReducedClip, layers: &[usize]) {
+    let length = clip.length;
+    clip.commands.extend(
+        layers
+            .iter()
+            .map(|layer| (length, ClipCommand::End(*layer))),
     );
-    let from = timerange.from.as_ref().map(time_expression_to_time);
-    let to = timerange.to.as_ref().map(time_expression_to_time);
-    println!("from {:?}, to {:?}", from, to);
-    slice_clip(&mut clip, &from, &to);
-    (clip, reduced_clips)
 }
 
-fn slice_clip(clip: &mut ReducedClip, from: &Option<Time>, to: &Option<Time>) {
-    let zero = Time::zero();
-    let from = from.as_ref().unwrap_or(&zero);
-    for command in &mut clip.commands {
-        command.0 = &command.0 - &from;
-        if command.0.num < 0 {
-            match &command.1 {
-                ClipCommand::PlayClip(path, layer) => {
-                    command.1 = ClipCommand::PlayClipFrom(
-                        path.clone(),
-                        *layer,
-                        Time {
-                            num: -command.0.num,
-                            denom: command.0.denom,
-                        },
-                    )
-                }
-                ClipCommand::PlayClipFrom(path, layer, time) => {
-                    command.1 = ClipCommand::PlayClipFrom(path.clone(), *layer, time + &from)
-                }
-                ClipCommand::PlayMulti(path, subclips, extension) => {
-                    command.1 = ClipCommand::PlayMultiFrom(
-                        path.clone(),
-                        Time {
-                            num: -command.0.num,
-                            denom: command.0.denom,
-                        },
-                        *subclips,
-                        extension.clone(),
-                    )
-                }
-                ClipCommand::PlayMultiFrom(path, time, subclips, extension) => {
-                    command.1 = ClipCommand::PlayMultiFrom(
-                        path.clone(),
-                        time + &from,
-                        *subclips,
-                        extension.clone(),
-                    )
-                }
-                ClipCommand::MultiNext(_) => (),
-                ClipCommand::Stop(_) => (),
-            };
-            command.0 = Time { num: 0, denom: 1 };
+/// Drops the ends of what `clip` plays, so that it keeps showing until
+/// something else plays on its layers.
+fn hold(clip: &ReducedClip) -> ReducedClip {
+    ReducedClip {
+        commands: clip
+            .commands
+            .iter()
+            .filter(|(_, command)| !matches!(command, ClipCommand::End(_)))
+            .cloned()
+            .collect(),
+        length: clip.length,
+    }
+}
+
+fn reduce_clip_loop_expression(
+    settings: &Settings,
+    repetitions: usize,
+    clip: &Box<ClipExpression>,
+    all_clip_expressions: &HashMap<String, ClipExpression>,
+    memo: &mut Memo,
+    rng: &mut Rng,
+) -> Result<Arc<ReducedClip>, ReduceError> {
+    let clip = reduce_clip_expression(settings, clip, all_clip_expressions, memo, rng)?;
+    let length = clip
+        .length
+        .checked_mul(whole(repetitions)?)
+        .ok_or(ReduceErrorKind::Overflow)?;
+    let commands = (0..repetitions)
+        .flat_map(|i| {
+            let start = clip.length.checked_mul(Time::from(i as i64));
+            clip.commands
+                .iter()
+                .map(move |(time, command)| Some((time.checked_add(start?)?, command.clone())))
+        })
+        .collect::<Option<_>>()
+        .ok_or(ReduceErrorKind::Overflow)?;
+    Ok(Arc::new(ReducedClip { commands, length }))
+}
+
+fn reduce_layer_expression(
+    settings: &Settings,
+    clip_a: &Box<ClipExpression>,
+    all_clip_expressions: &HashMap<String, ClipExpression>,
+    memo: &mut Memo,
+    clip_b: &Box<ClipExpression>,
+    rng: &mut Rng,
+) -> Result<Arc<ReducedClip>, ReduceError> {
+    let clip_a = reduce_clip_expression(settings, clip_a, all_clip_expressions, memo, rng)?;
+    let max_layer = clip_a
+        .commands
+        .iter()
+        .map(|(_, c)| c.layer())
+        .max()
+        .unwrap_or(0);
+    let clip_b = reduce_clip_expression(settings, clip_b, all_clip_expressions, memo, rng)?;
+    Ok(Arc::new(layer(&clip_b, &clip_a, max_layer)))
+}
+fn layer(clip_a: &ReducedClip, clip_b: &ReducedClip, max_layer: usize) -> ReducedClip {
+    let lifted = clip_b
+        .commands
+        .iter()
+        .map(|(time, command)| (*time, command.add_layer(max_layer + 1)));
+    let mut commands: Vec<(Time, ClipCommand)> =
+        clip_a.commands.iter().cloned().chain(lifted).collect();
+    // commands play in order, so the shorter clip's end is not put off
+    commands.sort_by_key(|(time, _)| *time);
+    ReducedClip {
+        commands,
+        length: clip_a.length.max(clip_b.length),
+    }
+}
+
+fn reduce_chain_expression(
+    settings: &Settings,
+    clip_a: &Box<ClipExpression>,
+    all_clip_expressions: &HashMap<String, ClipExpression>,
+    memo: &mut Memo,
+    clip_b: &Box<ClipExpression>,
+    rng: &mut Rng,
+) -> Result<Arc<ReducedClip>, ReduceError> {
+    let clip_a = reduce_clip_expression(settings, clip_a, all_clip_expressions, memo, rng)?;
+    let clip_b = reduce_clip_expression(settings, clip_b, all_clip_expressions, memo, rng)?;
+    Ok(Arc::new(chain(&clip_b, &clip_a)?))
+}
+
+fn chain(clip_a: &ReducedClip, clip_b: &ReducedClip) -> Result<ReducedClip, ReduceError> {
+    let shifted = clip_b
+        .commands
+        .iter()
+        .map(|(time, command)| Some((time.checked_add(clip_a.length)?, command.clone())));
+    Ok(ReducedClip {
+        commands: clip_a
+            .commands
+            .iter()
+            .cloned()
+            .map(Some)
+            .chain(shifted)
+            .collect::<Option<_>>()
+            .ok_or(ReduceErrorKind::Overflow)?,
+        length: clip_a
+            .length
+            .checked_add(clip_b.length)
+            .ok_or(ReduceErrorKind::Overflow)?,
+    })
+}
+
+/// The commands of `clip` that start something on a layer, in order, each
+/// with how long it lasts until the next one on its layer or the end of `clip`.
+fn stretches(clip: &ReducedClip) -> Vec<(&Time, &ClipCommand, Time)> {
+    let mut starts: Vec<&(Time, ClipCommand)> = clip
+        .commands
+        .iter()
+        .filter(|(_, command)| !matches!(command, ClipCommand::MultiNext(_)))
+        .collect();
+    starts.sort_by_key(|(time, _)| *time);
+    starts
+        .iter()
+        .enumerate()
+        .map(|(index, (time, command))| {
+            let end = starts[index + 1..]
+                .iter()
+                .find(|(_, next)| next.layer() == command.layer())
+                .map_or(&clip.length, |(next_time, _)| next_time);
+            (time, command, end - time)
+        })
+        .collect()
+}
+
+/// Mirrors `clip` in time. Every stretch a file plays on a layer becomes a
+/// stretch that plays it backwards, from where it ended to where it started.
+/// Multi videos keep playing forwards, only the times they switch at mirror.
+fn reverse(clip: &ReducedClip) -> Result<ReducedClip, ReduceError> {
+    let length = &clip.length;
+    let mut commands: Vec<(Time, ClipCommand)> = Vec::new();
+    let stretches = stretches(clip);
+    for (index, (time, command, duration)) in stretches.iter().enumerate() {
+        let layer = command.layer();
+        if *duration == Time::zero() {
+            continue;
+        }
+        let end = *time + duration;
+        let reversed = match command.playback() {
+            Some((file, layer, from, speed)) => {
+                let to = speed
+                    .checked_mul(*duration)
+                    .and_then(|played| from.checked_add(played))
+                    .ok_or(ReduceErrorKind::Overflow)?;
+                ClipCommand::play_at(file.clone(), layer, to, -speed)
+            }
+            None => (*command).clone(),
+        };
+        commands.push((length - end, reversed));
+        // nothing played on this layer before its first command
+        if **time > Time::zero()
+            && !stretches[..index]
+                .iter()
+                .any(|(_, c, _)| c.layer() == layer)
+        {
+            commands.push((length - *time, ClipCommand::Stop(layer)));
+        }
+    }
+    commands.extend(
+        clip.commands
+            .iter()
+            .filter_map(|(time, command)| match command {
+                ClipCommand::MultiNext(_) if *time > Time::zero() => {
+                    Some((length - time, command.clone()))
+                }
+                _ => None,
+            }),
+    );
+    commands.sort_by_key(|(time, _)| *time);
+    let mut reversed = ReducedClip {
+        commands,
+        length: clip.length,
+    };
+    // what plays from the start of `clip` plays until the end reversed
+    end_layers(&mut reversed, &ending_layers(clip, &Time::zero()));
+    Ok(reversed)
+}
+
+/// Plays the files in `clip` `speed` times as fast, without changing when
+/// they start. With a negative speed, every stretch plays backwards from where
+/// it would have ended at the positive speed. Multi videos keep their speed.
+fn change_speed(clip: &ReducedClip, speed: &Time) -> Result<ReducedClip, ReduceError> {
+    let mut commands: Vec<(Time, ClipCommand)> = stretches(clip)
+        .into_iter()
+        .map(|(time, command, duration)| {
+            let command = match command.playback() {
+                Some((file, layer, from, own_speed)) => {
+                    let scaled = own_speed.checked_mul(*speed);
+                    let from = if *speed < Time::zero() {
+                        scaled
+                            .and_then(|scaled| scaled.checked_mul(duration))
+                            .and_then(|played| from.checked_sub(played))
+                    } else {
+                        Some(from)
+                    };
+                    match (from, scaled) {
+                        (Some(from), Some(scaled)) => {
+                            ClipCommand::play_at(file.clone(), layer, from, scaled)
+                        }
+                        _ => return Err(ReduceErrorKind::Overflow),
+                    }
+                }
+                None => command.clone(),
+            };
+            Ok((*time, command))
+        })
+        .collect::<Result<_, _>>()?;
+    commands.extend(
+        clip.commands
+            .iter()
+            .filter(|(_, command)| matches!(command, ClipCommand::MultiNext(_)))
+            .cloned(),
+    );
+    commands.sort_by_key(|(time, _)| *time);
+    Ok(ReducedClip {
+        commands,
+        length: clip.length,
+    })
+}
+
+/// Stretches the file `clip` starts with to last `length`, at any tempo.
+fn fit(clip: &ReducedClip, start: Option<f64>, end: Option<f64>, length: Time) -> ReducedClip {
+    let command = match first_file(clip) {
+        Some(file) => ClipCommand::PlayClipFit(
+            file,
+            0,
+            Fit {
+                start,
+                end,
+                length,
+                from: Time::zero(),
+            },
+        ),
+        None => ClipCommand::Stop(0),
+    };
+    single(command, length)
+}
+
+/// Plays the file `clip` starts with forwards and backwards for as long as
+/// `clip` lasts.
+fn pingpong(clip: &ReducedClip, start: Option<f64>, end: Option<f64>) -> ReducedClip {
+    let command = match first_file(clip) {
+        Some(file) => ClipCommand::PlayClipPingPong(
+            file,
+            0,
+            PingPong {
+                start,
+                end,
+                from: Time::zero(),
+            },
+        ),
+        None => ClipCommand::Stop(0),
+    };
+    single(command, clip.length)
+}
+
+fn first_file(clip: &ReducedClip) -> Option<Source> {
+    stretches(clip)
+        .into_iter()
+        .find_map(|(_, command, _)| command.playback().map(|(file, ..)| file.clone()))
+}
+
+fn reduce_beat_expression(
+    beat: &BeatExpression,
+    all_beat_expressions: &HashMap<String, BeatExpression>,
+    reduced_beats: &mut HashMap<String, ReducedBeat>,
+) -> Result<ReducedBeat, ReduceError> {
+    let result = match beat {
+        BeatExpression::DotBeatExpression(e) => reduce_dot_beat_expression(e),
+        BeatExpression::NumberBeatExpression(e) => reduce_number_beat_expression(e),
+        BeatExpression::BeatChainExpression(e) => {
+            reduce_beat_chain_expression(e, all_beat_expressions, reduced_beats)?
+        }
+        BeatExpression::ReferenceBeatExpression(e) => {
+            reduce_reference_beat_expression(e, all_beat_expressions, reduced_beats)?
+        }
+        BeatExpression::EuclideanBeatExpression(e) => reduce_euclidean_beat_expression(e)?,
+        BeatExpression::UnionBeatExpression(UnionBeatExpression { beat_a, beat_b }) => {
+            let beat_a = reduce_beat_expression(beat_a, all_beat_expressions, reduced_beats)?;
+            let beat_b = reduce_beat_expression(beat_b, all_beat_expressions, reduced_beats)?;
+            union_beats(beat_a, beat_b)?
+        }
+        BeatExpression::IntersectionBeatExpression(IntersectionBeatExpression {
+            beat_a,
+            beat_b,
+        }) => {
+            let beat_a = reduce_beat_expression(beat_a, all_beat_expressions, reduced_beats)?;
+            let beat_b = reduce_beat_expression(beat_b, all_beat_expressions, reduced_beats)?;
+            intersect_beats(beat_a, beat_b)?
+        }
+        BeatExpression::InvertedBeatExpression(InvertedBeatExpression { beat }) => invert_beat(
+            reduce_beat_expression(beat, all_beat_expressions, reduced_beats)?,
+        )?,
+        BeatExpression::RotatedBeatExpression(RotatedBeatExpression { beat, steps }) => {
+            let beat = reduce_beat_expression(beat, all_beat_expressions, reduced_beats)?;
+            rotate_beat(beat, literal(steps))?
+        }
+        BeatExpression::RepeatedBeatExpression(RepeatedBeatExpression { beat, repetitions }) => {
+            let beat = reduce_beat_expression(beat, all_beat_expressions, reduced_beats)?;
+            repeat_beat(beat, literal(repetitions))?
+        }
+        BeatExpression::ParenthesesBeatExpression(ParenthesesBeatExpression { beat }) => {
+            reduce_beat_expression(beat, all_beat_expressions, reduced_beats)?
+        }
+    };
+    // each part is checked, so no beat grows past twice the limit first
+    steps(&result)?;
+    Ok(order_beat(result))
+}
+
+/// The hits of both beats, as long as the longer one.
+fn union_beats(beat_a: ReducedBeat, beat_b: ReducedBeat) -> Result<ReducedBeat, ReduceError> {
+    let length = beat_a.length.max(beat_b.length);
+    let resolution = lcm(beat_a.resolution, beat_b.resolution)?;
+    let mut beats = beat_a.beats;
+    for beat in beat_b.beats {
+        if !beats.contains(&beat) {
+            beats.push(beat);
         }
     }
-    if let Some(to) = to {
-        clip.commands
-            .retain(|c| time_to_frac(&c.0) < time_to_frac(to));
-        clip.length = to - from;
-    }
+    Ok(ReducedBeat {
+        beats,
+        length,
+        resolution,
+    })
 }
 
-fn reduce_clip_loop_expression(
-    path: &str,
-    extension: &str,
-    repetitions: usize,
-    clip: &Box<ClipExpression>,
-    all_clip_expressions: &HashMap<String, ClipExpression>,
-    reduced_clips: &HashMap<String, ReducedClip>,
-    reduced_beats: &HashMap<String, ReducedBeat>,
-) -> (ReducedClip, HashMap<String, ReducedClip>) {
-    let (clip, reduced_clips) = reduce_clip_expression(
-        path,
-        extension,
-        clip,
-        all_clip_expressions,
-        reduced_clips,
-        reduced_beats,
-    );
-    let mut clips = Vec::new();
-
-    for i in 0..repetitions {
-        let mut new_clip = clip.clone();
-        for c in &mut new_clip.commands {
-            c.0 = &c.0 + &clip.length.mul(i as isize);
-        }
-        clips.push(new_clip.commands);
-    }
-    (
-        ReducedClip {
-            commands: clips.into_iter().flatten().collect(),
-            length: clip.length.mul(repetitions as isize),
-        },
-        reduced_clips,
-    )
+/// The hits the beats share, as long as the longer one.
+fn intersect_beats(beat_a: ReducedBeat, beat_b: ReducedBeat) -> Result<ReducedBeat, ReduceError> {
+    let length = beat_a.length.max(beat_b.length);
+    let resolution = lcm(beat_a.resolution, beat_b.resolution)?;
+    let beats = beat_a
+        .beats
+        .into_iter()
+        .filter(|beat| beat_b.beats.contains(beat))
+        .collect();
+    Ok(ReducedBeat {
+        beats,
+        length,
+        resolution,
+    })
 }
 
-fn reduce_layer_expression(
-    path: &str,
-    extension: &str,
-    clip_a: &Box<ClipExpression>,
-    all_clip_expressions: &HashMap<String, ClipExpression>,
-    reduced_clips: &HashMap<String, ReducedClip>,
-    reduced_beats: &HashMap<String, ReducedBeat>,
-    clip_b: &Box<ClipExpression>,
-) -> (ReducedClip, HashMap<String, ReducedClip>) {
-    let (clip_a, reduced_clips) = reduce_clip_expression(
-        path,
-        extension,
-        clip_a,
-        all_clip_expressions,
-        reduced_clips,
-        reduced_beats,
-    );
-    let max_layer = clip_a
-        .commands
+/// A hit on every step of `beat` that has none.
+fn invert_beat(beat: ReducedBeat) -> Result<ReducedBeat, ReduceError> {
+    let resolution = beat.resolution;
+    let hits: Vec<usize> = beat
+        .beats
         .iter()
-        .map(|(_, c)| c.layer())
-        .max()
-        .unwrap_or(0);
-    let (clip_b, reduced_clips) = reduce_clip_expression(
-        path,
-        extension,
-        clip_b,
-        all_clip_expressions,
-        &reduced_clips,
-        reduced_beats,
-    );
-    let clip = layer(clip_b, clip_a, max_layer);
-    (clip, reduced_clips)
+        .map(|time| time_to_step(time, resolution))
+        .collect();
+    let beats = (0..steps(&beat)?)
+        .filter(|step| !hits.contains(step))
+        .map(|step| step_to_time(step, resolution))
+        .collect::<Result<_, _>>()?;
+    Ok(ReducedBeat {
+        beats,
+        length: beat.length,
+        resolution,
+    })
 }
-fn layer(clip_a: ReducedClip, mut clip_b: ReducedClip, max_layer: usize) -> ReducedClip {
-    for command in &mut clip_b.commands {
-        command.1 = command.1.add_layer(max_layer + 1);
-    }
 
-    ReducedClip {
-        commands: vec![clip_a.commands, clip_b.commands]
-            .into_iter()
-            .flatten()
-            .collect(),
-        length: if f64::from(&clip_a.length) > f64::from(&clip_b.length) {
-            clip_a.length.clone()
-        } else {
-            clip_b.length.clone()
-        },
+/// Moves the hits of `beat` `shift` of its steps to the left, and the ones
+/// that move past its start to its end.
+fn rotate_beat(beat: ReducedBeat, shift: usize) -> Result<ReducedBeat, ReduceError> {
+    let resolution = beat.resolution;
+    let length = steps(&beat)?;
+    if length == 0 {
+        return Ok(beat);
     }
+    let shift = shift % length;
+    let beats = beat
+        .beats
+        .iter()
+        .map(|time| {
+            let step = (time_to_step(time, resolution) + length - shift) % length;
+            step_to_time(step, resolution)
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(ReducedBeat {
+        beats,
+        length: beat.length,
+        resolution,
+    })
 }
 
-fn reduce_chain_expression(
-    path: &str,
-    extension: &str,
-    clip_a: &Box<ClipExpression>,
-    all_clip_expressions: &HashMap<String, ClipExpression>,
-    reduced_clips: &HashMap<String, ReducedClip>,
-    reduced_beats: &HashMap<String, ReducedBeat>,
-    clip_b: &Box<ClipExpression>,
-) -> (ReducedClip, HashMap<String, ReducedClip>) {
-    let (clip_a, reduced_clips) = reduce_clip_expression(
-        path,
-        extension,
-        clip_a,
-        all_clip_expressions,
-        reduced_clips,
-        reduced_beats,
-    );
-    let (clip_b, reduced_clips) = reduce_clip_expression(
-        path,
-        extension,
-        clip_b,
-        all_clip_expressions,
-        &reduced_clips,
-        reduced_beats,
-    );
-    let clip = chain(clip_b, clip_a);
-    (clip, reduced_clips)
+/// `beat` `repetitions` times over, as long as that is at most `MAX_STEPS`
+/// steps. The hits are only laid out once that is known.
+fn repeat_beat(beat: ReducedBeat, repetitions: usize) -> Result<ReducedBeat, ReduceError> {
+    let length = beat
+        .length
+        .checked_mul(whole(repetitions)?)
+        .ok_or(ReduceErrorKind::Overflow)?;
+    let repeated = ReducedBeat {
+        beats: Vec::new(),
+        length,
+        resolution: beat.resolution,
+    };
+    steps(&repeated)?;
+    let beats = (0..repetitions)
+        .flat_map(|repetition| {
+            let start = beat.length.checked_mul(Time::from(repetition as i64));
+            beat.beats.iter().map(move |time| time.checked_add(start?))
+        })
+        .collect::<Option<_>>()
+        .ok_or(ReduceErrorKind::Overflow)?;
+    Ok(ReducedBeat { beats, ..repeated })
+}
+
+/// The least common multiple of two resolutions, if a time can have it as
+/// its denominator.
+fn lcm(a: usize, b: usize) -> Result<usize, ReduceError> {
+    (a / gcd(a, b))
+        .checked_mul(b)
+        .filter(|lcm| i64::try_from(*lcm).is_ok())
+        .ok_or(ReduceErrorKind::Overflow.into())
 }
 
-fn chain(clip_a: ReducedClip, mut clip_b: ReducedClip) -> ReducedClip {
-    for command in &mut clip_b.commands {
-        command.0 = &command.0 + &clip_a.length;
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
     }
+}
 
-    ReducedClip {
-        commands: vec![clip_a.commands, clip_b.commands]
-            .into_iter()
-            .flatten()
-            .collect(),
-        length: &clip_a.length + &clip_b.length,
+/// The step `time` falls on, of `resolution` to a whole note. Only called
+/// with times in beats of at most `MAX_STEPS` steps, which fit.
+fn time_to_step(time: &Time, resolution: usize) -> usize {
+    (i128::from(time.num()) * resolution as i128 / i128::from(time.denom())) as usize
+}
+
+fn step_to_time(step: usize, resolution: usize) -> Result<Time, ReduceError> {
+    match (i64::try_from(step), i64::try_from(resolution)) {
+        (Ok(step), Ok(resolution)) => Ok(Time::new(step, resolution)),
+        _ => Err(ReduceErrorKind::Overflow.into()),
     }
 }
 
-fn reduce_beat_expression(
-    beat: &BeatExpression,
-    all_beat_expressions: &HashMap<String, BeatExpression>,
-    reduced_beats: &HashMap<String, ReducedBeat>,
-) -> (ReducedBeat, HashMap<String, ReducedBeat>) {
-    let (result, reduced_beats) = match beat {
-        BeatExpression::DotBeatExpression(e) => {
-            (reduce_dot_beat_expression(e), reduced_beats.clone())
-        }
-        BeatExpression::NumberBeatExpression(e) => {
-            (reduce_number_beat_expression(e), reduced_beats.clone())
-        }
-        BeatExpression::BeatChainExpression(e) => {
-            reduce_beat_chain_expression(e, all_beat_expressions, reduced_beats)
-        }
-        BeatExpression::ReferenceBeatExpression(e) => {
-            reduce_reference_beat_expression(e, all_beat_expressions, reduced_beats)
-        }
-    };
-    let result = order_beat(result);
-    (result, reduced_beats)
+/// How many steps `beat` takes, if that is at most `MAX_STEPS`.
+fn steps(beat: &ReducedBeat) -> Result<usize, ReduceError> {
+    let steps =
+        i128::from(beat.length.num()) * beat.resolution as i128 / i128::from(beat.length.denom());
+    if steps > MAX_STEPS as i128 {
+        return Err(ReduceErrorKind::LongBeat.into());
+    }
+    Ok(steps as usize)
 }
 
 fn order_beat(mut input: ReducedBeat) -> ReducedBeat {
-    input.beats.sort_unstable_by_key(time_to_frac);
+    input.beats.sort_unstable();
     input
 }
 
 fn reduce_beat_chain_expression(
     expression: &BeatChainExpression,
     all_beat_expressions: &HashMap<String, BeatExpression>,
-    reduced_beats: &HashMap<String, ReducedBeat>,
-) -> (ReducedBeat, HashMap<String, ReducedBeat>) {
-    let (beat_a, reduced_beats) =
-        reduce_beat_expression(&expression.beat_a, all_beat_expressions, reduced_beats);
-    let (beat_b, reduced_beats) =
-        reduce_beat_expression(&expression.beat_b, all_beat_expressions, &reduced_beats);
-    let a_length = time_to_frac(&beat_a.length);
-    let sum_length = frac_to_time(&(a_length + time_to_frac(&beat_b.length)));
-    let beats_b_updated: Vec<Time> = beat_b
-        .beats
-        .iter()
-        .map(|b| frac_to_time(&(a_length + time_to_frac(b))))
-        .collect();
+    reduced_beats: &mut HashMap<String, ReducedBeat>,
+) -> Result<ReducedBeat, ReduceError> {
+    let beat_a = reduce_beat_expression(&expression.beat_a, all_beat_expressions, reduced_beats)?;
+    let beat_b = reduce_beat_expression(&expression.beat_b, all_beat_expressions, reduced_beats)?;
+    let later = |time: &Time| {
+        beat_a
+            .length
+            .checked_add(*time)
+            .ok_or(ReduceErrorKind::Overflow)
+    };
+    let sum_length = later(&beat_b.length)?;
+    let beats_b_updated: Vec<Time> = beat_b.beats.iter().map(later).collect::<Result<_, _>>()?;
     let all_beats = beats_b_updated.into_iter().chain(beat_a.beats).collect();
-    (
-        ReducedBeat {
-            beats: all_beats,
-            length: sum_length,
-        },
-        reduced_beats,
-    )
+    Ok(ReducedBeat {
+        beats: all_beats,
+        length: sum_length,
+        resolution: lcm(beat_a.resolution, beat_b.resolution)?,
+    })
 }
 
 fn reduce_reference_beat_expression(
     expression: &ReferenceBeatExpression,
     all_beat_expressions: &HashMap<String, BeatExpression>,
-    reduced_beats: &HashMap<String, ReducedBeat>,
-) -> (ReducedBeat, HashMap<String, ReducedBeat>) {
+    reduced_beats: &mut HashMap<String, ReducedBeat>,
+) -> Result<ReducedBeat, ReduceError> {
     let name = &expression.name;
-    if reduced_beats.contains_key(name) {
-        (reduced_beats[name].clone(), reduced_beats.clone())
+    if let Some(beat) = reduced_beats.get(name) {
+        Ok(beat.clone())
     } else {
-        reduce_beat_expression(
-            &all_beat_expressions[name].clone(),
+        let beat = reduce_beat_expression(
+            &all_beat_expressions[name],
             all_beat_expressions,
             reduced_beats,
-        )
+        )?;
+        reduced_beats.insert(name.clone(), beat.clone());
+        Ok(beat)
     }
 }
 
@@ -610,10 +1592,7 @@ fn reduce_dot_beat_expression(expression: &DotBeatExpression) -> ReducedBeat {
         .iter()
         .enumerate()
         .filter_map(|(index, beat_on)| {
-            let time = Time {
-                num: index as isize,
-                denom: 16,
-            };
+            let time = Time::new(index as i64, expression.resolution as i64);
             if *beat_on {
                 Some(time)
             } else {
@@ -623,11 +1602,64 @@ fn reduce_dot_beat_expression(expression: &DotBeatExpression) -> ReducedBeat {
         .collect();
     ReducedBeat {
         beats,
-        length: Time {
-            num: expression.beats.len() as isize,
-            denom: 16,
-        },
+        length: Time::new(expression.beats.len() as i64, expression.resolution as i64),
+        resolution: expression.resolution,
+    }
+}
+
+/// Spreads the hits over the steps with Bjorklund's algorithm, then rotates
+/// the pattern left by `rotation` steps.
+fn reduce_euclidean_beat_expression(
+    expression: &EuclideanBeatExpression,
+) -> Result<ReducedBeat, ReduceError> {
+    let steps = literal(&expression.steps);
+    if steps > MAX_STEPS {
+        return Err(ReduceErrorKind::LongBeat.at(expression.span));
+    }
+    let hits = literal(&expression.hits).min(steps);
+    let pattern = bjorklund(hits, steps);
+    let rotation = if steps == 0 {
+        0
+    } else {
+        literal(&expression.rotation) % steps
+    };
+    let beats = (0..steps)
+        .filter(|step| pattern[(step + rotation) % steps])
+        .map(|step| Time::new(step as i64, DEFAULT_RESOLUTION as i64))
+        .collect();
+    Ok(ReducedBeat {
+        beats,
+        length: Time::new(steps as i64, DEFAULT_RESOLUTION as i64),
+        resolution: DEFAULT_RESOLUTION,
+    })
+}
+
+fn literal(number: &NumberExpression) -> usize {
+    match number {
+        NumberExpression::Literal(number) => *number,
+        NumberExpression::Parameter(_) => unreachable!("parameters are expanded before reduction"),
+    }
+}
+
+/// Starts with a group per hit and a group per rest, and keeps appending the
+/// remainder groups to the others until at most one remainder is left.
+fn bjorklund(hits: usize, steps: usize) -> Vec<bool> {
+    let mut groups = vec![vec![true]; hits];
+    let mut remainders = vec![vec![false]; steps - hits];
+    while groups.len() > 1 && remainders.len() > 1 {
+        let count = groups.len().min(remainders.len());
+        let rest_of_groups = groups.split_off(count);
+        let rest_of_remainders = remainders.split_off(count);
+        for (group, remainder) in groups.iter_mut().zip(remainders) {
+            group.extend(remainder);
+        }
+        remainders = if rest_of_groups.is_empty() {
+            rest_of_remainders
+        } else {
+            rest_of_groups
+        };
     }
+    groups.into_iter().chain(remainders).flatten().collect()
 }
 
 fn reduce_number_beat_expression(expression: &NumberBeatExpression) -> ReducedBeat {
@@ -636,23 +1668,18 @@ fn reduce_number_beat_expression(expression: &NumberBeatExpression) -> ReducedBe
             .beats
             .iter()
             .fold((1, Vec::new()), |(current_position, mut beats), beat| {
-                let new_beat = Time {
-                    num: current_position,
-                    denom: 16,
-                };
+                let new_beat = Time::new(current_position as i64, expression.resolution as i64);
                 beats.push(new_beat);
                 (current_position + *beat as isize, beats)
             });
     ReducedBeat {
         beats,
-        length: Time {
-            num: length,
-            denom: 16,
-        },
+        length: Time::new(length as i64, expression.resolution as i64),
+        resolution: expression.resolution,
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ReducedClip {
     pub commands: Vec<(Time, ClipCommand)>,
     pub length: Time,
@@ -660,43 +1687,68 @@ pub struct ReducedClip {
 
 impl ReducedClip {
     pub fn print(&self) {
-        println!(
-            "length {}:{}={}",
-            self.length.num,
-            self.length.denom,
-            self.length.num as f64 / self.length.denom as f64
-        );
+        println!("length {}={}", self.length, f64::from(self.length));
         for (time, command) in &self.commands {
-            print!(
-                "time {}:{}={}",
-                time.num,
-                time.denom,
-                time.num as f64 / time.denom as f64
-            );
+            print!("time {}={}", time, f64::from(time));
             println!("command {:?}", command);
         }
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ClipCommand {
-    PlayClip(String, usize),
-    PlayClipFrom(String, usize, Time),
-    PlayMulti(String, usize, String),
-    PlayMultiFrom(String, Time, usize, String),
+    PlayClip(Source, usize),
+    PlayClipFrom(Source, usize, Time),
+    /// Plays a file from the given time into it at the given speed, backwards
+    /// if the speed is negative.
+    PlayClipAt(Source, usize, Time, Time),
+    PlayClipFit(Source, usize, Fit),
+    PlayClipPingPong(Source, usize, PingPong),
+    PlayMulti(Multi, usize),
+    PlayMultiFrom(Multi, usize, Time),
     Stop(usize),
     MultiNext(usize),
+    /// Shows nothing on a layer, like `Stop`, where the clip playing on it
+    /// ends. `hold` drops it.
+    End(usize),
 }
 
 impl ClipCommand {
+    /// Plays `file` from `from` at `speed`, with the plainest command that does.
+    pub fn play_at(file: Source, layer: usize, from: Time, speed: Time) -> ClipCommand {
+        if speed == Time::one() {
+            ClipCommand::PlayClipFrom(file, layer, from)
+        } else {
+            ClipCommand::PlayClipAt(file, layer, from, speed)
+        }
+    }
+
+    /// The file a command plays, with the time into it and the speed it
+    /// starts at, unless it plays a multi video or nothing.
+    pub fn playback(&self) -> Option<(&Source, usize, Time, Time)> {
+        let one = Time::one();
+        match self {
+            ClipCommand::PlayClip(file, layer) => Some((file, *layer, Time::zero(), one)),
+            ClipCommand::PlayClipFrom(file, layer, from) => Some((file, *layer, *from, one)),
+            ClipCommand::PlayClipAt(file, layer, from, speed) => {
+                Some((file, *layer, *from, *speed))
+            }
+            _ => None,
+        }
+    }
+
     pub fn layer(&self) -> usize {
         match self {
             ClipCommand::PlayClip(_, layer) => *layer,
             ClipCommand::PlayClipFrom(_, layer, _) => *layer,
-            ClipCommand::PlayMulti(_, _, _) => 0,
-            ClipCommand::PlayMultiFrom(_, _, _, _) => 0,
+            ClipCommand::PlayClipAt(_, layer, _, _) => *layer,
+            ClipCommand::PlayClipFit(_, layer, _) => *layer,
+            ClipCommand::PlayClipPingPong(_, layer, _) => *layer,
+            ClipCommand::PlayMulti(_, layer) => *layer,
+            ClipCommand::PlayMultiFrom(_, layer, _) => *layer,
             ClipCommand::MultiNext(layer) => *layer,
             ClipCommand::Stop(layer) => *layer,
+            ClipCommand::End(layer) => *layer,
         }
     }
 
@@ -704,73 +1756,737 @@ impl ClipCommand {
         match self {
             ClipCommand::PlayClip(file, l) => ClipCommand::PlayClip(file.clone(), layer + l),
             ClipCommand::PlayClipFrom(file, l, time) => {
-                ClipCommand::PlayClipFrom(file.clone(), layer + l, time.clone())
+                ClipCommand::PlayClipFrom(file.clone(), layer + l, *time)
+            }
+            ClipCommand::PlayClipAt(file, l, time, speed) => {
+                ClipCommand::PlayClipAt(file.clone(), layer + l, *time, *speed)
+            }
+            ClipCommand::PlayClipFit(file, l, fit) => {
+                ClipCommand::PlayClipFit(file.clone(), layer + l, fit.clone())
+            }
+            ClipCommand::PlayClipPingPong(file, l, pingpong) => {
+                ClipCommand::PlayClipPingPong(file.clone(), layer + l, pingpong.clone())
+            }
+            ClipCommand::PlayMulti(multi, l) => ClipCommand::PlayMulti(multi.clone(), layer + l),
+            ClipCommand::PlayMultiFrom(multi, l, time) => {
+                ClipCommand::PlayMultiFrom(multi.clone(), layer + l, *time)
             }
-            ClipCommand::PlayMulti(_, _, _) => self.clone(),
-            ClipCommand::PlayMultiFrom(_, _, _, _) => self.clone(),
             ClipCommand::MultiNext(l) => ClipCommand::MultiNext(layer + l),
             ClipCommand::Stop(l) => ClipCommand::Stop(l + layer),
+            ClipCommand::End(l) => ClipCommand::End(l + layer),
         }
     }
 }
 
+/// A video file, and where in it the time a command plays it from counts from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Source {
+    pub path: String,
+    pub offset: Option<SourceOffset>,
+}
+
+/// How a file is stretched: the part of it from `start` to `end` seconds, or
+/// all of it, lasts `length`. It starts `from` into `length`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fit {
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub length: Time,
+    pub from: Time,
+}
+
+/// The files of a multi video, `path` with `_0` up to one less than
+/// `subclips` and `extension` after it. A beat switches to the next one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Multi {
+    pub path: String,
+    pub subclips: usize,
+    pub extension: String,
+}
+
+/// How a file plays forwards and backwards: it turns around at `start` and
+/// `end` seconds into it, or at its start and end. It starts `from` into the
+/// sweep, counted in time played.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PingPong {
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub from: Time,
+}
+
 #[derive(Clone, Debug)]
 pub struct ReducedBeat {
     pub beats: Vec<Time>,
     pub length: Time,
+    /// The steps per whole note its hits fall on.
+    pub resolution: usize,
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub struct Time {
-    pub num: isize,
-    pub denom: usize,
-}
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    /// A main file of `declarations`, with its clips in `d/` and named `*.mov`.
+    pub(crate) fn source(declarations: &str) -> String {
+        format!("directory = 'd/'; extension = '.mov';\n{}", declarations)
+    }
+
+    /// Reduces the main file of `declarations`, with none of its files
+    /// available.
+    pub(crate) fn reduced(declarations: &str) -> Result<Program, Vec<ReduceError>> {
+        reduce(parse(&source(declarations)).unwrap(), &HashSet::new())
+    }
+
+    pub(crate) fn program(source: &str) -> Program {
+        reduced(source).unwrap_or_else(|problems| panic!("{:?}", problems))
+    }
 
-impl Time {
-    pub fn mul(&self, other: isize) -> Self {
-        Time {
-            num: self.num * other,
-            denom: self.denom,
+    /// The errors reducing `source` finds, without the warnings.
+    pub(crate) fn errors(source: &str) -> Vec<String> {
+        match reduced(source) {
+            Ok(_) => Vec::new(),
+            Err(problems) => problems
+                .iter()
+                .filter(|problem| !problem.is_warning())
+                .map(|problem| problem.to_string())
+                .collect(),
         }
     }
 
-    pub fn zero() -> Self {
-        Time { num: 0, denom: 1 }
+    fn takes(program: &Program, seed: u64, count: usize) -> Vec<ReducedClip> {
+        let mut rng = Rng::new(seed);
+        (0..count)
+            .map(|_| program.take(&mut rng).unwrap())
+            .collect()
     }
-}
 
-impl From<Time> for f64 {
-    fn from(val: Time) -> Self {
-        val.num as f64 / val.denom as f64
+    #[test]
+    fn clips_without_choices_are_reduced_once() {
+        let program = program(
+            "clip a = 'a' | 'b';\n\
+             clip b = a * 2 ? 'c';\n\
+             clip c = b : a;\n\
+             c",
+        );
+        // and the parts of the others without choices, which have no name
+        let mut fixed: Vec<&String> = program
+            .fixed
+            .keys()
+            .filter(|name| !name.starts_with('#'))
+            .collect();
+        fixed.sort();
+        assert_eq!(fixed, ["a"]);
+        let a = program
+            .take_named_clip("a", &mut Rng::new(0))
+            .unwrap()
+            .unwrap();
+        assert_eq!(a, ReducedClip::clone(&program.fixed["a"]));
     }
-}
 
-impl From<&Time> for f64 {
-    fn from(val: &Time) -> Self {
-        val.num as f64 / val.denom as f64
+    #[test]
+    fn only_choices_are_reduced_again() {
+        let program = program("clip a = 'a' ? 'b';\n('a' * 4)[1:3] | rev (a ? 'c' * 2)");
+        for clip in [
+            &program.clips["a"],
+            program.main_expression.as_ref().unwrap(),
+        ] {
+            let clip = format!("{:?}", clip);
+            for fixed in ["RawVideo", "Loop", "Truncated"] {
+                assert!(!clip.contains(fixed), "{}", clip);
+            }
+        }
+        let main = format!("{:?}", program.main_expression);
+        assert_eq!(main.matches("Choice(").count(), 1);
+        assert!(main.contains("Reverse"));
+        let mut lengths: Vec<Time> = takes(&program, 0, 40)
+            .iter()
+            .map(|take| take.length)
+            .collect();
+        lengths.sort();
+        lengths.dedup();
+        assert_eq!(lengths, [Time::new(3, 2), Time::new(5, 2)]);
     }
-}
 
-impl Sub for &Time {
-    type Output = Time;
+    #[test]
+    fn takes_roll_only_what_they_play() {
+        let played = "clip a = 'a' ? 'b';\na | a | ('c' ? 'd')";
+        // a clip that is never played rolls nothing, wherever it is declared
+        let unused = "clip u = 'u' ? 'v';\nclip a = 'a' ? 'b';\na | a | ('c' ? 'd')";
+        for seed in 0..20 {
+            assert_eq!(
+                takes(&program(played), seed, 5),
+                takes(&program(unused), seed, 5)
+            );
+        }
+        // a clip keeps what it rolled for the whole take
+        for take in takes(&program(played), 3, 20) {
+            let mut files: Vec<&str> = take
+                .commands
+                .iter()
+                .filter_map(|(_, command)| command.playback())
+                .map(|(file, ..)| file.path.as_str())
+                .filter(|path| ["d/a.mov", "d/b.mov"].contains(path))
+                .collect();
+            assert_eq!(files.len(), 2);
+            files.dedup();
+            assert_eq!(files.len(), 1);
+        }
+    }
 
-    fn sub(self, rhs: Self) -> Self::Output {
-        frac_to_time(&(time_to_frac(self) - time_to_frac(rhs)))
+    #[test]
+    fn a_fit_to_no_beats_is_an_error() {
+        let declarations = "clip f(n) = fit 'a' n;\nf(0) | f(2)";
+        let problems = reduced(declarations).err().unwrap();
+        let source = source(declarations);
+        let located: Vec<(String, &str)> = problems
+            .iter()
+            .filter(|problem| !problem.is_warning())
+            .map(|problem| {
+                let span = problem.span.unwrap();
+                (problem.to_string(), &source[span.start..span.end])
+            })
+            .collect();
+        assert_eq!(
+            located,
+            [("a clip cannot be fitted to 0 beats".to_string(), "n")]
+        );
+        assert_eq!(
+            program("clip f(n) = fit 'a' n;\nf(2)")
+                .take(&mut Rng::new(0))
+                .unwrap()
+                .length,
+            Time::new(1, 2)
+        );
     }
-}
 
-impl Sub for Time {
-    type Output = Time;
+    fn length(source: &str) -> Time {
+        takes(&program(source), 0, 1)[0].length
+    }
 
-    fn sub(self, rhs: Self) -> Self::Output {
-        frac_to_time(&(time_to_frac(&self) - time_to_frac(&rhs)))
+    #[test]
+    fn the_meter_sets_how_long_beats_and_bars_are() {
+        // a bar of 7/8 is seven eighth notes, a beat one
+        assert_eq!(length("meter = 7/8;\n'a'"), Time::new(7, 8));
+        assert_eq!(length("meter = 7/8;\n-"), Time::new(7, 8));
+        assert_eq!(length("meter = 7/8;\n'a' * 3"), Time::new(21, 8));
+        assert_eq!(length("meter = 7/8;\n('a' * 2)[2:5]"), Time::new(3, 8));
+        assert_eq!(length("meter = 7/8;\n('a' * 2)[2:1 :]"), Time::new(7, 8));
+        assert_eq!(
+            length("meter = 7/8;\n('a' * 2)[1:2.3 : 2:1]"),
+            Time::new(5, 8)
+        );
+        // dots are sixteenths in any meter, fourteen to a bar of 7/8
+        assert_eq!(length("meter = 7/8;\n.-. >> 'a'"), Time::new(3, 16));
+        assert_eq!(
+            length("meter = 7/8;\n.-.-.-.-.-.-.- >> 'a'"),
+            length("meter = 7/8;\n'a'")
+        );
+        assert_eq!(length("meter = 7/8;\nfit 'a' 2"), Time::new(2, 8));
+        assert_eq!(length("meter = 3/2;\n'a'[2:]"), Time::new(2, 2));
+        // without a declaration it is 4/4
+        assert_eq!(length("('a' * 2)[2:5]"), Time::new(3, 4));
+        assert_eq!(
+            errors("bpm = 128;\nmeter = 7/8;\n'a'[9:]"),
+            ["range [9:] of a clip 7 beats long is empty"]
+        );
+    }
+
+    #[test]
+    fn ranges_are_cut_from_positions_or_back_from_the_end() {
+        assert_eq!(length("'a'[2:]"), Time::new(3, 4));
+        assert_eq!(length("'a'[:-1]"), Time::new(3, 4));
+        assert_eq!(length("'a'[-1:]"), Time::new(1, 4));
+        assert_eq!(length("'a'[2:-1]"), Time::new(2, 4));
+        // beats can be fractional, and sixteenths are counted from the beat
+        assert_eq!(length("'a'[3/2:]"), Time::new(7, 8));
+        assert_eq!(length("'a'[:-1/2]"), Time::new(7, 8));
+        assert_eq!(length("'a'[1.2:1.4]"), Time::new(1, 8));
+        // bars come before beats
+        assert_eq!(length("('a' * 4)[2:1.3 : 4:1]"), Time::new(15, 8));
+        assert_eq!(length("('a' * 4)[-1:0 :]"), Time::new(1, 1));
+        assert_eq!(length("('a' * 4)[: -1:2]"), Time::new(5, 2));
+        assert_eq!(
+            errors("'a'[3:3]"),
+            ["range [3:3] of a clip 4 beats long is empty"]
+        );
+        assert_eq!(
+            errors("'a'[:-4]"),
+            ["range [:-4] of a clip 4 beats long is empty"]
+        );
+        assert_eq!(
+            errors("('a' * 2)[2:1 : 1:3]"),
+            ["range [2:1 : 1:3] of a clip 8 beats long is empty"]
+        );
+        assert_eq!(
+            errors("'a'[-9/2:]"),
+            ["range [-9/2:] starts before the clip 4 beats long does"]
+        );
+    }
+
+    #[test]
+    fn reversed_clips_play_backwards_from_where_they_end() {
+        let commands = |source: &str| takes(&program(source), 0, 1).remove(0).commands;
+        let file = |name: &str| Source {
+            path: format!("d/{}.mov", name),
+            offset: None,
+        };
+        let backwards = |name: &str, layer: usize, from: Time, speed: i64| {
+            ClipCommand::PlayClipAt(file(name), layer, from, Time::from(-speed))
+        };
+        let whole = Time::one;
+        assert_eq!(
+            commands("rev 'a'"),
+            [
+                (Time::zero(), backwards("a", 0, whole(), 1)),
+                (whole(), ClipCommand::End(0))
+            ]
+        );
+        // what played last plays first
+        assert_eq!(
+            commands("rev ('a' | 'b' * 2)"),
+            [
+                (Time::zero(), backwards("a", 0, whole(), 1)),
+                (Time::from(1), backwards("b", 0, whole(), 1)),
+                (Time::from(2), backwards("b", 0, whole(), 1)),
+                (Time::from(3), ClipCommand::End(0))
+            ]
+        );
+        // a shorter layer starts late, from where it was cut
+        assert_eq!(
+            commands("rev ('a' : 'b'[1:2])"),
+            [
+                (Time::zero(), backwards("a", 1, whole(), 1)),
+                (Time::zero(), ClipCommand::End(0)),
+                (Time::new(3, 4), backwards("b", 0, Time::new(1, 4), 1)),
+                (whole(), ClipCommand::End(0)),
+                (whole(), ClipCommand::End(1))
+            ]
+        );
+        assert_eq!(
+            commands("rev ('a' ~ 2)"),
+            [
+                (Time::zero(), backwards("a", 0, Time::from(2), 2)),
+                (whole(), ClipCommand::End(0))
+            ]
+        );
+        assert_eq!(
+            commands("rev rev 'a'"),
+            [
+                (
+                    Time::zero(),
+                    ClipCommand::PlayClipFrom(file("a"), 0, Time::zero())
+                ),
+                (whole(), ClipCommand::End(0))
+            ]
+        );
+    }
+
+    #[test]
+    fn multi_videos_play_on_their_own_layer() {
+        let commands = |source: &str| takes(&program(source), 0, 1).remove(0).commands;
+        let multi = |name: &str, subclips: usize| Multi {
+            path: format!("d/{}", name),
+            subclips,
+            extension: ".mov".into(),
+        };
+        let sixteenth = Time::new(1, 16);
+        assert_eq!(
+            commands("(.. @ multi 2 'x') : -- @ multi 3 'y'"),
+            [
+                (Time::zero(), ClipCommand::PlayMulti(multi("y", 3), 0)),
+                (Time::zero(), ClipCommand::PlayMulti(multi("x", 2), 1)),
+                (Time::zero(), ClipCommand::MultiNext(1)),
+                (sixteenth, ClipCommand::MultiNext(1)),
+                (Time::one(), ClipCommand::End(0)),
+                (Time::one(), ClipCommand::End(1))
+            ]
+        );
+        // a beat over both layers switches both
+        assert_eq!(
+            commands("-. @ (multi 2 'x' : multi 3 'y')"),
+            [
+                (Time::zero(), ClipCommand::PlayMulti(multi("y", 3), 0)),
+                (Time::zero(), ClipCommand::PlayMulti(multi("x", 2), 1)),
+                (sixteenth, ClipCommand::MultiNext(0)),
+                (sixteenth, ClipCommand::MultiNext(1)),
+                (Time::one(), ClipCommand::End(0)),
+                (Time::one(), ClipCommand::End(1))
+            ]
+        );
+        // cut, it starts as far into its file and on the subclip it had got to
+        assert_eq!(
+            commands("('a' : ..-- @ multi 2 'x')[2:]"),
+            [
+                (
+                    Time::zero(),
+                    ClipCommand::PlayMultiFrom(multi("x", 2), 0, Time::new(1, 4))
+                ),
+                (Time::zero(), ClipCommand::MultiNext(0)),
+                (
+                    Time::zero(),
+                    ClipCommand::PlayClipFrom(
+                        Source {
+                            path: "d/a.mov".into(),
+                            offset: None
+                        },
+                        1,
+                        Time::new(1, 4)
+                    )
+                ),
+                (Time::zero(), ClipCommand::MultiNext(0)),
+                (Time::new(3, 4), ClipCommand::End(0)),
+                (Time::new(3, 4), ClipCommand::End(1))
+            ]
+        );
+    }
+
+    #[test]
+    fn layers_end_with_their_clip_unless_held() {
+        let commands = |source: &str| takes(&program(source), 0, 1).remove(0).commands;
+        let play = |name: &str, layer: usize| {
+            ClipCommand::PlayClip(
+                Source {
+                    path: format!("d/{}.mov", name),
+                    offset: None,
+                },
+                layer,
+            )
+        };
+        let beat = Time::new(1, 4);
+        assert_eq!(
+            commands("'a'[1:2] : 'b'"),
+            [
+                (Time::zero(), play("b", 0)),
+                (Time::zero(), play("a", 1)),
+                (beat, ClipCommand::End(1)),
+                (Time::one(), ClipCommand::End(0))
+            ]
+        );
+        // each part of a chain and each repetition ends before the next plays
+        assert_eq!(
+            commands("'a' | 'b'"),
+            [
+                (Time::zero(), play("b", 0)),
+                (Time::one(), ClipCommand::End(0)),
+                (Time::one(), play("a", 0)),
+                (Time::from(2), ClipCommand::End(0))
+            ]
+        );
+        assert_eq!(
+            commands("'a'[1:2] * 2 : 'b'"),
+            [
+                (Time::zero(), play("b", 0)),
+                (Time::zero(), play("a", 1)),
+                (beat, ClipCommand::End(1)),
+                (beat, play("a", 1)),
+                (Time::new(1, 2), ClipCommand::End(1)),
+                (Time::one(), ClipCommand::End(0))
+            ]
+        );
+        // a held clip shows until something else plays on its layer
+        assert_eq!(
+            commands("hold 'a'[1:2] : 'b'"),
+            [
+                (Time::zero(), play("b", 0)),
+                (Time::zero(), play("a", 1)),
+                (Time::one(), ClipCommand::End(0))
+            ]
+        );
+        assert_eq!(
+            commands("hold ('a' | 'b')"),
+            [(Time::zero(), play("b", 0)), (Time::one(), play("a", 0))]
+        );
+    }
+
+    #[test]
+    fn a_sliced_pingpong_starts_mid_sweep() {
+        let commands = |source: &str| takes(&program(source), 0, 1).remove(0).commands;
+        let pingpong = |layer: usize, start: Option<f64>, end: Option<f64>, from: Time| {
+            let file = Source {
+                path: "d/a.mov".into(),
+                offset: None,
+            };
+            ClipCommand::PlayClipPingPong(file, layer, PingPong { start, end, from })
+        };
+        assert_eq!(
+            commands("pingpong 'a' [1.5s:3s]"),
+            [
+                (
+                    Time::zero(),
+                    pingpong(0, Some(1.5), Some(3.0), Time::zero())
+                ),
+                (Time::one(), ClipCommand::End(0))
+            ]
+        );
+        assert_eq!(
+            commands("(pingpong 'a')[2:]"),
+            [
+                (Time::zero(), pingpong(0, None, None, Time::new(1, 4))),
+                (Time::new(3, 4), ClipCommand::End(0))
+            ]
+        );
+        // the second sweep starts over
+        assert_eq!(
+            commands("(pingpong 'a' * 2)[3:6]"),
+            [
+                (Time::zero(), pingpong(0, None, None, Time::new(1, 2))),
+                (Time::new(1, 2), ClipCommand::End(0)),
+                (Time::new(1, 2), pingpong(0, None, None, Time::zero())),
+                (Time::new(3, 4), ClipCommand::End(0))
+            ]
+        );
+        // the file played first is played for the length of the whole clip
+        assert_eq!(
+            commands("pingpong ('b' * 2 | 'a')")[0],
+            (Time::zero(), pingpong(0, None, None, Time::zero()))
+        );
+        assert_eq!(length("pingpong ('b' * 2 | 'a')"), Time::from(3));
+        assert_eq!(
+            commands("pingpong -"),
+            [
+                (Time::zero(), ClipCommand::Stop(0)),
+                (Time::one(), ClipCommand::End(0))
+            ]
+        );
+    }
+
+    #[test]
+    fn offsets_stay_with_their_file() {
+        let commands = |source: &str| takes(&program(source), 0, 1).remove(0).commands;
+        let file = |offset: SourceOffset| Source {
+            path: "d/a.mov".into(),
+            offset: Some(offset),
+        };
+        assert_eq!(
+            commands("'a'@2.5s"),
+            [
+                (
+                    Time::zero(),
+                    ClipCommand::PlayClip(file(SourceOffset::Seconds(2.5)), 0)
+                ),
+                (Time::one(), ClipCommand::End(0))
+            ]
+        );
+        // slicing moves on in beats from the offset, which stays in the file
+        let drop = || file(SourceOffset::Cue("drop".into()));
+        assert_eq!(
+            commands("('a'@cue(drop))[2:]"),
+            [
+                (
+                    Time::zero(),
+                    ClipCommand::PlayClipFrom(drop(), 0, Time::new(1, 4))
+                ),
+                (Time::new(3, 4), ClipCommand::End(0))
+            ]
+        );
+        assert_eq!(
+            commands("rev 'a'@f120"),
+            [
+                (
+                    Time::zero(),
+                    ClipCommand::PlayClipAt(
+                        file(SourceOffset::Frame(120)),
+                        0,
+                        Time::one(),
+                        -Time::one()
+                    )
+                ),
+                (Time::one(), ClipCommand::End(0))
+            ]
+        );
+        // a file with an offset is the same file
+        let available = HashSet::from(["d/a.mov".to_string()]);
+        let source = source("'a'@2s | 'a'@f3");
+        let program = reduce(parse(&source).unwrap(), &available).unwrap();
+        assert!(program.warnings().is_empty());
+    }
+
+    fn beat(source: &str) -> ReducedBeat {
+        let (rest, beat) = crate::parser::beats_parser::parse_beat_expression(source).unwrap();
+        assert_eq!(rest, "", "{}", source);
+        reduce_beat_expression(&beat, &HashMap::new(), &mut HashMap::new()).unwrap()
+    }
+
+    /// The beat `source` reduces to, with a `.` for every step of it with a
+    /// hit and a `-` for every other one.
+    fn pattern(source: &str) -> String {
+        let beat = beat(source);
+        let steps = beat.resolution;
+        (0..time_to_step(&beat.length, steps))
+            .map(|step| Time::new(step as i64, steps as i64))
+            .map(|time| if beat.beats.contains(&time) { '.' } else { '-' })
+            .collect()
+    }
+
+    #[test]
+    fn euclidean_beats_spread_their_hits() {
+        assert_eq!(pattern("e(3, 8)"), ".--.--.-");
+        assert_eq!(pattern("e(5,16)"), ".--.--.--.--.---");
+        assert_eq!(pattern("e(4, 12)"), ".--.--.--.--");
+        assert_eq!(pattern("e(7, 8)"), ".......-");
+        // rotated to the left
+        assert_eq!(pattern("e(3, 8, 2)"), "-.--.-.-");
+        assert_eq!(pattern("e(3, 8, 10)"), pattern("e(3, 8, 2)"));
+        assert_eq!(pattern("e(0, 4)"), "----");
+        assert_eq!(pattern("e(4, 4)"), "....");
+        assert_eq!(pattern("e(6, 4)"), "....");
+        assert_eq!(pattern("e(2, 0, 1)"), "");
+        // wherever a beat goes
+        assert_eq!(pattern("e(1, 2) | e(2, 4) * 2"), ".-.-.-.-.-");
+        assert_eq!(pattern("!e(3, 8)"), "-..-..-.");
+        let cuts = takes(&program("e(3, 8) >> 'a'"), 0, 1).remove(0);
+        let starts: Vec<Time> = cuts
+            .commands
+            .iter()
+            .filter(|(_, command)| command.playback().is_some())
+            .map(|(time, _)| *time)
+            .collect();
+        assert_eq!(starts, [Time::zero(), Time::new(3, 16), Time::new(6, 16)]);
+        assert_eq!(cuts.length, Time::new(1, 2));
+        assert_eq!(
+            takes(&program("clip f(n) = e(n, 8) >> 'a';\nf(3)"), 0, 1),
+            takes(&program("e(3, 8) >> 'a'"), 0, 1)
+        );
+        // with a single number it is a call
+        assert_eq!(errors("clip f(b) = b >> 'a';\nf(e(3))"), ["unknown clip e"]);
+    }
+
+    #[test]
+    fn beats_combine() {
+        assert_eq!(pattern(".-.- + ..--"), "...-");
+        assert_eq!(pattern(".-.- & ..--"), ".---");
+        assert_eq!(pattern("!.-.."), "-.--");
+        assert_eq!(pattern("!!.-.."), ".-..");
+        assert_eq!(pattern(".--- << 1"), "---.");
+        assert_eq!(pattern("..-- << 6"), "--..");
+        assert_eq!(pattern(".-- * 3"), ".--.--.--");
+        assert_eq!(pattern(".-- * 0"), "");
+        // the shorter beat is padded with rests
+        assert_eq!(pattern(". + ---."), ".--.");
+        assert_eq!(pattern(". & ---."), "----");
+        // `+` binds loosest, then `&`, then `!`, then `*` and `<<`
+        assert_eq!(pattern("!.- + -. & .."), "-.");
+        assert_eq!(pattern("!.- * 2"), "-.-.");
+        assert_eq!(pattern("(.- | -.) << 1 * 2"), "--..--..");
+        assert_eq!(pattern(".- | !.- | .- + -."), ".--...");
+        // the steps are those of the finer beat
+        assert_eq!(pattern("{..}/8 + .---"), ".-.-");
+        assert_eq!(pattern("!({.}/8 | .)"), "-.-");
+        let references = program("beat x = .--;\nbeat y = x + x << 1;\n(y | !y) >> 'a'");
+        assert_eq!(
+            takes(&references, 0, 1),
+            takes(&program(".-.-.- >> 'a'"), 0, 1)
+        );
+    }
+
+    #[test]
+    fn beats_past_the_limit_are_errors() {
+        let too_long = ["the beat is more than 65536 steps long"];
+        assert_eq!(errors("beat x = .- * 99999999999;\nx @ 'a'"), too_long);
+        assert_eq!(errors("beat x = .- * 4294967296;\nx >> 'a'"), too_long);
+        assert_eq!(errors("!(- * 65537) @ 'a'"), too_long);
+        // references double a beat at every step, and are checked at each
+        let name = |i: usize| format!("x{}", "i".repeat(i));
+        let mut doubled = "beat x = .-;\n".to_string();
+        for i in 1..64 {
+            doubled += &format!("beat {} = {} | {};\n", name(i), name(i - 1), name(i - 1));
+        }
+        assert_eq!(errors(&(doubled + &name(63) + " @ 'a'")), too_long);
+        // a union takes the steps of both, whatever they are
+        assert_eq!(
+            errors("({.}/65521 + {.}/65519 + {.}/65497 + {.}/65479) @ 'a'"),
+            too_long
+        );
+        assert_eq!(
+            errors("({.}/4294967291 + {.}/4294967279 + {.}/4294967231) @ 'a'"),
+            ["the clip is too long to play"]
+        );
+        assert!(errors("- * 65536 @ 'a'").is_empty());
+        assert_eq!(beat(".- * 32768").beats.len(), 32768);
     }
-}
 
-impl Add for &Time {
-    type Output = Time;
+    #[test]
+    fn groups_set_the_steps_of_a_beat() {
+        let hits = |source: &str| {
+            let beat = beat(source);
+            (beat.beats, beat.length)
+        };
+        let third = |n: i64| Time::new(n, 12);
+        assert_eq!(
+            hits("{...}/12"),
+            (vec![third(0), third(1), third(2)], Time::new(1, 4))
+        );
+        assert_eq!(
+            hits("{. - .}/12"),
+            (vec![third(0), third(2)], Time::new(1, 4))
+        );
+        assert_eq!(hits("{3 1}/12"), (vec![third(1), third(4)], third(5)));
+        assert_eq!(hits("{.....}/20").1, Time::new(1, 4));
+        assert_eq!(hits("{.}/1"), (vec![Time::zero()], Time::one()));
+        // mixed with sixteenths, every hit stays exact
+        assert_eq!(
+            hits("{...}/12 | .-.."),
+            (
+                vec![
+                    third(0),
+                    third(1),
+                    third(2),
+                    Time::new(4, 16),
+                    Time::new(6, 16),
+                    Time::new(7, 16)
+                ],
+                Time::new(1, 2)
+            )
+        );
+        assert_eq!(beat("{...}/12 | .").resolution, 48);
+        assert_eq!(pattern("!{.-.}/12"), "-.-");
+        assert_eq!(pattern("{.--}/12 << 2 * 2"), "-.--.-");
+        let cuts = takes(&program("({...}/12 >> 'a') | ({..}/8 >> 'b')"), 0, 1).remove(0);
+        let starts: Vec<Time> = cuts
+            .commands
+            .iter()
+            .filter(|(_, command)| command.playback().is_some())
+            .map(|(time, _)| *time)
+            .collect();
+        // eighths of b, then triplets of a
+        assert_eq!(
+            starts,
+            [
+                Time::zero(),
+                Time::new(1, 8),
+                Time::new(1, 4),
+                Time::new(1, 4) + third(1),
+                Time::new(1, 4) + third(2)
+            ]
+        );
+        assert_eq!(cuts.length, Time::new(1, 2));
+    }
 
-    fn add(self, rhs: Self) -> Self::Output {
-        frac_to_time(&(time_to_frac(self) + time_to_frac(rhs)))
+    #[test]
+    fn every_alternative_is_checked() {
+        // the range only fits the longer alternative, whichever is rolled
+        let too_short = ["range [-5:] starts before the clip 4 beats long does"];
+        assert_eq!(errors("('a' * 2 ? 'a')[-5:]"), too_short);
+        assert_eq!(errors("('a' ? 'a' * 2)[-5:]"), too_short);
+        assert_eq!(
+            errors("(('a' ? 'a' * 3) | 'b')[-10:]"),
+            ["range [-10:] starts before the clip 8 beats long does"]
+        );
+        // in a clip that is never played, and in a scene
+        assert_eq!(
+            errors("clip x = 'a' ? 'b' ? 'c'[3:2];\n'a'"),
+            ["range [3:2] of a clip 4 beats long is empty"]
+        );
+        assert_eq!(
+            errors("scene one = 'a';\nscene two = 'a' * 4 ? 'b'[2:1];"),
+            ["range [2:1] of a clip 4 beats long is empty"]
+        );
+        // an error in a clip is reported once, however often it is referred to
+        assert_eq!(
+            errors("clip x = 'a' ? 'b'[2:1];\nclip y = x | x;\ny ? x"),
+            ["range [2:1] of a clip 4 beats long is empty"]
+        );
+        assert!(errors("('a' * 2 ? 'a')[-4:]").is_empty());
     }
 }
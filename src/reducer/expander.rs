@@ -0,0 +1,526 @@
+use std::collections::HashMap;
+
+use crate::parser::{
+    beats_parser::{
+        BeatChainExpression, BeatExpression, DotBeatExpression, EuclideanBeatExpression,
+        IntersectionBeatExpression, InvertedBeatExpression, NumberBeatExpression,
+        ParenthesesBeatExpression, ReferenceBeatExpression, RepeatedBeatExpression,
+        RotatedBeatExpression, UnionBeatExpression, DEFAULT_RESOLUTION,
+    },
+    span::Span,
+    ApplyBeatExpression, ArgumentExpression, CallClipExpression, ChoiceAlternative,
+    ChoiceClipExpression, ClipChainExpression, ClipDeclaration, ClipExpression,
+    ClipLayerExpression, ClipLoopExpression, FitClipExpression, HoldClipExpression,
+    NumberExpression, ParenthesesClipExpression, PingPongClipExpression, ReferenceClipExpression,
+    RestartExpression, ReverseClipExpression, SpeedClipExpression, TruncatedClipExpression,
+};
+
+use super::{ReduceError, ReduceErrorKind};
+
+/// Replaces the calls of parameterized clips in `clip` by their bodies.
+/// `bindings` holds the arguments for the parameters of the clip whose body
+/// `clip` is. Arguments are expanded in the scope of the caller before they
+/// are bound, so the parameters of a body never capture the caller's names.
+pub fn expand_clip_expression(
+    clip: &ClipExpression,
+    clip_declarations: &HashMap<String, &ClipDeclaration>,
+    bindings: &HashMap<String, ArgumentExpression>,
+    stack: &mut Vec<String>,
+) -> Result<ClipExpression, ReduceError> {
+    let expand = |clip: &ClipExpression, stack: &mut Vec<String>| {
+        expand_clip_expression(clip, clip_declarations, bindings, stack).map(Box::new)
+    };
+    Ok(match clip {
+        ClipExpression::Empty | ClipExpression::RawVideo(_) | ClipExpression::MultiVideo(_) => {
+            clip.clone()
+        }
+        ClipExpression::Reference(ReferenceClipExpression { name, span }) => {
+            if let Some(argument) = bindings.get(name) {
+                argument_as_clip(name, argument, span)?
+            } else {
+                match clip_declarations.get(name) {
+                    Some(declaration) if !declaration.parameters.is_empty() => {
+                        return Err(ReduceErrorKind::ArgumentCount {
+                            name: name.clone(),
+                            expected: declaration.parameters.len(),
+                            found: 0,
+                        }
+                        .at(*span))
+                    }
+                    _ => clip.clone(),
+                }
+            }
+        }
+        ClipExpression::Call(CallClipExpression {
+            name,
+            arguments,
+            span,
+        }) => {
+            let declaration = clip_declarations
+                .get(name)
+                .ok_or_else(|| ReduceErrorKind::UnknownClip(name.clone()).at(*span))?;
+            if declaration.parameters.len() != arguments.len() {
+                return Err(ReduceErrorKind::ArgumentCount {
+                    name: name.clone(),
+                    expected: declaration.parameters.len(),
+                    found: arguments.len(),
+                }
+                .at(*span));
+            }
+            if stack.contains(name) {
+                return Err(ReduceErrorKind::RecursiveCall(name.clone()).at(*span));
+            }
+            let arguments = arguments
+                .iter()
+                .map(|argument| {
+                    expand_argument_expression(argument, clip_declarations, bindings, stack)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let bindings = declaration
+                .parameters
+                .iter()
+                .cloned()
+                .zip(arguments)
+                .collect();
+            stack.push(name.clone());
+            let body = expand_clip_expression(
+                &declaration.expression,
+                clip_declarations,
+                &bindings,
+                stack,
+            );
+            stack.pop();
+            ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression {
+                clip: Box::new(body?),
+            })
+        }
+        ClipExpression::Chain(ClipChainExpression { clip_a, clip_b }) => {
+            ClipExpression::Chain(ClipChainExpression {
+                clip_a: expand(clip_a, stack)?,
+                clip_b: expand(clip_b, stack)?,
+            })
+        }
+        ClipExpression::Layer(ClipLayerExpression { clip_a, clip_b }) => {
+            ClipExpression::Layer(ClipLayerExpression {
+                clip_a: expand(clip_a, stack)?,
+                clip_b: expand(clip_b, stack)?,
+            })
+        }
+        ClipExpression::Loop(ClipLoopExpression {
+            clip,
+            repetitions,
+            span,
+        }) => ClipExpression::Loop(ClipLoopExpression {
+            clip: expand(clip, stack)?,
+            repetitions: NumberExpression::Literal(
+                expand_number_expression(repetitions, bindings).map_err(|e| e.kind.at(*span))?,
+            ),
+            span: *span,
+        }),
+        ClipExpression::Truncated(TruncatedClipExpression {
+            clip,
+            timerange,
+            span,
+        }) => ClipExpression::Truncated(TruncatedClipExpression {
+            clip: expand(clip, stack)?,
+            timerange: timerange.clone(),
+            span: *span,
+        }),
+        ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression { clip }) => {
+            ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression {
+                clip: expand(clip, stack)?,
+            })
+        }
+        ClipExpression::Reverse(ReverseClipExpression { clip }) => {
+            ClipExpression::Reverse(ReverseClipExpression {
+                clip: expand(clip, stack)?,
+            })
+        }
+        ClipExpression::Hold(HoldClipExpression { clip }) => {
+            ClipExpression::Hold(HoldClipExpression {
+                clip: expand(clip, stack)?,
+            })
+        }
+        ClipExpression::Fit(FitClipExpression {
+            clip,
+            start,
+            end,
+            beats,
+            span,
+        }) => ClipExpression::Fit(FitClipExpression {
+            clip: expand(clip, stack)?,
+            start: *start,
+            end: *end,
+            beats: NumberExpression::Literal(
+                expand_number_expression(beats, bindings).map_err(|e| e.kind.at(*span))?,
+            ),
+            span: *span,
+        }),
+        ClipExpression::PingPong(PingPongClipExpression { clip, start, end }) => {
+            ClipExpression::PingPong(PingPongClipExpression {
+                clip: expand(clip, stack)?,
+                start: *start,
+                end: *end,
+            })
+        }
+        ClipExpression::Speed(SpeedClipExpression {
+            clip,
+            numerator,
+            denominator,
+        }) => ClipExpression::Speed(SpeedClipExpression {
+            clip: expand(clip, stack)?,
+            numerator: *numerator,
+            denominator: *denominator,
+        }),
+        ClipExpression::Choice(ChoiceClipExpression { alternatives }) => {
+            ClipExpression::Choice(ChoiceClipExpression {
+                alternatives: alternatives
+                    .iter()
+                    .map(|alternative| {
+                        Ok(ChoiceAlternative {
+                            clip: *expand(&alternative.clip, stack)?,
+                            weight: alternative.weight,
+                        })
+                    })
+                    .collect::<Result<_, ReduceError>>()?,
+            })
+        }
+        ClipExpression::Restart(RestartExpression {
+            beat_expression,
+            clip_expression,
+        }) => ClipExpression::Restart(RestartExpression {
+            beat_expression: expand_beat_expression(beat_expression, bindings)?,
+            clip_expression: expand(clip_expression, stack)?,
+        }),
+        ClipExpression::ApplyBeat(ApplyBeatExpression {
+            beat_expression,
+            clip_expression,
+        }) => ClipExpression::ApplyBeat(ApplyBeatExpression {
+            beat_expression: expand_beat_expression(beat_expression, bindings)?,
+            clip_expression: expand(clip_expression, stack)?,
+        }),
+    })
+}
+
+fn expand_argument_expression(
+    argument: &ArgumentExpression,
+    clip_declarations: &HashMap<String, &ClipDeclaration>,
+    bindings: &HashMap<String, ArgumentExpression>,
+    stack: &mut Vec<String>,
+) -> Result<ArgumentExpression, ReduceError> {
+    Ok(match argument {
+        ArgumentExpression::Number(_) => argument.clone(),
+        // a lone parameter is passed on with its kind intact
+        ArgumentExpression::Clip(ClipExpression::Reference(ReferenceClipExpression {
+            name,
+            ..
+        })) if bindings.contains_key(name) => bindings[name].clone(),
+        // without a clip called `e`, `e(5, 16)` is a Euclidean beat
+        ArgumentExpression::Clip(ClipExpression::Call(call))
+            if !clip_declarations.contains_key(&call.name) && call_as_euclidean(call).is_some() =>
+        {
+            let beat = call_as_euclidean(call).expect("checked by the guard");
+            ArgumentExpression::Beat(expand_beat_expression(&beat, bindings)?)
+        }
+        ArgumentExpression::Clip(clip) => ArgumentExpression::Clip(expand_clip_expression(
+            clip,
+            clip_declarations,
+            bindings,
+            stack,
+        )?),
+        ArgumentExpression::Beat(beat) => {
+            ArgumentExpression::Beat(expand_beat_expression(beat, bindings)?)
+        }
+    })
+}
+
+/// Reads a call like `e(5, 16)` as the Euclidean beat it looks like.
+fn call_as_euclidean(call: &CallClipExpression) -> Option<BeatExpression> {
+    if call.name != "e" {
+        return None;
+    }
+    let numbers: Vec<NumberExpression> = call
+        .arguments
+        .iter()
+        .map(|argument| match argument {
+            ArgumentExpression::Number(number) => Some(NumberExpression::Literal(*number)),
+            ArgumentExpression::Clip(ClipExpression::Reference(ReferenceClipExpression {
+                name,
+                ..
+            })) => Some(NumberExpression::Parameter(name.clone())),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    let mut numbers = numbers.into_iter();
+    let beat = EuclideanBeatExpression {
+        hits: numbers.next()?,
+        steps: numbers.next()?,
+        rotation: numbers.next().unwrap_or(NumberExpression::Literal(0)),
+        span: call.span,
+    };
+    if numbers.next().is_some() {
+        return None;
+    }
+    Some(BeatExpression::EuclideanBeatExpression(beat))
+}
+
+/// Substitutes the parameters in `beat`.
+pub fn expand_beat_expression(
+    beat: &BeatExpression,
+    bindings: &HashMap<String, ArgumentExpression>,
+) -> Result<BeatExpression, ReduceError> {
+    Ok(match beat {
+        BeatExpression::ReferenceBeatExpression(ReferenceBeatExpression { name, span }) => {
+            match bindings.get(name) {
+                Some(argument) => argument_as_beat(name, argument, span)?,
+                None => beat.clone(),
+            }
+        }
+        BeatExpression::BeatChainExpression(BeatChainExpression { beat_a, beat_b }) => {
+            BeatExpression::BeatChainExpression(BeatChainExpression {
+                beat_a: Box::new(expand_beat_expression(beat_a, bindings)?),
+                beat_b: Box::new(expand_beat_expression(beat_b, bindings)?),
+            })
+        }
+        BeatExpression::UnionBeatExpression(UnionBeatExpression { beat_a, beat_b }) => {
+            BeatExpression::UnionBeatExpression(UnionBeatExpression {
+                beat_a: Box::new(expand_beat_expression(beat_a, bindings)?),
+                beat_b: Box::new(expand_beat_expression(beat_b, bindings)?),
+            })
+        }
+        BeatExpression::IntersectionBeatExpression(IntersectionBeatExpression {
+            beat_a,
+            beat_b,
+        }) => BeatExpression::IntersectionBeatExpression(IntersectionBeatExpression {
+            beat_a: Box::new(expand_beat_expression(beat_a, bindings)?),
+            beat_b: Box::new(expand_beat_expression(beat_b, bindings)?),
+        }),
+        BeatExpression::InvertedBeatExpression(InvertedBeatExpression { beat }) => {
+            BeatExpression::InvertedBeatExpression(InvertedBeatExpression {
+                beat: Box::new(expand_beat_expression(beat, bindings)?),
+            })
+        }
+        BeatExpression::RotatedBeatExpression(RotatedBeatExpression { beat, steps }) => {
+            BeatExpression::RotatedBeatExpression(RotatedBeatExpression {
+                beat: Box::new(expand_beat_expression(beat, bindings)?),
+                steps: NumberExpression::Literal(expand_number_expression(steps, bindings)?),
+            })
+        }
+        BeatExpression::RepeatedBeatExpression(RepeatedBeatExpression { beat, repetitions }) => {
+            BeatExpression::RepeatedBeatExpression(RepeatedBeatExpression {
+                beat: Box::new(expand_beat_expression(beat, bindings)?),
+                repetitions: NumberExpression::Literal(expand_number_expression(
+                    repetitions,
+                    bindings,
+                )?),
+            })
+        }
+        BeatExpression::ParenthesesBeatExpression(ParenthesesBeatExpression { beat }) => {
+            BeatExpression::ParenthesesBeatExpression(ParenthesesBeatExpression {
+                beat: Box::new(expand_beat_expression(beat, bindings)?),
+            })
+        }
+        BeatExpression::EuclideanBeatExpression(EuclideanBeatExpression {
+            hits,
+            steps,
+            rotation,
+            span,
+        }) => BeatExpression::EuclideanBeatExpression(EuclideanBeatExpression {
+            hits: NumberExpression::Literal(expand_number_expression(hits, bindings)?),
+            steps: NumberExpression::Literal(expand_number_expression(steps, bindings)?),
+            rotation: NumberExpression::Literal(expand_number_expression(rotation, bindings)?),
+            span: *span,
+        }),
+        BeatExpression::DotBeatExpression(_) | BeatExpression::NumberBeatExpression(_) => {
+            beat.clone()
+        }
+    })
+}
+
+fn expand_number_expression(
+    number: &NumberExpression,
+    bindings: &HashMap<String, ArgumentExpression>,
+) -> Result<usize, ReduceError> {
+    match number {
+        NumberExpression::Literal(number) => Ok(*number),
+        NumberExpression::Parameter(name) => match bindings.get(name) {
+            Some(ArgumentExpression::Number(number)) => Ok(*number),
+            Some(_) => Err(ReduceErrorKind::ArgumentKind {
+                parameter: name.clone(),
+                expected: "number",
+            }
+            .into()),
+            None => Err(ReduceErrorKind::UnknownParameter(name.clone()).into()),
+        },
+    }
+}
+
+fn argument_as_clip(
+    parameter: &str,
+    argument: &ArgumentExpression,
+    span: &Span,
+) -> Result<ClipExpression, ReduceError> {
+    match argument {
+        ArgumentExpression::Clip(clip) => Ok(ClipExpression::ParenthesesClipExpression(
+            ParenthesesClipExpression {
+                clip: Box::new(clip.clone()),
+            },
+        )),
+        _ => Err(ReduceErrorKind::ArgumentKind {
+            parameter: parameter.into(),
+            expected: "clip",
+        }
+        .at(*span)),
+    }
+}
+
+/// Names and `-` are parsed as clips and numbers as numbers, but read the
+/// same as beats.
+fn argument_as_beat(
+    parameter: &str,
+    argument: &ArgumentExpression,
+    span: &Span,
+) -> Result<BeatExpression, ReduceError> {
+    match argument {
+        ArgumentExpression::Beat(beat) => Ok(beat.clone()),
+        ArgumentExpression::Number(number) => {
+            Ok(BeatExpression::NumberBeatExpression(NumberBeatExpression {
+                beats: number
+                    .to_string()
+                    .chars()
+                    .filter_map(|digit| digit.to_digit(10))
+                    .map(|digit| digit as usize)
+                    .collect(),
+                resolution: DEFAULT_RESOLUTION,
+            }))
+        }
+        ArgumentExpression::Clip(clip) => clip_as_beat(clip).ok_or_else(|| {
+            ReduceErrorKind::ArgumentKind {
+                parameter: parameter.into(),
+                expected: "beat",
+            }
+            .at(*span)
+        }),
+    }
+}
+
+/// Reads a clip argument that is written like a beat, such as `a | -` or
+/// `x * 2`, as that beat.
+fn clip_as_beat(clip: &ClipExpression) -> Option<BeatExpression> {
+    Some(match clip {
+        ClipExpression::Reference(ReferenceClipExpression { name, span }) => {
+            BeatExpression::ReferenceBeatExpression(ReferenceBeatExpression {
+                name: name.clone(),
+                span: *span,
+            })
+        }
+        ClipExpression::Empty => BeatExpression::DotBeatExpression(DotBeatExpression {
+            beats: vec![false],
+            resolution: DEFAULT_RESOLUTION,
+        }),
+        ClipExpression::Chain(ClipChainExpression { clip_a, clip_b }) => {
+            BeatExpression::BeatChainExpression(BeatChainExpression {
+                beat_a: Box::new(clip_as_beat(clip_a)?),
+                beat_b: Box::new(clip_as_beat(clip_b)?),
+            })
+        }
+        ClipExpression::Loop(ClipLoopExpression {
+            clip, repetitions, ..
+        }) => BeatExpression::RepeatedBeatExpression(RepeatedBeatExpression {
+            beat: Box::new(clip_as_beat(clip)?),
+            repetitions: repetitions.clone(),
+        }),
+        ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression { clip }) => {
+            BeatExpression::ParenthesesBeatExpression(ParenthesesBeatExpression {
+                beat: Box::new(clip_as_beat(clip)?),
+            })
+        }
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        reducer::{
+            tests::{errors, program},
+            ReducedClip,
+        },
+        rng::Rng,
+    };
+
+    fn take(source: &str) -> ReducedClip {
+        program(source).take(&mut Rng::new(0)).unwrap()
+    }
+
+    #[test]
+    fn calls_are_replaced_by_their_bodies() {
+        assert_eq!(
+            take("clip stutter(x, b) = b >> x[1:2] | x;\nstutter('a', .-.-)"),
+            take(".-.- >> 'a'[1:2] | 'a'")
+        );
+        assert_eq!(
+            take("clip n(x, k) = x * k;\nn('a' | 'b', 3)"),
+            take("('a' | 'b') * 3")
+        );
+        assert_eq!(
+            take("clip on(b, x) = b @ x;\non(1011, 'a') | on(e(3, 8), 'b')"),
+            take("1011 @ 'a' | e(3, 8) @ 'b'")
+        );
+        assert_eq!(
+            take("beat x = ..-.;\nclip on(b) = b @ 'a';\non(x | -)"),
+            take("..-. | - @ 'a'")
+        );
+    }
+
+    #[test]
+    fn parameters_are_scoped_to_their_clip() {
+        // a parameter hides a clip of its name in the body only
+        assert_eq!(
+            take("clip x = 'g';\nclip f(x) = x | 'f';\nf('a') | x"),
+            take("'a' | 'f' | 'g'")
+        );
+        // arguments are expanded where the call is, not in the body called
+        assert_eq!(
+            take(
+                "clip inner(x, y) = y | x;\n\
+                 clip outer(x, y) = inner(x | 'o', y);\n\
+                 outer('a', 'b')"
+            ),
+            take("'b' | 'a' | 'o'")
+        );
+        assert_eq!(
+            take("clip f(n) = 'a' * n;\nclip g(n) = f(n) | f(1);\ng(2)"),
+            take("'a' * 2 | 'a'")
+        );
+    }
+
+    #[test]
+    fn wrong_arguments_are_errors() {
+        assert_eq!(
+            errors("clip f(x) = x;\nf('a', 'b')"),
+            ["clip f takes 1 arguments, but 2 were given"]
+        );
+        assert_eq!(
+            errors("clip f(x, y) = x | y;\nf | 'a'"),
+            ["clip f takes 2 arguments, but 0 were given"]
+        );
+        assert_eq!(
+            errors("clip f(n) = 'a' * n;\nf('b')"),
+            ["the argument for parameter n cannot be used as a number"]
+        );
+        assert_eq!(
+            errors("clip f(x) = x | 'a';\nf(2)"),
+            ["the argument for parameter x cannot be used as a clip"]
+        );
+        assert_eq!(
+            errors("clip f(b) = b @ 'a';\nf('b' : 'c')"),
+            ["the argument for parameter b cannot be used as a beat"]
+        );
+        assert_eq!(errors("g('a')"), ["unknown clip g"]);
+        assert_eq!(
+            errors("clip f(x) = 'a' | f(x);\nf('b')"),
+            ["clip f calls itself"]
+        );
+    }
+}
@@ -0,0 +1,634 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::{
+    parser::{
+        beats_parser::{
+            BeatChainExpression, BeatExpression, IntersectionBeatExpression,
+            InvertedBeatExpression, ParenthesesBeatExpression, ReferenceBeatExpression,
+            RepeatedBeatExpression, RotatedBeatExpression, UnionBeatExpression,
+        },
+        span::Span,
+        ApplyBeatExpression, ArgumentExpression, CallClipExpression, ChoiceClipExpression,
+        ClipChainExpression, ClipExpression, ClipLayerExpression, ClipLoopExpression, Declaration,
+        FitClipExpression, HoldClipExpression, NumberExpression, ParenthesesClipExpression,
+        PingPongClipExpression, ReferenceClipExpression, RestartExpression, ReverseClipExpression,
+        SpeedClipExpression, TruncatedClipExpression,
+    },
+    time::Time,
+    util::{bar_length, time_expression_to_time},
+};
+
+use super::{reduce_beat_expression, whole, Program, ReduceError, ReduceErrorKind, ReducedBeat};
+
+/// A name an expression refers to, with the kind of declaration it needs.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum Name<'a> {
+    Clip(&'a str),
+    Beat(&'a str),
+}
+
+impl<'a> Name<'a> {
+    fn as_str(&self) -> &'a str {
+        match self {
+            Name::Clip(name) | Name::Beat(name) => name,
+        }
+    }
+}
+
+/// Clips and beats share one namespace, scenes have one of their own. Each
+/// name declared again is reported where it is.
+pub fn check_collisions(declarations: &[Declaration]) -> Vec<ReduceError> {
+    let mut names = HashSet::new();
+    let mut scene_names = HashSet::new();
+    let mut problems = Vec::new();
+    for declaration in declarations {
+        let (declared, name, span) = match declaration {
+            Declaration::ClipDeclaration(cd) => (&mut names, &cd.name, cd.span),
+            Declaration::BeatDeclaration(bd) => (&mut names, &bd.name, bd.span),
+            Declaration::SceneDeclaration(sd) => (&mut scene_names, &sd.name, sd.span),
+            Declaration::ImportDeclaration(_) => continue,
+        };
+        if !declared.insert(name) {
+            problems.push(ReduceErrorKind::NameCollision(name.clone()).at(span));
+        }
+    }
+    problems
+}
+
+/// Makes sure every name in the expanded `program` is declared as the kind
+/// it is used as, and that no clip or beat is defined in terms of itself.
+/// Reducing relies on both.
+pub fn check_references(program: &Program) -> Vec<ReduceError> {
+    let mut beat_names: Vec<&String> = program.beats.keys().collect();
+    beat_names.sort();
+    let nodes: Vec<Name> = program
+        .clip_names
+        .iter()
+        .map(|name| Name::Clip(name))
+        .chain(beat_names.into_iter().map(|name| Name::Beat(name)))
+        .collect();
+    let mut names = Vec::new();
+    for node in &nodes {
+        names.extend(dependencies(program, *node));
+    }
+    for clip in program
+        .main_expression
+        .iter()
+        .chain(program.scenes.iter().map(|(_, scene)| scene))
+    {
+        names_in_clip(clip, &mut names);
+    }
+    let mut problems = Vec::new();
+    // the body of a clip with parameters is in the program once per call
+    let mut reported = HashSet::new();
+    for (name, span) in names {
+        let kind = match name {
+            Name::Clip(name) if !program.clips.contains_key(name) => {
+                ReduceErrorKind::UnknownClip(name.into())
+            }
+            Name::Beat(name) if !program.beats.contains_key(name) => {
+                ReduceErrorKind::UnknownBeat(name.into())
+            }
+            _ => continue,
+        };
+        if reported.insert(span) {
+            problems.push(kind.at(span));
+        }
+    }
+    if !problems.is_empty() {
+        return problems;
+    }
+    let mut finished = HashSet::new();
+    for node in nodes {
+        if let Err(e) = visit(program, node, None, &mut Vec::new(), &mut finished) {
+            // one cycle is reported from every name along it
+            problems.push(e);
+            break;
+        }
+    }
+    problems
+}
+
+fn dependencies<'a>(program: &'a Program, name: Name<'a>) -> Vec<(Name<'a>, Span)> {
+    let mut names = Vec::new();
+    match name {
+        Name::Clip(name) => names_in_clip(&program.clips[name], &mut names),
+        Name::Beat(name) => names_in_beat(&program.beats[name], &mut names),
+    }
+    names
+}
+
+/// A depth first search that fails on the first name it meets again while
+/// following the names it depends on, at the reference that closes the
+/// cycle.
+fn visit<'a>(
+    program: &'a Program,
+    name: Name<'a>,
+    span: Option<Span>,
+    path: &mut Vec<Name<'a>>,
+    finished: &mut HashSet<Name<'a>>,
+) -> Result<(), ReduceError> {
+    if finished.contains(&name) {
+        return Ok(());
+    }
+    if let Some(start) = path.iter().position(|visited| *visited == name) {
+        return Err(ReduceError {
+            kind: ReduceErrorKind::RecursiveDefinition(
+                path[start..]
+                    .iter()
+                    .chain([&name])
+                    .map(|name| name.as_str().to_string())
+                    .collect(),
+            ),
+            span,
+        });
+    }
+    path.push(name);
+    for (dependency, span) in dependencies(program, name) {
+        visit(program, dependency, Some(span), path, finished)?;
+    }
+    path.pop();
+    finished.insert(name);
+    Ok(())
+}
+
+/// Finds every length the clips of `program` can have, over all the
+/// alternatives of their choices, without rolling any. Whether a range fits
+/// the clip it slices depends on that length, so this finds each range that
+/// would not fit in some take. `clip_spans` has where each clip is declared,
+/// and `loops` the main expression and scenes, which must not be empty.
+pub fn check_lengths(
+    program: &Program,
+    clip_spans: &HashMap<&str, Span>,
+    loops: &[(String, &ClipExpression, Span)],
+) -> Vec<ReduceError> {
+    let mut lengths = Lengths {
+        program,
+        clips: HashMap::new(),
+        beats: HashMap::new(),
+    };
+    let mut problems: Vec<ReduceError> = Vec::new();
+    // an error in a clip shows again in every clip that refers to it
+    let mut report = |e: ReduceError, span: Span| {
+        let e = ReduceError {
+            span: e.span.or(Some(span)),
+            ..e
+        };
+        if problems.iter().all(|problem| problem.span != e.span) {
+            problems.push(e);
+        }
+    };
+    for name in &program.clip_names {
+        if let Err(e) = lengths.of_clip(name) {
+            report(e, clip_spans[name.as_str()]);
+        }
+    }
+    for (what, clip, span) in loops {
+        match lengths.of(clip) {
+            Ok(possible) if possible.contains(&Time::zero()) => report(
+                ReduceErrorKind::ZeroLengthLoop(what.clone()).at(*span),
+                *span,
+            ),
+            Ok(_) => (),
+            Err(e) => report(e, *span),
+        }
+    }
+    problems
+}
+
+/// The lengths found so far, of the clips by name, and the beats, which are
+/// the same in every take.
+struct Lengths<'a> {
+    program: &'a Program,
+    clips: HashMap<&'a str, BTreeSet<Time>>,
+    beats: HashMap<String, ReducedBeat>,
+}
+
+impl<'a> Lengths<'a> {
+    fn of_clip(&mut self, name: &'a str) -> Result<BTreeSet<Time>, ReduceError> {
+        if let Some(clip) = self.program.fixed.get(name) {
+            return Ok(BTreeSet::from([clip.length]));
+        }
+        if let Some(lengths) = self.clips.get(name) {
+            return Ok(lengths.clone());
+        }
+        let lengths = self.of(&self.program.clips[name])?;
+        self.clips.insert(name, lengths.clone());
+        Ok(lengths)
+    }
+
+    /// Follows the reduction of `clip` with every length a part of it can
+    /// have in place of the one it has in a take.
+    fn of(&mut self, clip: &'a ClipExpression) -> Result<BTreeSet<Time>, ReduceError> {
+        let meter = &self.program.settings.meter;
+        match clip {
+            ClipExpression::Empty | ClipExpression::RawVideo(_) | ClipExpression::MultiVideo(_) => {
+                Ok(BTreeSet::from([bar_length(meter)]))
+            }
+            ClipExpression::Reference(ReferenceClipExpression { name, .. }) => self.of_clip(name),
+            ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression { clip })
+            | ClipExpression::Reverse(ReverseClipExpression { clip })
+            | ClipExpression::Hold(HoldClipExpression { clip })
+            | ClipExpression::Speed(SpeedClipExpression { clip, .. })
+            | ClipExpression::PingPong(PingPongClipExpression { clip, .. }) => self.of(clip),
+            // the beat only switches multi videos, but must reduce all the same
+            ClipExpression::ApplyBeat(ApplyBeatExpression {
+                beat_expression,
+                clip_expression,
+            }) => {
+                reduce_beat_expression(beat_expression, &self.program.beats, &mut self.beats)?;
+                self.of(clip_expression)
+            }
+            ClipExpression::Chain(ClipChainExpression { clip_a, clip_b }) => {
+                let lengths_a = self.of(clip_a)?;
+                let lengths_b = self.of(clip_b)?;
+                let mut lengths = BTreeSet::new();
+                for a in &lengths_a {
+                    for b in &lengths_b {
+                        lengths.insert(a.checked_add(*b).ok_or(ReduceErrorKind::Overflow)?);
+                    }
+                }
+                Ok(lengths)
+            }
+            ClipExpression::Layer(ClipLayerExpression { clip_a, clip_b }) => {
+                let lengths_a = self.of(clip_a)?;
+                let lengths_b = self.of(clip_b)?;
+                Ok(lengths_a
+                    .iter()
+                    .flat_map(|a| lengths_b.iter().map(move |b| *a.max(b)))
+                    .collect())
+            }
+            ClipExpression::Loop(ClipLoopExpression {
+                clip,
+                repetitions: NumberExpression::Literal(repetitions),
+                ..
+            }) => {
+                let repetitions = whole(*repetitions)?;
+                self.of(clip)?
+                    .into_iter()
+                    .map(|length| {
+                        length
+                            .checked_mul(repetitions)
+                            .ok_or(ReduceErrorKind::Overflow.into())
+                    })
+                    .collect()
+            }
+            ClipExpression::Truncated(TruncatedClipExpression {
+                clip,
+                timerange,
+                span,
+            }) => {
+                let lengths = self.of(clip).map_err(|e| ReduceError {
+                    span: e.span.or(Some(*span)),
+                    ..e
+                })?;
+                lengths
+                    .into_iter()
+                    .map(|length| {
+                        let overflow = || ReduceErrorKind::Overflow.at(*span);
+                        let position = |time| {
+                            time_expression_to_time(time, meter, &length).ok_or_else(overflow)
+                        };
+                        let start = timerange.from.as_ref().map_or(Ok(Time::zero()), position)?;
+                        let end = timerange.to.as_ref().map_or(Ok(length), position)?;
+                        let beats = length
+                            .checked_mul(Time::from(meter.unit as i64))
+                            .ok_or_else(overflow)?;
+                        if start < Time::zero() {
+                            return Err(ReduceErrorKind::RangeBeforeStart {
+                                range: timerange.to_string(),
+                                beats,
+                            }
+                            .at(*span));
+                        }
+                        if end <= start {
+                            return Err(ReduceErrorKind::EmptyRange {
+                                range: timerange.to_string(),
+                                beats,
+                            }
+                            .at(*span));
+                        }
+                        Ok(end - start)
+                    })
+                    .collect()
+            }
+            ClipExpression::Fit(FitClipExpression {
+                clip,
+                beats: NumberExpression::Literal(beats),
+                span,
+                ..
+            }) => {
+                self.of(clip)?;
+                if *beats == 0 {
+                    return Err(ReduceErrorKind::ZeroLengthFit.at(*span));
+                }
+                let length = whole(*beats)?
+                    .checked_div(Time::from(meter.unit as i64))
+                    .ok_or(ReduceErrorKind::Overflow.at(*span))?;
+                Ok(BTreeSet::from([length]))
+            }
+            // the clip is cut at every hit, and the pieces from the first hit
+            // on are played, or the clip as it is without one
+            ClipExpression::Restart(RestartExpression {
+                beat_expression,
+                clip_expression,
+            }) => {
+                let lengths = self.of(clip_expression)?;
+                let beat =
+                    reduce_beat_expression(beat_expression, &self.program.beats, &mut self.beats)?;
+                Ok(match beat.beats.first() {
+                    Some(first) => BTreeSet::from([beat.length - first]),
+                    None => lengths,
+                })
+            }
+            ClipExpression::Choice(ChoiceClipExpression { alternatives }) => {
+                let mut lengths = BTreeSet::new();
+                for alternative in alternatives {
+                    lengths.extend(self.of(&alternative.clip)?);
+                }
+                Ok(lengths)
+            }
+            ClipExpression::Loop(_) | ClipExpression::Fit(_) | ClipExpression::Call(_) => {
+                unreachable!("calls and parameters are expanded before reduction")
+            }
+        }
+    }
+}
+
+/// The clips and beats that neither the main expression nor any scene ends
+/// up using, in the order they are declared in. Declarations imported with
+/// a namespace are left out, since a library is rarely used completely.
+pub fn unused_declarations(
+    declarations: &[Declaration],
+    main_expression: Option<&ClipExpression>,
+) -> Vec<ReduceError> {
+    let mut dependencies: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut used = Vec::new();
+    for declaration in declarations {
+        let mut names = Vec::new();
+        match declaration {
+            Declaration::ClipDeclaration(cd) => {
+                names_in_clip(&cd.expression, &mut names);
+                let names = names
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .filter(|name| !cd.parameters.iter().any(|parameter| parameter == name))
+                    .collect();
+                dependencies.insert(&cd.name, names);
+            }
+            Declaration::BeatDeclaration(bd) => {
+                names_in_beat(&bd.expression, &mut names);
+                dependencies.insert(
+                    &bd.name,
+                    names.iter().map(|(name, _)| name.as_str()).collect(),
+                );
+            }
+            Declaration::SceneDeclaration(sd) => {
+                names_in_clip(&sd.expression, &mut names);
+                used.extend(names.iter().map(|(name, _)| name.as_str()));
+            }
+            Declaration::ImportDeclaration(_) => (),
+        }
+    }
+    if let Some(main_expression) = main_expression {
+        let mut names = Vec::new();
+        names_in_clip(main_expression, &mut names);
+        used.extend(names.iter().map(|(name, _)| name.as_str()));
+    }
+    let mut reached = HashSet::new();
+    while let Some(name) = used.pop() {
+        if reached.insert(name) {
+            used.extend(dependencies.get(name).into_iter().flatten());
+        }
+    }
+    declarations
+        .iter()
+        .filter_map(|declaration| match declaration {
+            Declaration::ClipDeclaration(cd) => Some((&cd.name, cd.span)),
+            Declaration::BeatDeclaration(bd) => Some((&bd.name, bd.span)),
+            _ => None,
+        })
+        .filter(|(name, _)| !name.contains('.') && !reached.contains(name.as_str()))
+        .map(|(name, span)| ReduceErrorKind::Unused(name.clone()).at(span))
+        .collect()
+}
+
+/// A clip passed as an argument is collected as a clip even where the
+/// parameter is used as a beat.
+fn names_in_clip<'a>(clip: &'a ClipExpression, names: &mut Vec<(Name<'a>, Span)>) {
+    match clip {
+        ClipExpression::Empty | ClipExpression::RawVideo(_) | ClipExpression::MultiVideo(_) => (),
+        ClipExpression::Reference(ReferenceClipExpression { name, span }) => {
+            names.push((Name::Clip(name), *span))
+        }
+        ClipExpression::Call(CallClipExpression {
+            name,
+            arguments,
+            span,
+        }) => {
+            names.push((Name::Clip(name), *span));
+            for argument in arguments {
+                match argument {
+                    ArgumentExpression::Clip(clip) => names_in_clip(clip, names),
+                    ArgumentExpression::Beat(beat) => names_in_beat(beat, names),
+                    ArgumentExpression::Number(_) => (),
+                }
+            }
+        }
+        ClipExpression::Chain(ClipChainExpression { clip_a, clip_b })
+        | ClipExpression::Layer(ClipLayerExpression { clip_a, clip_b }) => {
+            names_in_clip(clip_a, names);
+            names_in_clip(clip_b, names);
+        }
+        ClipExpression::Loop(ClipLoopExpression { clip, .. })
+        | ClipExpression::Truncated(TruncatedClipExpression { clip, .. })
+        | ClipExpression::ParenthesesClipExpression(ParenthesesClipExpression { clip })
+        | ClipExpression::Reverse(ReverseClipExpression { clip })
+        | ClipExpression::Hold(HoldClipExpression { clip })
+        | ClipExpression::Fit(FitClipExpression { clip, .. })
+        | ClipExpression::PingPong(PingPongClipExpression { clip, .. })
+        | ClipExpression::Speed(SpeedClipExpression { clip, .. }) => names_in_clip(clip, names),
+        ClipExpression::Choice(ChoiceClipExpression { alternatives }) => {
+            for alternative in alternatives {
+                names_in_clip(&alternative.clip, names);
+            }
+        }
+        ClipExpression::Restart(RestartExpression {
+            beat_expression,
+            clip_expression,
+        })
+        | ClipExpression::ApplyBeat(ApplyBeatExpression {
+            beat_expression,
+            clip_expression,
+        }) => {
+            names_in_beat(beat_expression, names);
+            names_in_clip(clip_expression, names);
+        }
+    }
+}
+
+fn names_in_beat<'a>(beat: &'a BeatExpression, names: &mut Vec<(Name<'a>, Span)>) {
+    match beat {
+        BeatExpression::ReferenceBeatExpression(ReferenceBeatExpression { name, span }) => {
+            names.push((Name::Beat(name), *span))
+        }
+        BeatExpression::BeatChainExpression(BeatChainExpression { beat_a, beat_b })
+        | BeatExpression::UnionBeatExpression(UnionBeatExpression { beat_a, beat_b })
+        | BeatExpression::IntersectionBeatExpression(IntersectionBeatExpression {
+            beat_a,
+            beat_b,
+        }) => {
+            names_in_beat(beat_a, names);
+            names_in_beat(beat_b, names);
+        }
+        BeatExpression::InvertedBeatExpression(InvertedBeatExpression { beat })
+        | BeatExpression::RotatedBeatExpression(RotatedBeatExpression { beat, .. })
+        | BeatExpression::RepeatedBeatExpression(RepeatedBeatExpression { beat, .. })
+        | BeatExpression::ParenthesesBeatExpression(ParenthesesBeatExpression { beat }) => {
+            names_in_beat(beat, names)
+        }
+        BeatExpression::EuclideanBeatExpression(_)
+        | BeatExpression::DotBeatExpression(_)
+        | BeatExpression::NumberBeatExpression(_) => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::reducer::{
+        tests::{reduced, source},
+        ReduceError, ReduceErrorKind,
+    };
+
+    /// What reducing `declarations` reports, errors and warnings other than
+    /// missing files, each with the name it points at.
+    fn problems(declarations: &str) -> Vec<(String, String)> {
+        let source = source(declarations);
+        let describe = |problem: &ReduceError| {
+            if let ReduceErrorKind::MissingFile(_) = problem.kind {
+                return None;
+            }
+            let span = problem.span.unwrap();
+            Some((
+                problem.to_string(),
+                source[span.start..span.end].to_string(),
+            ))
+        };
+        match reduced(declarations) {
+            Ok(program) => program.warnings().iter().filter_map(describe).collect(),
+            Err(problems) => problems.iter().filter_map(describe).collect(),
+        }
+    }
+
+    fn messages(source: &str) -> Vec<String> {
+        problems(source)
+            .into_iter()
+            .map(|(message, _)| message)
+            .collect()
+    }
+
+    #[test]
+    fn unknown_names_are_errors() {
+        assert_eq!(
+            problems("'a' | b"),
+            [("unknown clip b".to_string(), "b".to_string())]
+        );
+        assert_eq!(
+            problems("clip a = 'a';\nx @ a"),
+            [("unknown beat x".to_string(), "x".to_string())]
+        );
+        // a beat is not a clip, nor a clip a beat
+        assert_eq!(messages("beat x = .-;\nx"), ["unknown clip x"]);
+        assert_eq!(messages("clip a = 'a';\na @ a"), ["unknown beat a"]);
+        // every unknown name is reported, in scenes too
+        assert_eq!(
+            messages("clip a = b | c;\nscene one = a | d;"),
+            ["unknown clip b", "unknown clip c", "unknown clip d"]
+        );
+        // parameters are only known in their own clip
+        assert_eq!(
+            messages("clip f(x) = x * 2;\nf('a') | x"),
+            ["unknown clip x"]
+        );
+    }
+
+    #[test]
+    fn recursive_definitions_are_errors() {
+        assert_eq!(
+            messages("clip a = 'a' | a;\na"),
+            ["recursive definition a -> a"]
+        );
+        assert_eq!(
+            messages("clip a = 'a' | b;\nclip b = a * 2;\na"),
+            ["recursive definition a -> b -> a"]
+        );
+        assert_eq!(
+            messages("beat x = .- | y;\nbeat y = x * 2;\nx @ 'a'"),
+            ["recursive definition x -> y -> x"]
+        );
+        // a beat a clip depends on is not a cycle
+        assert!(messages("beat x = .-;\nclip a = x @ 'a';\nclip b = x @ a;\nb").is_empty());
+    }
+
+    #[test]
+    fn unused_declarations_are_warnings() {
+        assert_eq!(
+            problems("clip a = 'a';\nbeat x = .-;\n'b'"),
+            [
+                ("a is never used".to_string(), "a".to_string()),
+                ("x is never used".to_string(), "x".to_string())
+            ]
+        );
+        // used from the main expression, a scene, or another used clip
+        assert!(
+            messages("beat x = .-;\nclip a = x @ 'a';\nclip b = a;\nscene one = b;").is_empty()
+        );
+        // a clip only used by an unused one is unused too
+        assert_eq!(
+            messages("clip a = 'a';\nclip b = a;\n'c'"),
+            ["a is never used", "b is never used"]
+        );
+    }
+
+    #[test]
+    fn clips_and_beats_share_their_names() {
+        assert_eq!(
+            problems("clip x = 'a';\nbeat x = .-;\nx"),
+            [("x is declared more than once".to_string(), "x".to_string())]
+        );
+        assert_eq!(
+            messages("clip a = 'a';\nclip a = 'b';\na"),
+            ["a is declared more than once"]
+        );
+        // scenes have names of their own
+        assert!(messages("clip a = 'a';\nscene a = a;").is_empty());
+        assert_eq!(
+            messages("scene a = 'a';\nscene a = 'b';"),
+            ["a is declared more than once"]
+        );
+    }
+
+    #[test]
+    fn euclidean_beats_of_too_many_steps_are_errors() {
+        let too_long = |at: &str| {
+            vec![(
+                "the beat is more than 65536 steps long".to_string(),
+                at.to_string(),
+            )]
+        };
+        assert_eq!(
+            problems("e(1, 10000000000) @ 'a'"),
+            too_long("e(1, 10000000000)")
+        );
+        assert_eq!(
+            problems("beat x = e( 1, 65537 );\nx >> 'a'"),
+            too_long("e( 1, 65537 )")
+        );
+        // a count passed as an argument is checked where it is used
+        assert_eq!(
+            problems("clip f(n) = e(1, n) @ 'a';\nf(10000000000)"),
+            too_long("e(1, n)")
+        );
+        assert!(problems("e(1, 65536) @ 'a'").is_empty());
+    }
+}
@@ -0,0 +1,33 @@
+/// A small splitmix64 generator. Its sequence is part of how a source plays,
+/// so the same seed has to give the same performance on every build.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Picks an index of `weights`, each with a chance proportional to its weight.
+    pub fn choose_weighted(&mut self, weights: &[usize]) -> usize {
+        let total: u64 = weights.iter().map(|weight| *weight as u64).sum();
+        let mut roll = self.next_u64() % total.max(1);
+        for (index, weight) in weights.iter().enumerate() {
+            if roll < *weight as u64 {
+                return index;
+            }
+            roll -= *weight as u64;
+        }
+        weights.len() - 1
+    }
+}
@@ -1,11 +1,13 @@
 use ffmpeg_sys_next::AVAdler;
 use filetime::FileTime;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use crate::{
-    parser::parse_main,
-    reducer::{reduce, ReducedClip},
+    importer::{ImportError, Importer},
+    parser::MeterDeclaration,
+    reducer::{reduce, Program, ReduceError},
 };
 use std::{
     collections::HashSet,
@@ -13,24 +15,41 @@ use std::{
     thread::{self, JoinHandle},
 };
 
+/// A reduced source file along with the tempo and meter it declares.
+pub struct Source {
+    pub program: Program,
+    pub bpm: Option<f64>,
+    pub meter: MeterDeclaration,
+}
+
+/// Whether a source with warnings, such as a missing file, replaces the one
+/// that is playing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarningPolicy {
+    Reload,
+    Block,
+}
+
 pub struct SourceWatcher {
-    receiver: Receiver<ReducedClip>,
+    receiver: Receiver<Source>,
     handle: JoinHandle<()>,
     path: String,
+    policy: WarningPolicy,
 }
 
 impl SourceWatcher {
-    pub fn new(path: String) -> Self {
+    pub fn new(path: String, policy: WarningPolicy) -> Self {
         let (_sender, receiver) = channel();
         let handle = thread::spawn(|| {});
         Self {
             receiver,
             handle,
             path,
+            policy,
         }
     }
 
-    pub fn get_new_interpreted(&mut self) -> Option<ReducedClip> {
+    pub fn get_new_interpreted(&mut self) -> Option<Source> {
         let mut interpreted = None;
         for i in self.receiver.try_iter() {
             interpreted = Some(i);
@@ -42,20 +61,29 @@ impl SourceWatcher {
     fn restart(&mut self) {
         if self.handle.is_finished() {
             let path = self.path.clone();
+            let policy = self.policy;
             let (sender, receiver) = channel();
             let handle = thread::spawn(move || {
-                let mut last_timestamp = FileTime::now();
+                let mut watched: Vec<(PathBuf, FileTime)> = Vec::new();
 
                 loop {
-                    let timestamp = fs::metadata(path.clone())
-                        .map(|m| FileTime::from_last_modification_time(&m))
-                        .unwrap_or(FileTime::zero());
-                    if last_timestamp != timestamp {
-                        let input = fs::read_to_string(path.clone())
-                            .map_err(|_e| ())
-                            .expect("could not find file");
-                        let _ = read_input(input, &sender);
-                        last_timestamp = timestamp;
+                    let changed = watched.is_empty()
+                        || watched
+                            .iter()
+                            .any(|(path, timestamp)| modification_time(path) != *timestamp);
+                    if changed {
+                        let mut importer = Importer::default();
+                        if let Err(e) = read_input(&mut importer, &path, policy, &sender) {
+                            eprintln!("{}", e);
+                        }
+                        watched = importer
+                            .files
+                            .into_iter()
+                            .map(|path| {
+                                let timestamp = modification_time(&path);
+                                (path, timestamp)
+                            })
+                            .collect();
                     }
                 }
             });
@@ -65,10 +93,19 @@ impl SourceWatcher {
     }
 }
 
-fn read_file_paths_in_directory(path: &str) -> HashSet<String> {
+fn modification_time(path: &Path) -> FileTime {
+    fs::metadata(path)
+        .map(|m| FileTime::from_last_modification_time(&m))
+        .unwrap_or(FileTime::zero())
+}
+
+pub fn read_file_paths_in_directory(path: &str) -> HashSet<String> {
     let mut file_paths = Vec::new();
+    let Ok(entries) = fs::read_dir(path) else {
+        return HashSet::new();
+    };
 
-    for entry in fs::read_dir(path).unwrap() {
+    for entry in entries {
         if entry.is_err() {
             continue;
         }
@@ -84,13 +121,87 @@ fn read_file_paths_in_directory(path: &str) -> HashSet<String> {
     file_paths.into_iter().collect()
 }
 
+enum SourceError {
+    Import(ImportError),
+    /// Every problem that kept the source from loading, located.
+    Reduce(Vec<String>),
+}
+
+impl From<ImportError> for SourceError {
+    fn from(e: ImportError) -> Self {
+        SourceError::Import(e)
+    }
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::Import(e) => write!(f, "{}", e),
+            SourceError::Reduce(problems) => write!(f, "{}", problems.join("\n")),
+        }
+    }
+}
+
 fn read_input(
-    input: String,
-    sender: &std::sync::mpsc::Sender<ReducedClip>,
-) -> Result<(), ()> {
-    let parsed = parse_main(&input).map_err(|_e| ())?.1;
+    importer: &mut Importer,
+    path: &str,
+    policy: WarningPolicy,
+    sender: &std::sync::mpsc::Sender<Source>,
+) -> Result<(), SourceError> {
+    let parsed = importer.load(Path::new(path))?;
     let available_files = read_file_paths_in_directory(&parsed.directory_declaration.directory);
-    let reduced = reduce(parsed, &available_files);
-    let _ = sender.send(reduced);
+    let bpm = parsed
+        .bpm_declaration
+        .as_ref()
+        .map(|declaration| declaration.bpm);
+    let meter = parsed.meter_declaration.unwrap_or_default();
+    let files = &importer.files;
+    let program = reduce(parsed, &available_files).map_err(|problems| {
+        SourceError::Reduce(problems.iter().map(|p| locate(files, p)).collect())
+    })?;
+    let warnings: Vec<String> = program
+        .warnings()
+        .iter()
+        .map(|warning| locate(files, warning))
+        .collect();
+    if policy == WarningPolicy::Block && !warnings.is_empty() {
+        return Err(SourceError::Reduce(warnings));
+    }
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+    let _ = sender.send(Source {
+        program,
+        bpm,
+        meter,
+    });
     Result::Ok(())
 }
+
+/// Prefixes `problem` with the file, line and column it is at, like
+/// `main.chop:3:9: error: unknown clip a`.
+fn locate(files: &[PathBuf], problem: &ReduceError) -> String {
+    let severity = if problem.is_warning() {
+        "warning"
+    } else {
+        "error"
+    };
+    match problem
+        .span
+        .and_then(|span| Some((files.get(span.file)?, span)))
+    {
+        Some((file, span)) => {
+            let source = fs::read_to_string(file).unwrap_or_default();
+            let (line, column) = span.line_column(&source);
+            format!(
+                "{}:{}:{}: {}: {}",
+                file.display(),
+                line,
+                column,
+                severity,
+                problem
+            )
+        }
+        None => format!("{}: {}", severity, problem),
+    }
+}
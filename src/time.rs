@@ -0,0 +1,265 @@
+use std::{
+    cmp::Ordering,
+    fmt,
+    ops::{Add, Div, Mul, Neg, Sub},
+};
+
+/// A time or length in whole notes, as an exact fraction. It is kept in
+/// lowest terms with a positive denominator, so equal times are equal as
+/// values too. Arithmetic that does not fit panics, the `checked_` methods
+/// return `None` instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Time {
+    num: i64,
+    denom: i64,
+}
+
+impl Time {
+    pub fn new(num: i64, denom: i64) -> Self {
+        Self::normalized(num.into(), denom.into()).expect("a time needs a denominator above 0")
+    }
+
+    pub fn zero() -> Self {
+        Time { num: 0, denom: 1 }
+    }
+
+    pub fn one() -> Self {
+        Time { num: 1, denom: 1 }
+    }
+
+    pub fn num(&self) -> i64 {
+        self.num
+    }
+
+    pub fn denom(&self) -> i64 {
+        self.denom
+    }
+
+    /// How long this time lasts at `bpm` beats of `1 / unit` a minute.
+    pub fn seconds(&self, bpm: f64, unit: f64) -> f64 {
+        f64::from(self) * unit * 60.0 / bpm
+    }
+
+    pub fn checked_add(self, rhs: Self) -> Option<Self> {
+        Self::normalized(
+            i128::from(self.num) * i128::from(rhs.denom)
+                + i128::from(rhs.num) * i128::from(self.denom),
+            i128::from(self.denom) * i128::from(rhs.denom),
+        )
+    }
+
+    pub fn checked_sub(self, rhs: Self) -> Option<Self> {
+        self.checked_add(-rhs)
+    }
+
+    pub fn checked_mul(self, rhs: Self) -> Option<Self> {
+        Self::normalized(
+            i128::from(self.num) * i128::from(rhs.num),
+            i128::from(self.denom) * i128::from(rhs.denom),
+        )
+    }
+
+    /// `None` for a division by 0 as well.
+    pub fn checked_div(self, rhs: Self) -> Option<Self> {
+        Self::normalized(
+            i128::from(self.num) * i128::from(rhs.denom),
+            i128::from(self.denom) * i128::from(rhs.num),
+        )
+    }
+
+    fn normalized(num: i128, denom: i128) -> Option<Self> {
+        if denom == 0 {
+            return None;
+        }
+        let divisor = gcd(num.unsigned_abs(), denom.unsigned_abs()) as i128 * denom.signum();
+        Some(Time {
+            num: (num / divisor).try_into().ok()?,
+            denom: (denom / divisor).try_into().ok()?,
+        })
+    }
+}
+
+fn gcd(a: u128, b: u128) -> u128 {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+impl Default for Time {
+    fn default() -> Self {
+        Time::zero()
+    }
+}
+
+impl From<i64> for Time {
+    fn from(whole: i64) -> Self {
+        Time {
+            num: whole,
+            denom: 1,
+        }
+    }
+}
+
+impl From<Time> for f64 {
+    fn from(time: Time) -> Self {
+        time.num as f64 / time.denom as f64
+    }
+}
+
+impl From<&Time> for f64 {
+    fn from(time: &Time) -> Self {
+        f64::from(*time)
+    }
+}
+
+impl Ord for Time {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (i128::from(self.num) * i128::from(other.denom))
+            .cmp(&(i128::from(other.num) * i128::from(self.denom)))
+    }
+}
+
+impl PartialOrd for Time {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Neg for Time {
+    type Output = Time;
+
+    fn neg(self) -> Time {
+        Time {
+            num: self.num.checked_neg().expect("time overflow"),
+            denom: self.denom,
+        }
+    }
+}
+
+impl Neg for &Time {
+    type Output = Time;
+
+    fn neg(self) -> Time {
+        -*self
+    }
+}
+
+/// Implements an operator for every mix of values and references, by the
+/// checked method that does it.
+macro_rules! time_operator {
+    ($trait:ident, $method:ident, $checked:ident, $message:literal) => {
+        impl $trait for Time {
+            type Output = Time;
+
+            fn $method(self, rhs: Time) -> Time {
+                self.$checked(rhs).expect($message)
+            }
+        }
+
+        impl $trait<&Time> for Time {
+            type Output = Time;
+
+            fn $method(self, rhs: &Time) -> Time {
+                self.$checked(*rhs).expect($message)
+            }
+        }
+
+        impl $trait<Time> for &Time {
+            type Output = Time;
+
+            fn $method(self, rhs: Time) -> Time {
+                self.$checked(rhs).expect($message)
+            }
+        }
+
+        impl $trait for &Time {
+            type Output = Time;
+
+            fn $method(self, rhs: &Time) -> Time {
+                self.$checked(*rhs).expect($message)
+            }
+        }
+
+        impl $trait<i64> for Time {
+            type Output = Time;
+
+            fn $method(self, rhs: i64) -> Time {
+                self.$checked(rhs.into()).expect($message)
+            }
+        }
+
+        impl $trait<i64> for &Time {
+            type Output = Time;
+
+            fn $method(self, rhs: i64) -> Time {
+                self.$checked(rhs.into()).expect($message)
+            }
+        }
+    };
+}
+
+time_operator!(Add, add, checked_add, "time overflow");
+time_operator!(Sub, sub, checked_sub, "time overflow");
+time_operator!(Mul, mul, checked_mul, "time overflow");
+time_operator!(Div, div, checked_div, "time overflow or division by 0");
+
+/// Like `3/2`, or `3` for a whole number.
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.denom == 1 {
+            write!(f, "{}", self.num)
+        } else {
+            write!(f, "{}/{}", self.num, self.denom)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Time;
+    use proptest::prelude::*;
+
+    fn time() -> impl Strategy<Value = Time> {
+        (-10_000i64..10_000, 1i64..1_000).prop_map(|(num, denom)| Time::new(num, denom))
+    }
+
+    proptest! {
+        #[test]
+        fn normalized(num in -10_000i64..10_000, denom in 1i64..1_000, factor in 1i64..1_000) {
+            let time = Time::new(num, denom);
+            prop_assert_eq!(time, Time::new(num * factor, denom * factor));
+            prop_assert_eq!(time, Time::new(-num, -denom));
+            prop_assert!(time.denom() > 0);
+        }
+
+        #[test]
+        fn exact(a in time(), b in time(), c in time()) {
+            prop_assert_eq!(a + b - b, a);
+            prop_assert_eq!((a + b) + c, a + (b + c));
+            prop_assert_eq!(a * (b + c), a * b + a * c);
+            if b != Time::zero() {
+                prop_assert_eq!(a / b * b, a);
+            }
+        }
+
+        #[test]
+        fn ordered(a in time(), b in time()) {
+            prop_assert_eq!(a < b, f64::from(a) < f64::from(b));
+            prop_assert_eq!(a < b, b - a > Time::zero());
+            prop_assert_eq!(a.max(b) - a.min(b), if a < b { b - a } else { a - b });
+        }
+    }
+
+    #[test]
+    fn overflow() {
+        let big = Time::from(i64::MAX);
+        assert_eq!(big.checked_add(Time::one()), None);
+        assert_eq!(big.checked_mul(Time::from(2)), None);
+        assert_eq!(Time::one().checked_div(Time::zero()), None);
+        // intermediate products do not overflow when the result fits
+        assert_eq!(big * Time::new(1, 3) * 3, big);
+        assert_eq!(Time::new(-1, 2).to_string(), "-1/2");
+    }
+}
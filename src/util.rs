@@ -1,24 +1,64 @@
-use fraction::Fraction;
+use crate::{
+    parser::{MeterDeclaration, TimeExpression},
+    time::Time,
+};
 
-use crate::{parser::TimeExpression, reducer::Time};
-
-pub fn frac_to_time(frac: &fraction::prelude::GenericFraction<u64>) -> Time {
-    Time {
-        num: (*frac.fract().numer().unwrap_or(&0)
-            + frac.trunc().numer().unwrap_or(&0) * *frac.fract().denom().unwrap_or(&0))
-            as isize
-            * (if frac.is_sign_positive() { 1 } else { -1 }),
-        denom: *frac.fract().denom().unwrap_or(&0) as usize,
+/// Times are measured in whole notes, so a beat of the meter lasts `1 / unit`.
+/// A time counted back from the end is subtracted from `length`, the length
+/// of the clip it is in. `None` for a time that does not fit into one.
+pub fn time_expression_to_time(
+    time_expression: &TimeExpression,
+    meter: &MeterDeclaration,
+    length: &Time,
+) -> Option<Time> {
+    // the parts of a position count from 1, a part left out adds nothing
+    let first = Time::from(i64::from(!time_expression.from_end));
+    let whole = |number: usize| i64::try_from(number).ok().map(Time::from);
+    let part = |part: usize| whole(part)?.checked_sub(first);
+    let bars =
+        bar_length(meter).checked_mul(time_expression.bar.map_or(Some(Time::zero()), part)?)?;
+    let (numerator, denominator) = time_expression.beat;
+    let beats = whole(numerator)?
+        .checked_div(whole(denominator)?)?
+        .checked_sub(first)?
+        .checked_div(whole(meter.unit)?)?;
+    let sixteenths = time_expression
+        .sixteenth
+        .map_or(Some(Time::zero()), part)?
+        .checked_div(Time::from(16))?;
+    let time = bars.checked_add(beats)?.checked_add(sixteenths)?;
+    if time_expression.from_end {
+        length.checked_sub(time)
+    } else {
+        Some(time)
     }
 }
 
-pub fn time_to_frac(time: &Time) -> fraction::prelude::GenericFraction<u64> {
-    Fraction::new(time.num as u64, time.denom as u64)
+pub fn bar_length(meter: &MeterDeclaration) -> Time {
+    Time::new(meter.beats as i64, meter.unit as i64)
+}
+
+/// A directory of its own for a test to write files into, removed with what
+/// is in it once the test is done with it.
+#[cfg(test)]
+pub struct TestDirectory(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDirectory {
+    pub fn new(test: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("choppo-{}-{}", test, std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        TestDirectory(path)
+    }
+
+    pub fn path(&self) -> &std::path::Path {
+        &self.0
+    }
 }
 
-pub fn time_expression_to_time(time_expression: &TimeExpression) -> Time {
-    Time {
-        num: (time_expression.beat * 4 + time_expression.sixteenth.unwrap_or(0)) as isize,
-        denom: 16,
+#[cfg(test)]
+impl Drop for TestDirectory {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
     }
 }
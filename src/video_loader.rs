@@ -1,49 +1,279 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use ffmpeg::frame::Video;
 
-use crate::video_reader::VideoReader;
+use crate::parser::SourceOffset;
+use crate::reducer::Source;
+use crate::video_reader::{self, VideoInfo, VideoReader};
 
 pub struct VideoLoader {
-    readers: HashMap<(usize, String), (VideoReader, usize)>,
+    /// The readers of every layer and file, one reading forwards and one
+    /// backwards, so that a ping-pong keeps both as it turns.
+    readers: HashMap<(usize, String, bool), Reading>,
+    /// What is known about every file that is fitted, played ping-pong or
+    /// played from an offset.
+    infos: HashMap<String, Option<VideoInfo>>,
+    /// The files and cues that were missing a chapter, to report them once.
+    missing_cues: HashSet<(String, String)>,
     target_w: u32,
     target_h: u32,
 }
 
+/// How many frames a reader reads through to skip ahead, rather than seeking.
+const MAX_SKIP: usize = 8;
+
+struct Reading {
+    reader: VideoReader,
+    /// The frame last read, or the one the reader starts at until it reads.
+    frame: usize,
+    /// The frame last read.
+    video: Option<Video>,
+}
+
 impl VideoLoader {
     pub fn new(target_w: u32, target_h: u32) -> Self {
         Self {
             readers: HashMap::new(),
+            infos: HashMap::new(),
+            missing_cues: HashSet::new(),
             target_w,
             target_h,
         }
     }
 
-    pub fn load(&mut self, name: &str, frame: usize, layer: usize) -> Option<Video> {
-        if let Some((reader, last_frame)) = self.readers.get_mut(&(layer, name.to_string())) {
-            if *last_frame + 1 != frame {
-                *last_frame = frame;
-                reader.stop();
-                let mut reader =
-                    VideoReader::new(name.to_string(), frame, self.target_w, self.target_h);
-                reader.as_ref()?;
-                let frame_ = reader.as_mut()?.read_next_frame();
-                self.readers
-                    .insert((layer, name.to_string()), (reader?, frame));
-                frame_
-            } else {
-                *last_frame = frame;
-                reader.read_next_frame()
+    /// Loads the frame `frame` frames after the offset of `source`.
+    pub fn load(
+        &mut self,
+        source: &Source,
+        frame: usize,
+        layer: usize,
+        reversed: bool,
+    ) -> Option<Video> {
+        let offset = match &source.offset {
+            Some(SourceOffset::Frame(offset)) => *offset,
+            Some(_) => {
+                let fps = self.info(&source.path)?.fps;
+                (self.offset_seconds(source)? * fps).round() as usize
+            }
+            None => 0,
+        };
+        self.load_frame(&source.path, offset + frame, layer, reversed)
+    }
+
+    fn load_frame(
+        &mut self,
+        name: &str,
+        frame: usize,
+        layer: usize,
+        reversed: bool,
+    ) -> Option<Video> {
+        let key = (layer, name.to_string(), reversed);
+        if let Some(reading) = self.readers.get_mut(&key) {
+            match reads(reading.frame, reading.video.is_some(), frame, reversed) {
+                Some(reads) => {
+                    reading.frame = frame;
+                    for _ in 0..reads {
+                        if let Some(video) = reading.reader.read_next_frame() {
+                            reading.video = Some(video);
+                        }
+                    }
+                    reading.video.clone()
+                }
+                None => {
+                    reading.reader.stop();
+                    self.readers.remove(&key);
+                    self.load_frame(name, frame, layer, reversed)
+                }
             }
         } else {
-            self.readers.insert(
-                (layer, name.to_string()),
-                (
-                    VideoReader::new(name.to_string(), frame, self.target_w, self.target_h)?,
-                    frame,
-                ),
-            );
-            self.load(name, frame, layer)
+            let mut reading = self.start_reading(name, frame, reversed)?;
+            reading.video = reading.reader.read_next_frame();
+            let video = reading.video.clone();
+            self.readers.insert(key, reading);
+            video
+        }
+    }
+
+    /// Has a reader of `name` wait at `frame` for the layer to get there, so
+    /// that it need not seek then.
+    fn prepare(&mut self, name: &str, frame: usize, layer: usize, reversed: bool) {
+        let key = (layer, name.to_string(), reversed);
+        if let Some(reading) = self.readers.get_mut(&key) {
+            if reading.frame == frame && reading.video.is_none() {
+                return;
+            }
+            reading.reader.stop();
+            self.readers.remove(&key);
+        }
+        if let Some(reading) = self.start_reading(name, frame, reversed) {
+            self.readers.insert(key, reading);
+        }
+    }
+
+    fn start_reading(&self, name: &str, frame: usize, reversed: bool) -> Option<Reading> {
+        let reader = VideoReader::new(
+            name.to_string(),
+            frame,
+            self.target_w,
+            self.target_h,
+            reversed,
+        )?;
+        Some(Reading {
+            reader,
+            frame,
+            video: None,
+        })
+    }
+
+    /// Loads the frame `progress` of the way from `start` to `end` seconds
+    /// after the offset of `source`, or through all of it.
+    pub fn load_fitted(
+        &mut self,
+        source: &Source,
+        start: Option<f64>,
+        end: Option<f64>,
+        progress: f64,
+        layer: usize,
+    ) -> Option<Video> {
+        let (first, last) = self.region(source, start, end)?;
+        let frame = first + (progress.clamp(0.0, 1.0) * (last - first) as f64) as usize;
+        self.load_frame(&source.path, frame, layer, false)
+    }
+
+    /// Loads the frame `travel` frames into playing the part of `source` from
+    /// `start` to `end` seconds after its offset, or all of it, forwards and
+    /// backwards.
+    pub fn load_pingpong(
+        &mut self,
+        source: &Source,
+        start: Option<f64>,
+        end: Option<f64>,
+        travel: usize,
+        layer: usize,
+    ) -> Option<Video> {
+        let (first, last) = self.region(source, start, end)?;
+        let (frame, reversed) = bounce(first, last, travel);
+        // the reader of the way back is ready at the turn before it comes
+        if first < last {
+            let turn = if reversed { first } else { last };
+            self.prepare(&source.path, turn, layer, !reversed);
+        }
+        self.load_frame(&source.path, frame, layer, reversed)
+    }
+
+    /// The first and last frame from `start` to `end` seconds after the offset
+    /// of `source`, or to its end.
+    fn region(
+        &mut self,
+        source: &Source,
+        start: Option<f64>,
+        end: Option<f64>,
+    ) -> Option<(usize, usize)> {
+        let offset = self.offset_seconds(source)?;
+        let info = self.info(&source.path)?;
+        let last_frame = ((info.duration * info.fps) as usize).saturating_sub(1);
+        let frame = |seconds: f64| ((offset + seconds) * info.fps).round() as usize;
+        let first = frame(start.unwrap_or(0.0)).min(last_frame);
+        let last = end.map_or(last_frame, |end| frame(end).min(last_frame));
+        Some((first, last.max(first)))
+    }
+
+    fn offset_seconds(&mut self, source: &Source) -> Option<f64> {
+        match &source.offset {
+            None => Some(0.0),
+            Some(SourceOffset::Seconds(seconds)) => Some(*seconds),
+            Some(SourceOffset::Frame(frame)) => Some(*frame as f64 / self.info(&source.path)?.fps),
+            Some(SourceOffset::Cue(name)) => {
+                let cue = self
+                    .info(&source.path)?
+                    .cues
+                    .iter()
+                    .find(|(title, _)| title == name)
+                    .map(|(_, start)| *start);
+                if cue.is_none()
+                    && self
+                        .missing_cues
+                        .insert((source.path.clone(), name.clone()))
+                {
+                    println!("{} has no chapter {}", source.path, name);
+                }
+                cue
+            }
         }
     }
+
+    fn info(&mut self, name: &str) -> Option<&VideoInfo> {
+        self.infos
+            .entry(name.to_string())
+            .or_insert_with(|| video_reader::probe(name))
+            .as_ref()
+    }
+}
+
+/// How many frames a reader that is at `from`, and has read it or not yet,
+/// reads to get to `to`, unless it cannot get there without seeking.
+fn reads(from: usize, read: bool, to: usize, reversed: bool) -> Option<usize> {
+    // slow clips show a frame more than once, fast ones skip frames
+    let step = if reversed {
+        from.checked_sub(to)
+    } else {
+        to.checked_sub(from)
+    }?;
+    (step <= MAX_SKIP).then_some(if read { step } else { step + 1 })
+}
+
+/// The frame `travel` frames into playing from `first` to `last` and back
+/// again, and whether it is on the way back.
+fn bounce(first: usize, last: usize, travel: usize) -> (usize, bool) {
+    let span = last - first;
+    if span == 0 {
+        return (first, false);
+    }
+    let travel = travel % (2 * span);
+    if travel < span {
+        (first + travel, false)
+    } else {
+        (last - (travel - span), true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bounce, reads, MAX_SKIP};
+
+    #[test]
+    fn pingpong_turns_around_at_either_end() {
+        let frames: Vec<(usize, bool)> = (0..9).map(|travel| bounce(10, 13, travel)).collect();
+        assert_eq!(
+            frames,
+            [
+                (10, false),
+                (11, false),
+                (12, false),
+                (13, true),
+                (12, true),
+                (11, true),
+                (10, false),
+                (11, false),
+                (12, false)
+            ]
+        );
+        // a single frame is all there is to show
+        assert_eq!(bounce(4, 4, 0), (4, false));
+        assert_eq!(bounce(4, 4, 7), (4, false));
+    }
+
+    #[test]
+    fn readers_read_on_to_frames_ahead_of_them() {
+        assert_eq!(reads(10, true, 10, false), Some(0));
+        assert_eq!(reads(10, true, 12, false), Some(2));
+        assert_eq!(reads(10, true, 8, true), Some(2));
+        // a reader waiting at a turn has yet to read the frame it is at
+        assert_eq!(reads(13, false, 13, true), Some(1));
+        assert_eq!(reads(13, false, 11, true), Some(3));
+        // going the other way or too far ahead takes a new reader
+        assert_eq!(reads(10, true, 9, false), None);
+        assert_eq!(reads(10, true, 11, true), None);
+        assert_eq!(reads(10, true, 11 + MAX_SKIP, false), None);
+    }
 }
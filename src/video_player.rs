@@ -1,12 +1,13 @@
 use std::time::Duration;
 
 use crate::bpm_controller::BpmController;
-use crate::interpreter::{self, Interpreter};
+use crate::interpreter::{self, Interpreter, SwitchAt};
 use crate::source_watcher::SourceWatcher;
 use crate::time_controller::TimeController;
+use crate::util::bar_length;
 use crate::video_loader::VideoLoader;
 
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
 
 use sdl2::render::{Texture, TextureValueError};
 use sdl2::surface::Surface;
@@ -37,6 +38,8 @@ pub fn play_video(
     let _frame_duration = Duration::from_secs_f64(1.0 / fps);
     let mut event_pump = sdl_context.event_pump().unwrap();
     let mut time_controller = TimeController::new(fps);
+    // only a changed declaration overrides the bpm adjusted at runtime
+    let mut declared_bpm = None;
     'mainloop: loop {
         time_controller.frame_start();
 
@@ -48,18 +51,47 @@ pub fn play_video(
         }
         runner.set_bpm(bpm_controller.get_bpm());
         bpm_controller.tick();
-        if let Some(clip) = source_watcher.get_new_interpreted() {
-            runner.set_commands(clip.commands, clip.length.into());
+        if let Some(source) = source_watcher.get_new_interpreted() {
+            match runner.set_program(source.program) {
+                Ok(()) => {
+                    if let Some(bpm) = source.bpm.filter(|bpm| Some(*bpm) != declared_bpm) {
+                        bpm_controller.set_bpm(bpm);
+                    }
+                    declared_bpm = source.bpm;
+                    runner.set_beat_unit(source.meter.unit as f64);
+                    runner.set_bar_length(bar_length(&source.meter).into());
+                }
+                Err(e) => eprintln!("error: {}", e),
+            }
         }
-        let commands = runner.advance_time(1.0 / fps);
+        let commands = runner.advance_time(1.0 / fps).unwrap_or_else(|e| {
+            // the take before plays again
+            eprintln!("error: {}", e);
+            Vec::new()
+        });
         if !time_controller.skip_frame() {
             canvas.clear();
             let mut layer = 0;
             for cmd in commands {
                 let video = match cmd.clone() {
                     interpreter::FrameCommand::ShowSingleFrame { file, frame } => {
-                        video_loader.load(&file, frame, layer)
+                        video_loader.load(&file, frame, layer, false)
+                    }
+                    interpreter::FrameCommand::ShowReversedFrame { file, frame } => {
+                        video_loader.load(&file, frame, layer, true)
                     }
+                    interpreter::FrameCommand::ShowFittedFrame {
+                        file,
+                        start,
+                        end,
+                        progress,
+                    } => video_loader.load_fitted(&file, start, end, progress, layer),
+                    interpreter::FrameCommand::ShowPingPongFrame {
+                        file,
+                        start,
+                        end,
+                        travel,
+                    } => video_loader.load_pingpong(&file, start, end, travel, layer),
                     _ => None,
                 };
                 if let Some(video) = video {
@@ -85,6 +117,24 @@ pub fn play_video(
                 } => {
                     break 'mainloop;
                 }
+                Event::KeyDown {
+                    keycode: Some(keycode),
+                    keymod,
+                    ..
+                } => match scene_number(keycode) {
+                    // number keys switch scenes at the next bar, with shift at the end of the loop
+                    Some(number) => {
+                        let at = if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            SwitchAt::LoopEnd
+                        } else {
+                            SwitchAt::Bar
+                        };
+                        if !runner.switch_scene_number(number, at) {
+                            println!("no scene {}", number);
+                        }
+                    }
+                    None => bpm_controller.consume_event(&event),
+                },
                 Event::KeyDown { .. } => bpm_controller.consume_event(&event),
                 Event::KeyUp { .. } => bpm_controller.consume_event(&event),
                 _ => {}
@@ -94,6 +144,24 @@ pub fn play_video(
     Ok(())
 }
 
+/// The number of the scene a key switches to.
+fn scene_number(keycode: Keycode) -> Option<usize> {
+    [
+        Keycode::Num0,
+        Keycode::Num1,
+        Keycode::Num2,
+        Keycode::Num3,
+        Keycode::Num4,
+        Keycode::Num5,
+        Keycode::Num6,
+        Keycode::Num7,
+        Keycode::Num8,
+        Keycode::Num9,
+    ]
+    .iter()
+    .position(|key| *key == keycode)
+}
+
 fn frame_to_texture(
     mut rgb_frame: ffmpeg::frame::Video,
     target_w: u32,
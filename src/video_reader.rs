@@ -4,8 +4,9 @@ use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
 use std::time::Duration;
 
+use ffmpeg::decoder;
 use ffmpeg::ffi::{avformat_seek_file, AVSEEK_FLAG_FRAME};
-use ffmpeg::format::{input, Pixel};
+use ffmpeg::format::{context::Input, input, Pixel};
 use ffmpeg::frame::Video;
 use ffmpeg::media::Type;
 use ffmpeg::software::scaling::{context::Context, flag::Flags};
@@ -15,15 +16,25 @@ pub struct VideoReader {
     buffer: Vec<Video>,
     receiver: Receiver<(usize, Video)>,
     sender: Sender<ToVideoThread>,
+    reversed: bool,
 }
 
+/// How many frames a reversed reader keeps ahead.
+const REVERSED_AHEAD: usize = 30;
+
 enum ToVideoThread {
     LoadFrame,
     Stop,
 }
 
 impl VideoReader {
-    pub fn new(file_name: String, frame: usize, target_w: u32, target_h: u32) -> Option<Self> {
+    pub fn new(
+        file_name: String,
+        frame: usize,
+        target_w: u32,
+        target_h: u32,
+        reversed: bool,
+    ) -> Option<Self> {
         ffmpeg::init().unwrap();
         if let Ok(mut ictx) = input(&file_name) {
             let input = ictx
@@ -53,6 +64,29 @@ impl VideoReader {
                 channel();
             let timebase_numerator = input.time_base().numerator() as i64;
             let timebase_denominator = input.time_base().denominator() as i64;
+            if reversed {
+                let frames_per_timestamp = timebase_numerator as f64 / timebase_denominator as f64
+                    * input.avg_frame_rate().numerator() as f64
+                    / input.avg_frame_rate().denominator() as f64;
+                thread::spawn(move || {
+                    read_reversed(
+                        ictx,
+                        decoder,
+                        video_stream_index,
+                        frame,
+                        frames_per_timestamp,
+                        frame_receiver,
+                        video_sender,
+                    )
+                });
+                return Some(Self {
+                    scaler,
+                    sender: frame_sender,
+                    receiver: video_receiver,
+                    buffer: Vec::new(),
+                    reversed,
+                });
+            }
             thread::spawn(move || {
                 let mut current_frame = frame;
                 let mut frames_to_read = 3;
@@ -99,6 +133,7 @@ impl VideoReader {
                 sender: frame_sender,
                 receiver: video_receiver,
                 buffer: Vec::new(),
+                reversed,
             });
         }
         None
@@ -115,7 +150,13 @@ impl VideoReader {
             }
             thread::sleep(Duration::from_secs_f64(0.001))
         }
-        if let Some(frame) = self.buffer.pop() {
+        // a reversed reader sends its frames in the order they are shown
+        let frame = if self.reversed && !self.buffer.is_empty() {
+            Some(self.buffer.remove(0))
+        } else {
+            self.buffer.pop()
+        };
+        if let Some(frame) = frame {
             let mut rgb_frame = Video::empty();
             let _ = self.scaler.run(&frame, &mut rgb_frame);
             Some(rgb_frame)
@@ -127,3 +168,195 @@ impl VideoReader {
         let _ = self.sender.send(ToVideoThread::Stop);
     }
 }
+
+/// What there is to know about a file besides its frames.
+#[derive(Clone, Debug)]
+pub struct VideoInfo {
+    /// The length in seconds.
+    pub duration: f64,
+    pub fps: f64,
+    /// The title and start in seconds of every chapter.
+    pub cues: Vec<(String, f64)>,
+}
+
+pub fn probe(file_name: &str) -> Option<VideoInfo> {
+    ffmpeg::init().ok()?;
+    let ictx = input(&file_name).ok()?;
+    let stream = ictx.streams().best(Type::Video)?;
+    let rate = stream.avg_frame_rate();
+    if rate.denominator() == 0 || ictx.duration() <= 0 {
+        return None;
+    }
+    let cues = ictx
+        .chapters()
+        .filter_map(|chapter| {
+            let title = chapter.metadata().get("title")?.to_string();
+            Some((
+                title,
+                chapter.start() as f64 * f64::from(chapter.time_base()),
+            ))
+        })
+        .collect();
+    Some(VideoInfo {
+        duration: ictx.duration() as f64 / ffmpeg::ffi::AV_TIME_BASE as f64,
+        fps: f64::from(rate.numerator()) / f64::from(rate.denominator()),
+        cues,
+    })
+}
+
+/// Serves the frames from `frame` back to the first one, which it then keeps
+/// serving. Decoding only runs forwards, so this decodes the group of pictures
+/// from the keyframe before the frames served so far and sends it last frame
+/// first.
+fn read_reversed(
+    mut ictx: Input,
+    mut decoder: decoder::Video,
+    video_stream_index: usize,
+    frame: usize,
+    frames_per_timestamp: f64,
+    frame_receiver: Receiver<ToVideoThread>,
+    video_sender: Sender<(usize, Video)>,
+) {
+    let mut frames = Backwards::new(frame);
+    let mut requested = 0;
+    let mut sent = 0;
+    loop {
+        match frame_receiver.try_recv() {
+            Ok(ToVideoThread::Stop) => {
+                return;
+            }
+            Ok(ToVideoThread::LoadFrame) => requested += 1,
+            Err(_) => (),
+        }
+        let next = if sent < requested + REVERSED_AHEAD {
+            frames.next(|end| {
+                decode_group(
+                    &mut ictx,
+                    &mut decoder,
+                    video_stream_index,
+                    end,
+                    frames_per_timestamp,
+                )
+            })
+        } else {
+            None
+        };
+        match next {
+            Some(frame) => {
+                let _ = video_sender.send(frame);
+                sent += 1;
+            }
+            None => thread::sleep(Duration::from_secs_f64(0.001)),
+        }
+    }
+}
+
+/// Frames served last first, out of groups of pictures decoded forwards, each
+/// of them once.
+struct Backwards<T> {
+    /// The frames of the group being served that are still to be served.
+    group: Vec<(usize, T)>,
+    /// The first frame of the groups decoded so far.
+    end: usize,
+    first: Option<T>,
+}
+
+impl<T: Clone> Backwards<T> {
+    fn new(frame: usize) -> Self {
+        Self {
+            group: Vec::new(),
+            end: frame + 1,
+            first: None,
+        }
+    }
+
+    /// The next frame back. Once the group being served runs out, `decode`
+    /// decodes the one before the frame it is given. The first frame is
+    /// served from then on.
+    fn next(&mut self, decode: impl FnOnce(usize) -> Vec<(usize, T)>) -> Option<(usize, T)> {
+        if self.group.is_empty() && self.end > 0 {
+            self.group = decode(self.end);
+            // a group that does not decode is skipped
+            let start = self
+                .group
+                .first()
+                .map_or(self.end.saturating_sub(REVERSED_AHEAD), |(index, _)| *index);
+            if start == 0 {
+                self.first = self.group.first().map(|(_, video)| video.clone());
+            }
+            self.end = start;
+        }
+        self.group.pop().or_else(|| Some((0, self.first.clone()?)))
+    }
+}
+
+/// Decodes the frames from the keyframe at or before the frame before `end`
+/// up to `end`, in the order they are shown.
+fn decode_group(
+    ictx: &mut Input,
+    decoder: &mut decoder::Video,
+    video_stream_index: usize,
+    end: usize,
+    frames_per_timestamp: f64,
+) -> Vec<(usize, Video)> {
+    let timestamp = ((end - 1) as f64 / frames_per_timestamp) as i64;
+    unsafe {
+        // lands on the last keyframe at or before the timestamp
+        avformat_seek_file(
+            ictx.as_mut_ptr(),
+            video_stream_index as i32,
+            i64::MIN,
+            timestamp,
+            timestamp,
+            0,
+        );
+    }
+    decoder.flush();
+    let mut decoded_frames = Vec::new();
+    let mut decoded = Video::empty();
+    for (stream, packet) in ictx.packets() {
+        if stream.index() != video_stream_index {
+            continue;
+        }
+        let _ = decoder.send_packet(&packet);
+        while decoder.receive_frame(&mut decoded).is_ok() {
+            let index =
+                (decoded.timestamp().unwrap_or(0) as f64 * frames_per_timestamp).round() as usize;
+            if index >= end {
+                return decoded_frames;
+            }
+            decoded_frames.push((index, std::mem::replace(&mut decoded, Video::empty())));
+        }
+    }
+    decoded_frames
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::Backwards;
+
+    #[test]
+    fn every_group_of_pictures_is_decoded_once() {
+        // a keyframe every 10 frames
+        let decoded = RefCell::new(Vec::new());
+        let decode = |end: usize| {
+            let keyframe = (end - 1) / 10 * 10;
+            decoded.borrow_mut().push(keyframe..end);
+            (keyframe..end).map(|index| (index, index)).collect()
+        };
+        let mut frames = Backwards::new(24);
+        let served: Vec<usize> = (0..28).map(|_| frames.next(decode).unwrap().0).collect();
+        let expected: Vec<usize> = (0..=24).rev().chain([0, 0, 0]).collect();
+        assert_eq!(served, expected);
+        assert_eq!(*decoded.borrow(), [20..25, 10..20, 0..10]);
+    }
+
+    #[test]
+    fn a_group_that_does_not_decode_is_skipped() {
+        let mut frames = Backwards::new(40);
+        assert_eq!(frames.next(|_| Vec::<(usize, usize)>::new()), None);
+        assert_eq!(frames.next(|end| vec![(end - 1, end - 1)]), Some((10, 10)));
+    }
+}
@@ -0,0 +1,75 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fmt")
+        .join(name)
+}
+
+fn choppo_fmt(args: &[&str], path: &Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_choppo"))
+        .arg("fmt")
+        .args(args)
+        .arg(path)
+        .output()
+        .expect("choppo should run")
+}
+
+/// `choppo fmt` only prints a source that plays exactly like the original, so
+/// a successful run is the round trip check.
+fn assert_formats_to_expected(name: &str) {
+    let output = choppo_fmt(&[], &fixture(&format!("{}.chop", name)));
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let expected_path = fixture(&format!("{}.formatted.chop", name));
+    let expected = fs::read_to_string(&expected_path).unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+
+    let output = choppo_fmt(&["--check"], &expected_path);
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn formats_input() {
+    assert_formats_to_expected("input");
+}
+
+#[test]
+fn formats_operators() {
+    assert_formats_to_expected("operators");
+}
+
+#[test]
+fn formats_scenes_and_imports() {
+    assert_formats_to_expected("scenes");
+}
+
+#[test]
+fn check_fails_on_unformatted_source() {
+    let output = choppo_fmt(&["--check"], &fixture("input.chop"));
+    assert!(!output.status.success());
+}
+
+#[test]
+fn formats_example_input() {
+    let output = choppo_fmt(
+        &[],
+        &Path::new(env!("CARGO_MANIFEST_DIR")).join("input.chop"),
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}